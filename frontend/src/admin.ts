@@ -2,5 +2,6 @@ import { createApp, Suspense } from 'vue'
 import AdminPage from './AdminPage.vue'
 import VueCookies from 'vue-cookies'
 import './assets/main.css'
+import './csrf'
 
 createApp(AdminPage).use(VueCookies).mount('#admin')
@@ -0,0 +1,19 @@
+// Sends the CSRF token the server hands out at login with every request that
+// can change something.
+const fetch = window.fetch
+
+function csrfToken(): string | null {
+  let match = document.cookie.match(/(?:^|;\s*)csrf_token=([^;]*)/)
+  return match === null ? null : decodeURIComponent(match[1])
+}
+
+window.fetch = function (input: RequestInfo | URL, init: RequestInit = {}) {
+  let method = (init.method ?? 'GET').toUpperCase()
+  let token = csrfToken()
+  if (method !== 'GET' && method !== 'HEAD' && token !== null) {
+    let headers = new Headers(init.headers)
+    headers.set('X-CSRF-Token', token)
+    init = { ...init, headers }
+  }
+  return fetch(input, init)
+}
@@ -2,5 +2,6 @@ import { createApp } from 'vue'
 import App from './App.vue'
 import VueCookies from 'vue-cookies'
 import './assets/main.css'
+import './csrf'
 
 createApp(App).use(VueCookies).mount('#app')
@@ -0,0 +1,5 @@
+import { createApp } from 'vue'
+import ResetPasswordPage from './ResetPasswordPage.vue'
+import './assets/main.css'
+
+createApp(ResetPasswordPage).mount('#admin')
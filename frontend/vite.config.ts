@@ -19,7 +19,7 @@ export default defineConfig({
     outDir: '../web',
     assetsDir: 'assets',
     rollupOptions: {
-      input: ['index.html', 'admin.html', 'create_user.html'],
+      input: ['index.html', 'admin.html', 'create_user.html', 'reset_password.html'],
     },
   },
   server: {
@@ -1,25 +1,30 @@
-use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
-};
-
 use axum::{
-    extract::State,
-    http::Request,
-    middleware::{self, Next},
+    extract::{Query, State},
+    middleware,
     response::IntoResponse,
     routing, Extension, Json, Router,
 };
+use chrono::{Duration, Utc};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
-use crate::db::{self, PrimaryKeyValue};
+use crate::{
+    audit, categories,
+    db::{self, PrimaryKeyValue},
+    kiosk,
+    permissions::{self, Permission},
+    tombstone_store,
+};
+
+const DEFAULT_INVITE_HOURS: i64 = 72;
+const MAX_INVITE_HOURS: i64 = 24 * 30;
+const DEFAULT_RESET_HOURS: i64 = 24;
+const MAX_RESET_HOURS: i64 = 24 * 7;
 
 #[derive(Deserialize)]
-pub struct ChangePassIn {
+pub struct ResetLinkIn {
     name: String,
-    pass: String,
+    hours: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -27,24 +32,132 @@ pub struct DeleteUserIn {
     name: String,
 }
 
+#[derive(Deserialize)]
+pub struct RestoreIn {
+    id: String,
+}
+
+#[derive(Serialize)]
+pub struct TombstoneOut {
+    id: String,
+    // "note", "student" or "user".
+    kind: &'static str,
+    description: String,
+    deleted_by: String,
+    deleted: i64,
+    // When it can no longer be restored.
+    expires: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CategoryIn {
+    // Left out to add a new category.
+    id: Option<String>,
+    name: String,
+    order: u32,
+    editable: bool,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteCategoryIn {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetEmailIn {
+    name: String,
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GenTokenIn {
+    role: Option<db::UserRole>,
+    name: Option<String>,
+    hours: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeInviteIn {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockIn {
+    key: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionIn {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct SettingsIn {
+    require_admin_totp: Option<bool>,
+    min_password_length: Option<usize>,
+    password_needs_digit: Option<bool>,
+    password_needs_symbol: Option<bool>,
+    deleted_retention_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SessionOut {
+    id: String,
+    name: String,
+    role: db::UserRole,
+    created: i64,
+    expires: i64,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct UserOut {
     name: String,
     role: db::UserRole,
+    email: Option<String>,
 }
 
 pub fn routes() -> Router<crate::AppState> {
     Router::new()
-        .route("/clear_times", routing::post(clear_times))
         .route("/users", routing::get(users_get))
-        .route("/gen_token", routing::get(gen_token_get))
-        .route("/change_pass", routing::post(change_pass_post))
-        .route("/delete_user", routing::post(delete_user_post))
+        .route("/gen_token", routing::post(gen_token_post))
+        .route("/invites", routing::get(invites_get))
+        .route("/revoke_invite", routing::post(revoke_invite_post))
+        .route("/lockouts", routing::get(lockouts_get))
+        .route("/unlock", routing::post(unlock_post))
+        .route("/sessions", routing::get(sessions_get))
+        .route("/revoke_session", routing::post(revoke_session_post))
         .route(
-            "/load_students",
-            routing::post(crate::integration::load_students_post),
+            "/revoke_user_sessions",
+            routing::post(revoke_user_sessions_post),
+        )
+        .route("/reset_link", routing::post(reset_link_post))
+        .route("/delete_user", routing::post(delete_user_post))
+        .route("/set_email", routing::post(set_email_post))
+        .route("/settings", routing::get(settings_get).post(settings_post))
+        .route("/audit", routing::get(audit_get))
+        .route("/deleted", routing::get(deleted_get))
+        .route("/restore", routing::post(restore_post))
+        .route("/categories", routing::post(category_post))
+        .route("/delete_category", routing::post(delete_category_post))
+        .route("/rebuild_search", routing::post(rebuild_search_post))
+        .route_layer(middleware::from_fn_with_state(
+            Permission::ManageUsers,
+            permissions::require_layer,
+        ))
+        .merge(
+            Router::new()
+                .route("/clear_times", routing::post(clear_times))
+                .route(
+                    "/load_students",
+                    routing::post(crate::integration::load_students_post),
+                )
+                .route_layer(middleware::from_fn_with_state(
+                    Permission::RunSync,
+                    permissions::require_layer,
+                )),
         )
-        .layer(middleware::from_fn(require_admin_layer))
 }
 
 async fn clear_times(State(state): State<crate::AppState>) -> Result<impl IntoResponse, String> {
@@ -62,18 +175,6 @@ async fn clear_times(State(state): State<crate::AppState>) -> Result<impl IntoRe
     Ok(())
 }
 
-async fn require_admin_layer<B>(
-    Extension(session): Extension<db::Session>,
-    req: Request<B>,
-    next: Next<B>,
-) -> impl IntoResponse {
-    if session.user.role == db::UserRole::Admin {
-        next.run(req).await
-    } else {
-        StatusCode::UNAUTHORIZED.into_response()
-    }
-}
-
 async fn users_get(State(state): State<crate::AppState>) -> Result<Json<Vec<UserOut>>, String> {
     let db = state.db.read().await;
     let mut users = state.users.write().await;
@@ -85,47 +186,827 @@ async fn users_get(State(state): State<crate::AppState>) -> Result<Json<Vec<User
             .map(|user| UserOut {
                 name: user.name.clone(),
                 role: user.role.clone(),
+                email: user.email.clone(),
             })
             .collect(),
     ))
 }
 
-async fn gen_token_get(State(state): State<crate::AppState>) -> Json<String> {
-    let mut invites = state.invites.write().await;
+async fn gen_token_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    Json(payload): Json<GenTokenIn>,
+) -> Result<impl IntoResponse, String> {
+    let hours = payload.hours.unwrap_or(DEFAULT_INVITE_HOURS);
+    if !(1..=MAX_INVITE_HOURS).contains(&hours) {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            format!("`hours` must be between 1 and {}", MAX_INVITE_HOURS),
+        )
+            .into_response());
+    }
+    let db = state.db.read().await;
+    let invites = state.invites.read().await;
 
-    let token = Uuid::new_v4();
-    invites.insert(token.to_string(), db::UserRole::Standard);
-    Json(token.to_string())
+    let invite = invites
+        .create(
+            &db,
+            &session.user.name,
+            payload.role.unwrap_or(db::UserRole::Standard),
+            payload.name,
+            Duration::hours(hours),
+        )
+        .await?;
+    Ok(Json(invite.token).into_response())
 }
 
-async fn change_pass_post(
+async fn invites_get(
     State(state): State<crate::AppState>,
-    Json(payload): Json<ChangePassIn>,
-) -> impl IntoResponse {
+) -> Result<Json<Vec<db::Invite>>, String> {
     let db = state.db.read().await;
+    let invites = state.invites.read().await;
+    Ok(Json(invites.outstanding(&db).await?))
+}
 
-    let mut users = state.users.write().await;
-    if let Ok(Some(mut user)) = users.get(&db, &payload.name.clone()).await {
-        let mut hasher = DefaultHasher::new();
-        payload.pass.hash(&mut hasher);
-        user.hash = hasher.finish();
+async fn revoke_invite_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<RevokeInviteIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let invites = state.invites.read().await;
+    invites.revoke(&db, &payload.token).await?;
 
-        users
-            .put(&db, &payload.name.to_lowercase(), user.clone())
-            .await
-            .ok();
+    Ok(StatusCode::OK)
+}
+
+async fn lockouts_get(
+    State(state): State<crate::AppState>,
+) -> Result<Json<Vec<db::LoginAttempts>>, String> {
+    let db = state.db.read().await;
+    let throttle = state.throttle.read().await;
+    Ok(Json(throttle.locked(&db).await?))
+}
+
+async fn unlock_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<UnlockIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let throttle = state.throttle.read().await;
+    throttle.unlock(&db, &payload.key).await?;
+
+    Ok(StatusCode::OK)
+}
+
+async fn settings_get(State(state): State<crate::AppState>) -> Result<Json<db::Settings>, String> {
+    let db = state.db.read().await;
+    let settings = state.settings.read().await;
+    Ok(Json(settings.get(&db).await?))
+}
+
+async fn settings_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<SettingsIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let settings = state.settings.read().await;
+    let mut current = settings.get(&db).await?;
+    if let Some(require_admin_totp) = payload.require_admin_totp {
+        current.require_admin_totp = require_admin_totp;
+    }
+    if let Some(min_password_length) = payload.min_password_length {
+        current.min_password_length = min_password_length;
+    }
+    if let Some(password_needs_digit) = payload.password_needs_digit {
+        current.password_needs_digit = password_needs_digit;
+    }
+    if let Some(password_needs_symbol) = payload.password_needs_symbol {
+        current.password_needs_symbol = password_needs_symbol;
+    }
+    if let Some(days) = payload.deleted_retention_days {
+        if days < 0 {
+            return Ok((StatusCode::BAD_REQUEST, "The retention can't be negative").into_response());
+        }
+        if days > tombstone_store::MAX_RETENTION_DAYS {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "The retention can't be over {} days",
+                    tombstone_store::MAX_RETENTION_DAYS
+                ),
+            )
+                .into_response());
+        }
+        current.deleted_retention_days = days;
     }
 
-    StatusCode::OK
+    match settings.put(&db, current).await {
+        Ok(()) => Ok(StatusCode::OK.into_response()),
+        Err(db::WriteError::Conflict) => Ok(StatusCode::CONFLICT.into_response()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Returns the token for a `/reset_password?token=` link, so the admin never
+// learns the new password.
+async fn reset_link_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    Json(payload): Json<ResetLinkIn>,
+) -> Result<impl IntoResponse, String> {
+    let hours = payload.hours.unwrap_or(DEFAULT_RESET_HOURS);
+    if !(1..=MAX_RESET_HOURS).contains(&hours) {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            format!("`hours` must be between 1 and {}", MAX_RESET_HOURS),
+        )
+            .into_response());
+    }
+    let db = state.db.read().await;
+    let key = payload.name.to_lowercase();
+    if state.users.write().await.get(&db, &key).await?.is_none() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let resets = state.password_resets.read().await;
+    let reset = resets
+        .create(&db, &session.user.name, &key, Duration::hours(hours))
+        .await?;
+    Ok(Json(reset.token).into_response())
 }
 
 async fn delete_user_post(
     State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
     Json(payload): Json<DeleteUserIn>,
 ) -> Result<impl IntoResponse, String> {
     let db = state.db.read().await;
     let mut users = state.users.write().await;
+    // The account can be restored for a while, but not its sessions, tokens
+    // or reset links.
+    if let Some(user) = users.get(&db, &payload.name.to_lowercase()).await? {
+        let tombstones = state.tombstones.read().await;
+        tombstones
+            .bury(&db, &session.user.name, db::Deleted::User(user))
+            .await?;
+    }
     users.delete(&db, &payload.name.to_lowercase()).await?;
 
+    let sessions = state.sessions.read().await;
+    sessions
+        .remove_user(&db, &payload.name.to_lowercase(), None)
+        .await?;
+    let api_tokens = state.api_tokens.read().await;
+    api_tokens
+        .remove_user(&db, &payload.name.to_lowercase())
+        .await?;
+    let resets = state.password_resets.read().await;
+    resets
+        .remove_user(&db, &payload.name.to_lowercase())
+        .await?;
+
+    Ok(StatusCode::OK)
+}
+
+// The address the user logs in with through OpenID Connect.
+async fn set_email_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<SetEmailIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let mut users = state.users.write().await;
+    let key = payload.name.to_lowercase();
+    let Some(user) = users.get(&db, &key).await? else {
+        return Ok(StatusCode::NOT_FOUND);
+    };
+    let email = payload
+        .email
+        .map(|email| email.trim().to_string())
+        .filter(|email| !email.is_empty());
+    users
+        .diff_update(&db, &key, &user, |u| u.email = email)
+        .await?;
+
     Ok(StatusCode::OK)
 }
+
+async fn audit_get(
+    State(state): State<crate::AppState>,
+    Query(query): Query<audit::AuditQuery>,
+) -> Result<Json<Vec<db::AuditEntry>>, String> {
+    let db = state.db.read().await;
+    let audit = state.audit.read().await;
+    Ok(Json(audit.query(&db, &query).await?))
+}
+
+async fn deleted_get(
+    State(state): State<crate::AppState>,
+) -> Result<Json<Vec<TombstoneOut>>, String> {
+    let db = state.db.read().await;
+    let retention = tombstone_store::retention(&state.settings.read().await.get(&db).await?);
+    let tombstones = state.tombstones.read().await;
+    Ok(Json(
+        tombstones
+            .list(&db, retention)
+            .await?
+            .into_iter()
+            .map(|tombstone| {
+                let (kind, description) = match tombstone.item {
+                    db::Deleted::Note {
+                        student,
+                        note_type,
+                        note,
+                    } => (
+                        "note",
+                        format!(
+                            "{} of {} by {}: {}",
+                            note_type, student, note.user, note.content
+                        ),
+                    ),
+                    db::Deleted::Student(student) => ("student", student.name),
+                    db::Deleted::User(user) => ("user", user.name),
+                };
+                TombstoneOut {
+                    id: tombstone.id,
+                    kind,
+                    description,
+                    deleted_by: tombstone.deleted_by,
+                    deleted: tombstone.deleted,
+                    expires: tombstone.deleted + retention.num_seconds(),
+                }
+            })
+            .collect(),
+    ))
+}
+
+// Puts a deleted note, student or user back. Students and users are only
+// restored if nothing has taken their place since.
+async fn restore_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<RestoreIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let retention = tombstone_store::retention(&state.settings.read().await.get(&db).await?);
+    let tombstones = state.tombstones.read().await;
+    let Some(tombstone) = tombstones.get(&db, &payload.id, retention).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let result = match tombstone.item {
+        db::Deleted::Note {
+            student,
+            note_type,
+            note,
+        } => {
+            let categories = state.categories.read().await;
+            if categories.get(&db, &note_type).await?.is_none() {
+                return Ok((StatusCode::CONFLICT, "The category no longer exists").into_response());
+            }
+            let mut students = state.students.write().await;
+            let Some(original) = students.get(&db, &student).await? else {
+                return Ok((StatusCode::CONFLICT, "The student no longer exists").into_response());
+            };
+            students
+                .diff_update(&db, &student, &original, |s| {
+                    let notes = s.notes.entry(note_type).or_default();
+                    if !notes.iter().any(|n| n.id == note.id) {
+                        notes.push(note);
+                        notes.sort_by_key(|n| n.id);
+                    }
+                })
+                .await
+        }
+        db::Deleted::Student(student) => {
+            let mut students = state.students.write().await;
+            if students.get(&db, &student.id).await?.is_some() {
+                return Ok((StatusCode::CONFLICT, "The student exists again").into_response());
+            }
+            let student = db::Student {
+                revision: 0,
+                ..student
+            };
+            students.put(&db, &student.id.clone(), student).await
+        }
+        db::Deleted::User(user) => {
+            let mut users = state.users.write().await;
+            if users.get(&db, &user.primary_key).await?.is_some() {
+                return Ok(
+                    (StatusCode::CONFLICT, "Someone else has taken the username").into_response(),
+                );
+            }
+            let user = db::User {
+                revision: 0,
+                ..user
+            };
+            users.put(&db, &user.primary_key.clone(), user).await
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            tombstones.remove(&db, &tombstone.id).await?;
+            Ok(StatusCode::OK.into_response())
+        }
+        Err(db::WriteError::Conflict) => Ok(StatusCode::CONFLICT.into_response()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Adds a note category, or changes the one with the given id. Ids can't
+// change, since they are what students' notes are stored under.
+async fn category_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<CategoryIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let categories = state.categories.read().await;
+    let name = payload.name.trim().to_string();
+    if name.is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, "Categories need a name").into_response());
+    }
+
+    let category = match payload.id {
+        Some(id) => {
+            let Some(existing) = categories.get(&db, &id).await? else {
+                return Ok(StatusCode::NOT_FOUND.into_response());
+            };
+            db::Category {
+                name,
+                order: payload.order,
+                editable: payload.editable,
+                ..existing
+            }
+        }
+        None => {
+            let id = categories::id_for(&name);
+            // "assigned" is taken by the route that assigns a sensei.
+            if id.is_empty() || id == "assigned" {
+                return Ok((StatusCode::BAD_REQUEST, "Pick a different name").into_response());
+            }
+            if categories.get(&db, &id).await?.is_some() {
+                return Ok(
+                    (StatusCode::CONFLICT, "A category with that name exists").into_response()
+                );
+            }
+            db::Category {
+                id,
+                name,
+                order: payload.order,
+                editable: payload.editable,
+                revision: 0,
+            }
+        }
+    };
+
+    match categories.put(&db, category).await {
+        Ok(()) => Ok(StatusCode::OK.into_response()),
+        Err(db::WriteError::Conflict) => Ok(StatusCode::CONFLICT.into_response()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Only categories no student has notes in can be deleted, so no notes are
+// lost along with them.
+async fn delete_category_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<DeleteCategoryIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let mut students = state.students.write().await;
+    if students
+        .get_values(&db)
+        .await?
+        .iter()
+        .any(|student| !student.notes_in(&payload.id).is_empty())
+    {
+        return Ok((StatusCode::CONFLICT, "Students still have notes in it").into_response());
+    }
+
+    let categories = state.categories.read().await;
+    categories.delete(&db, &payload.id).await?;
+    Ok(StatusCode::OK.into_response())
+}
+
+// Indexes every student and imported student again, e.g. after notes were
+// changed in the database directly.
+async fn rebuild_search_post(
+    State(state): State<crate::AppState>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let students = state.students.write().await.get_values(&db).await?;
+    let imported = state.imported.write().await.get_values(&db).await?;
+    state.search.write().unwrap().rebuild(&students, &imported);
+    Ok(StatusCode::OK)
+}
+
+async fn sessions_get(
+    State(state): State<crate::AppState>,
+) -> Result<Json<Vec<SessionOut>>, String> {
+    let db = state.db.read().await;
+    let sessions = state.sessions.read().await;
+    let mut users = state.users.write().await;
+    let now = Utc::now().timestamp();
+    let mut out = vec![];
+    for session in sessions.get_values(&db).await? {
+        if session.expires < now {
+            continue;
+        }
+        let user = match &session.kiosk {
+            Some(kiosk) => kiosk::device_user(kiosk),
+            None => match users.get(&db, &session.user).await? {
+                Some(user) => user,
+                None => continue,
+            },
+        };
+        out.push(SessionOut {
+            id: session.id,
+            name: user.name,
+            role: user.role,
+            created: session.created,
+            expires: session.expires,
+            ip: session.ip,
+            user_agent: session.user_agent,
+        });
+    }
+    Ok(Json(out))
+}
+
+async fn revoke_session_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<RevokeSessionIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let sessions = state.sessions.read().await;
+    for session in sessions.get_values(&db).await? {
+        if session.id == payload.id {
+            sessions.remove(&db, &session.token).await?;
+        }
+    }
+
+    Ok(StatusCode::OK)
+}
+
+async fn revoke_user_sessions_post(
+    State(state): State<crate::AppState>,
+    Json(payload): Json<DeleteUserIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let sessions = state.sessions.read().await;
+    sessions
+        .remove_user(&db, &payload.name.to_lowercase(), None)
+        .await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::MAX_INVITE_HOURS;
+    use crate::config::Config;
+    use crate::db::{self, UserRole};
+    use crate::test_harness::{student, TestApp};
+    use crate::tombstone_store;
+
+    #[tokio::test]
+    async fn admin_routes_require_admin() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+
+        assert_eq!(
+            app.get("/api/admin/users").await.status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            app.send_json(Method::POST, "/api/admin/clear_times", &json!({}))
+                .await
+                .status,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn list_and_delete_users() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        app.create_user("Sensei", "password", UserRole::Standard)
+            .await;
+
+        let users = app.get("/api/admin/users").await;
+        assert_eq!(users.status, StatusCode::OK);
+        let mut names = users
+            .json::<Vec<serde_json::Value>>()
+            .iter()
+            .map(|u| u["name"].as_str().unwrap().to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, vec!["Admin", "Sensei"]);
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/delete_user",
+                &json!({ "name": "Sensei" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let users = app.get("/api/admin/users").await;
+        assert_eq!(users.json::<Vec<serde_json::Value>>().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn deleted_notes_students_and_users_can_be_restored() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        app.create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+        app.put_student(student("s2", "Alan Turing")).await;
+        app.send_json(
+            Method::PUT,
+            "/api/students/s1/behaviours",
+            &json!({ "note": "Helped a friend" }),
+        )
+        .await;
+        let note_id = app.get_student("s1").await.unwrap().notes_in("behaviours")[0].id;
+
+        app.send_json(Method::DELETE, "/api/students/s1/behaviours", &note_id)
+            .await;
+        let res = app
+            .send_json(Method::DELETE, "/api/students/s2", &json!(null))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        app.send_json(
+            Method::POST,
+            "/api/admin/delete_user",
+            &json!({ "name": "Sensei" }),
+        )
+        .await;
+        assert!(app
+            .get_student("s1")
+            .await
+            .unwrap()
+            .notes_in("behaviours")
+            .is_empty());
+        assert!(app.get_student("s2").await.is_none());
+
+        let deleted: Vec<serde_json::Value> = app.get("/api/admin/deleted").await.json();
+        let kinds: Vec<&str> = deleted
+            .iter()
+            .map(|d| d["kind"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds.len(), 3);
+        for kind in ["note", "student", "user"] {
+            assert!(kinds.contains(&kind));
+        }
+        assert!(deleted.iter().all(|d| d["deleted_by"] == "Admin"));
+
+        for tombstone in &deleted {
+            let res = app
+                .send_json(
+                    Method::POST,
+                    "/api/admin/restore",
+                    &json!({ "id": tombstone["id"] }),
+                )
+                .await;
+            assert_eq!(res.status, StatusCode::OK);
+        }
+        let restored = app.get_student("s1").await.unwrap();
+        assert_eq!(
+            restored.notes_in("behaviours")[0].content,
+            "Helped a friend"
+        );
+        assert_eq!(app.get_student("s2").await.unwrap().name, "Alan Turing");
+        let mut sensei = TestApp::from_state(app.state.clone());
+        assert_eq!(
+            sensei.login("Sensei", "password").await.status,
+            StatusCode::OK
+        );
+        let deleted: Vec<serde_json::Value> = app.get("/api/admin/deleted").await.json();
+        assert!(deleted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retention_is_bounded() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        for days in [-1, tombstone_store::MAX_RETENTION_DAYS + 1, i64::MAX] {
+            let res = app
+                .send_json(
+                    Method::POST,
+                    "/api/admin/settings",
+                    &json!({ "deleted_retention_days": days }),
+                )
+                .await;
+            assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        }
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/settings",
+                &json!({ "deleted_retention_days": tombstone_store::MAX_RETENTION_DAYS }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(app.get("/api/admin/deleted").await.status, StatusCode::OK);
+
+        // Settings saved before there was a maximum are clamped when used.
+        let too_long = db::Settings {
+            deleted_retention_days: i64::MAX,
+            ..db::Settings::default()
+        };
+        assert_eq!(
+            tombstone_store::retention(&too_long),
+            chrono::Duration::days(tombstone_store::MAX_RETENTION_DAYS)
+        );
+    }
+
+    #[tokio::test]
+    async fn gen_token_creates_invite() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+
+        let token = app
+            .send_json(
+                Method::POST,
+                "/api/admin/gen_token",
+                &json!({ "role": "Admin", "name": "Sensei", "hours": 24 }),
+            )
+            .await
+            .json::<String>();
+
+        let invites = app.get("/api/admin/invites").await;
+        assert_eq!(invites.status, StatusCode::OK);
+        let invites = invites.json::<Vec<serde_json::Value>>();
+        assert_eq!(invites.len(), 1);
+        assert_eq!(invites[0]["token"], token.as_str());
+        assert_eq!(invites[0]["creator"], "Admin");
+        assert_eq!(invites[0]["role"], "Admin");
+        assert_eq!(invites[0]["name"], "Sensei");
+
+        for hours in [0, -1, MAX_INVITE_HOURS + 1, i64::MAX] {
+            let res = app
+                .send_json(
+                    Method::POST,
+                    "/api/admin/gen_token",
+                    &json!({ "hours": hours }),
+                )
+                .await;
+            assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn revoked_invites_cannot_be_used() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let token = app
+            .send_json(Method::POST, "/api/admin/gen_token", &json!({}))
+            .await
+            .json::<String>();
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/revoke_invite",
+                &json!({ "token": token }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(app
+            .get("/api/admin/invites")
+            .await
+            .json::<Vec<serde_json::Value>>()
+            .is_empty());
+
+        let res = app.redeem_invite(&token, "Sensei", "hunter2").await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.text(), "This invite does not exist or was revoked");
+    }
+
+    #[tokio::test]
+    async fn locked_accounts_can_be_unlocked() {
+        let mut app = TestApp::with_config(Config {
+            lockout_threshold: 3,
+            ..Config::default()
+        })
+        .await;
+        app.create_user("Admin", "password", UserRole::Admin).await;
+        app.create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        app.login("Admin", "password").await;
+
+        let mut guesser = TestApp::from_state(app.state.clone());
+        guesser.addr = "10.0.0.2:50000".parse().unwrap();
+        for _ in 0..3 {
+            guesser.login("Sensei", "wrong").await;
+        }
+        assert_eq!(
+            guesser.login("Sensei", "password").await.status,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let lockouts = app
+            .get("/api/admin/lockouts")
+            .await
+            .json::<Vec<serde_json::Value>>();
+        let users = lockouts
+            .iter()
+            .filter(|l| l["kind"] == "User")
+            .collect::<Vec<&serde_json::Value>>();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0]["subject"], "sensei");
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/unlock",
+                &json!({ "key": users[0]["key"] }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            guesser.login("Sensei", "password").await.status,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn list_and_revoke_sessions() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        app.create_user("Sensei", "password", UserRole::Standard)
+            .await;
+
+        let mut tablet = TestApp::from_state(app.state.clone());
+        tablet.addr = "10.0.0.2:50000".parse().unwrap();
+        tablet
+            .request(
+                Method::POST,
+                "/api/login",
+                Some("application/json"),
+                json!({ "name": "Sensei", "pass": "password" })
+                    .to_string()
+                    .into(),
+                &[("User-Agent", "Dojo Tablet")],
+            )
+            .await;
+        let mut laptop = TestApp::from_state(app.state.clone());
+        laptop.login("Sensei", "password").await;
+
+        let sessions = app
+            .get("/api/admin/sessions")
+            .await
+            .json::<Vec<serde_json::Value>>();
+        assert_eq!(sessions.iter().filter(|s| s["name"] == "Sensei").count(), 2);
+        let on_tablet = sessions
+            .iter()
+            .find(|s| s["user_agent"] == "Dojo Tablet")
+            .unwrap();
+        assert_eq!(on_tablet["name"], "Sensei");
+        assert_eq!(on_tablet["ip"], "10.0.0.2");
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/revoke_session",
+                &json!({ "id": on_tablet["id"] }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            tablet.get("/api/role").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(laptop.get("/api/role").await.status, StatusCode::OK);
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/revoke_user_sessions",
+                &json!({ "name": "Sensei" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            laptop.get("/api/role").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(app.get("/api/role").await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_user_ends_their_sessions() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        app.create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        let mut sensei = TestApp::from_state(app.state.clone());
+        sensei.login("Sensei", "password").await;
+
+        app.send_json(
+            Method::POST,
+            "/api/admin/delete_user",
+            &json!({ "name": "Sensei" }),
+        )
+        .await;
+        assert_eq!(
+            sensei.get("/api/role").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
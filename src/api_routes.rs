@@ -1,24 +1,29 @@
+use std::collections::{BTreeMap, HashMap};
 use std::io::Read;
-use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
-};
 
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware,
+    response::{IntoResponse, Response},
     routing, Extension, Json, Router,
 };
-use chrono::{Datelike, Duration, Local};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, Utc};
 
 use crate::counter::Counter;
 use reqwest::StatusCode;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::db;
+use crate::{
+    db, password,
+    permissions::{self, Permission},
+    search::{SearchHit, SearchQuery},
+    student_query::StudentQuery,
+};
 
 #[derive(Deserialize)]
 struct ChangePassIn {
+    current_pass: String,
     new_pass: String,
 }
 
@@ -33,6 +38,114 @@ struct StudentNotePatch {
     note: String,
 }
 
+#[derive(Deserialize)]
+struct NoteRestoreIn {
+    // Index into the note's `revisions`.
+    revision: usize,
+}
+
+// Limits the notes returned to those created between `from` and `to`, both
+// inclusive. Either is an ISO 8601 timestamp, or a date which covers the whole
+// day in the server's timezone.
+#[derive(Deserialize, Default)]
+struct NoteRange {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl NoteRange {
+    fn is_set(&self) -> bool {
+        self.from.is_some() || self.to.is_some()
+    }
+
+    fn bounds(&self) -> Result<(DateTime<Local>, DateTime<Local>), String> {
+        let parse = |value: &str, end_of_day: bool| {
+            if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+                return Ok(time.with_timezone(&Local));
+            }
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| match end_of_day {
+                    true => date.and_hms_nano_opt(23, 59, 59, 999_999_999),
+                    false => date.and_hms_opt(0, 0, 0),
+                })
+                .and_then(|time| time.and_local_timezone(Local).earliest())
+                .ok_or(format!("`{}` is not an ISO 8601 date or timestamp", value))
+        };
+        let from = match &self.from {
+            Some(from) => parse(from, false)?,
+            None => DateTime::<Utc>::MIN_UTC.with_timezone(&Local),
+        };
+        let to = match &self.to {
+            Some(to) => parse(to, true)?,
+            None => DateTime::<Utc>::MAX_UTC.with_timezone(&Local),
+        };
+        Ok((from, to))
+    }
+}
+
+// Pages through each category's notes, oldest first, or through only the one
+// in `category`.
+#[derive(Deserialize)]
+struct NotePaging {
+    category: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+const NOTE_PAGE_SIZE: usize = 50;
+
+#[derive(Serialize)]
+struct NotePage {
+    // Notes in the category, including those not on this page.
+    total: usize,
+    offset: usize,
+    notes: Vec<db::Note>,
+}
+
+#[derive(Serialize)]
+struct StudentDetail {
+    id: String,
+    name: String,
+    first_name: String,
+    last_name: String,
+    belt: String,
+    assigned: Option<String>,
+    // "mystudio" for students synced from MyStudio, "imported" for those only
+    // known from the CSV import so far.
+    source: &'static str,
+    // The latest check-in, even if it wasn't today.
+    date: Option<DateTime<Local>>,
+    time: Option<String>,
+    check_ins: Vec<db::CheckIn>,
+    notes: BTreeMap<String, NotePage>,
+    revision: u64,
+}
+
+// The revision the client last saw, sent as `If-Match: <revision>`.
+fn expected_revision(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::IF_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim_matches('"').parse().ok())
+}
+
+// Sent back when a write lost a race with someone else's, along with the
+// stored record so the client can merge its change into it.
+async fn conflict_response(
+    students: &mut db::CachingColumn<db::Student>,
+    db: &db::Backend,
+    id: &str,
+) -> Response {
+    match students.get(db, id).await {
+        Ok(Some(student)) => (StatusCode::CONFLICT, Json(student)).into_response(),
+        _ => StatusCode::CONFLICT.into_response(),
+    }
+}
+
+// Which of the note permissions a change needs depends on who wrote the note,
+// so those are checked in the handlers instead.
 pub fn routes() -> Router<crate::AppState> {
     Router::new()
         .route("/change_pass", routing::post(change_pass_post))
@@ -42,184 +155,448 @@ pub fn routes() -> Router<crate::AppState> {
                 .delete(student_note_delete)
                 .patch(student_note_patch),
         )
-        .route("/students", routing::get(students_get))
-        .route("/senseis", routing::get(senseis_get))
-        .route("/load_csv", routing::post(load_csv_post))
+        .route(
+            "/students/:id/:note_type/:note_id/restore",
+            routing::post(note_restore_post),
+        )
+        .merge(
+            Router::new()
+                .route("/students", routing::get(students_get))
+                .route("/students/:id", routing::get(student_get))
+                .route("/senseis", routing::get(senseis_get))
+                .route("/categories", routing::get(categories_get))
+                .route("/search", routing::get(search_get))
+                .route(
+                    "/students/:id/:note_type/:note_id/history",
+                    routing::get(note_history_get),
+                )
+                .route_layer(middleware::from_fn_with_state(
+                    Permission::ViewStudents,
+                    permissions::require_layer,
+                )),
+        )
+        .merge(
+            Router::new()
+                .route("/students/:id", routing::delete(student_delete))
+                .route_layer(middleware::from_fn_with_state(
+                    Permission::DeleteStudents,
+                    permissions::require_layer,
+                )),
+        )
+        .merge(
+            Router::new()
+                .route("/load_csv", routing::post(load_csv_post))
+                .route_layer(middleware::from_fn_with_state(
+                    Permission::ImportCsv,
+                    permissions::require_layer,
+                )),
+        )
+}
+
+// The category `note_type` names, if it is one.
+async fn note_category(
+    state: &crate::AppState,
+    db: &db::Backend,
+    note_type: &str,
+) -> Result<Option<db::Category>, String> {
+    state.categories.read().await.get(db, note_type).await
+}
+
+fn note_author(student: &db::Student, note_type: &str, note_id: u32) -> Option<String> {
+    student
+        .notes_in(note_type)
+        .iter()
+        .find(|note| note.id == note_id)
+        .map(|note| note.user.clone())
+}
+
+// Whether the session may change a note written by `author`, given the
+// permission needed for other people's notes.
+fn may_change_note(session: &db::Session, author: Option<String>, others: Permission) -> bool {
+    match author {
+        Some(author) if author != session.user.name => session.can(others),
+        _ => session.can(Permission::EditOwnNotes),
+    }
 }
 
 async fn change_pass_post(
     State(state): State<crate::AppState>,
     Extension(session): Extension<db::Session>,
     Json(payload): Json<ChangePassIn>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, String> {
     let db = state.db.read().await;
+    let settings = state.settings.read().await.get(&db).await?;
+    if let Err(e) = password::check_policy(&settings, &payload.new_pass) {
+        return Ok((StatusCode::BAD_REQUEST, e).into_response());
+    }
+
+    // Counted like failed logins, so a stolen session can't be used to guess
+    // the password.
+    let throttle = state.throttle.read().await;
+    let key = session.user.primary_key.clone();
+    if let Some(wait) = throttle.check(&db, &key, None).await? {
+        return Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait.to_string())],
+            format!("Too many failed attempts, try again in {} seconds", wait),
+        )
+            .into_response());
+    }
 
     let mut users = state.users.write().await;
-    if let Ok(Some(mut user)) = users.get(&db, &session.user.name.clone()).await {
-        let original_user = user.clone();
-        let mut hasher = DefaultHasher::new();
-        payload.new_pass.hash(&mut hasher);
-        user.hash = hasher.finish();
+    let Some(user) = users.get(&db, &key).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    if !password::verify(&user, &payload.current_pass) {
+        throttle.record_failure(&db, &key, None).await?;
+        return Ok((StatusCode::FORBIDDEN, "Your current password is wrong").into_response());
+    }
+    let password_hash = password::hash(&payload.new_pass)?;
 
-        users
-            .diff_update(&db, &user.name, &original_user, |u| u.hash = user.hash)
-            .await
-            .ok();
+    let written = users
+        .diff_update(&db, &user.primary_key, &user, |u| {
+            u.hash = None;
+            u.password_hash = password_hash;
+        })
+        .await;
+    match written {
+        Err(db::WriteError::Conflict) => {
+            return Ok((
+                StatusCode::CONFLICT,
+                "Your account was changed at the same time, try again",
+            )
+                .into_response());
+        }
+        written => written?,
     }
 
-    StatusCode::OK
+    // Anyone else signed in with the old password is logged out.
+    let sessions = state.sessions.read().await;
+    sessions
+        .remove_user(&db, &user.primary_key, Some(&session.token))
+        .await?;
+
+    Ok(StatusCode::OK.into_response())
 }
 
 async fn student_note_put(
     Extension(session): Extension<db::Session>,
     Path((id, note_type)): Path<(String, String)>,
     State(state): State<crate::AppState>,
+    headers: HeaderMap,
     Json(payload): Json<StudentNotePut>,
 ) -> Result<impl IntoResponse, String> {
     let db = state.db.read().await;
-    if !["logins", "notes", "behaviours", "assigned"].contains(&note_type.as_str()) {
+    if note_type != "assigned" && note_category(&state, &db, &note_type).await?.is_none() {
         return Ok((
             StatusCode::BAD_REQUEST,
-            "`note_type` must be one of ['logins', 'notes', 'behaviours', 'assigned']",
-        ));
+            "`note_type` must be a note category or 'assigned'",
+        )
+            .into_response());
+    }
+    let needed = if note_type == "assigned" {
+        Permission::AssignStudents
+    } else {
+        Permission::EditOwnNotes
+    };
+    if !session.can(needed) {
+        return Ok(StatusCode::FORBIDDEN.into_response());
     }
     let mut students = state.students.write().await;
     let original_student = students.get(&db, id.as_str()).await;
-    if let Ok(Some(original_student)) = original_student {
-        let result = students
-            .diff_update(&db, &id.clone(), &original_student, |student| {
-                let date = chrono::Local::now().format("%m-%d-%y").to_string();
-                match note_type.as_str() {
-                    "logins" => student.logins.push(db::Note {
-                        id: student.note_counter.inc(),
-                        date,
-                        user: session.user.name.clone(),
-                        content: payload.note.clone(),
-                    }),
-                    "notes" => student.notes.push(db::Note {
-                        id: student.note_counter.inc(),
-                        date,
-                        user: session.user.name.clone(),
-                        content: payload.note.clone(),
-                    }),
-                    "behaviours" => student.behaviours.push(db::Note {
-                        id: student.note_counter.inc(),
-                        date,
+    let Ok(Some(original_student)) = original_student else {
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note.").into_response());
+    };
+    if expected_revision(&headers).is_some_and(|r| r != original_student.revision) {
+        return Ok(conflict_response(&mut students, &db, &id).await);
+    }
+    // A new note takes its id from the stored counter and is appended to the
+    // stored list, so unlike `assigned` it never conflicts with other writes.
+    let note_id = if note_type == "assigned" {
+        None
+    } else {
+        match students.increment(&db, &id, db::NOTE_COUNTER).await {
+            Ok(n) => Some(n as u32),
+            Err(e) => {
+                println!("{}", e);
+                return Ok(
+                    (StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note.").into_response(),
+                );
+            }
+        }
+    };
+    // Taking an id bumped the revision, so start from the newer copy.
+    let original_student = match note_id {
+        None => Some(original_student),
+        Some(_) => students.get(&db, id.as_str()).await.ok().flatten(),
+    };
+    let Some(original_student) = original_student else {
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note.").into_response());
+    };
+    let result = students
+        .diff_update(
+            &db,
+            &id.clone(),
+            &original_student,
+            |student| match note_id {
+                None => student.assigned = Some(payload.note.clone()),
+                Some(note_id) => {
+                    let note = db::Note {
+                        id: note_id,
+                        created: Some(Local::now()),
+                        edited: None,
                         user: session.user.name.clone(),
                         content: payload.note.clone(),
-                    }),
-                    "assigned" => student.assigned = Some(payload.note.clone()),
-                    _ => {}
+                        revisions: vec![],
+                    };
+                    student
+                        .notes
+                        .entry(note_type.clone())
+                        .or_default()
+                        .push(note);
                 }
-            })
-            .await;
+            },
+        )
+        .await;
 
-        if result.is_err() {
-            Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note."))
-        } else {
-            Ok((StatusCode::OK, ""))
-        }
-    } else {
-        Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note."))
+    match result {
+        Ok(()) => Ok((StatusCode::OK, "").into_response()),
+        Err(db::WriteError::Conflict) => Ok(conflict_response(&mut students, &db, &id).await),
+        Err(_) => Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note.").into_response()),
     }
 }
 
-#[allow(unused)]
 async fn student_note_patch(
+    Extension(session): Extension<db::Session>,
     Path((id, note_type)): Path<(String, String)>,
     State(state): State<crate::AppState>,
+    headers: HeaderMap,
     Json(payload): Json<StudentNotePatch>,
 ) -> Result<impl IntoResponse, String> {
+    revise_note(
+        &state,
+        &session,
+        &headers,
+        (&id, &note_type, payload.id),
+        |_| Some(payload.note),
+    )
+    .await
+}
+
+// The note's earlier versions, oldest first.
+async fn note_history_get(
+    Path((id, note_type, note_id)): Path<(String, String, u32)>,
+    State(state): State<crate::AppState>,
+) -> Result<Response, String> {
+    let db = state.db.read().await;
+    let mut students = state.students.write().await;
+    let Some(student) = students.get(&db, &id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    match student
+        .notes_in(&note_type)
+        .iter()
+        .find(|n| n.id == note_id)
+    {
+        Some(note) => Ok(Json(note.revisions.clone()).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+// Brings back what the note said in one of its revisions. The content it
+// replaces becomes a revision itself, so restoring can be undone too.
+async fn note_restore_post(
+    Extension(session): Extension<db::Session>,
+    Path((id, note_type, note_id)): Path<(String, String, u32)>,
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<NoteRestoreIn>,
+) -> Result<impl IntoResponse, String> {
+    revise_note(
+        &state,
+        &session,
+        &headers,
+        (&id, &note_type, note_id),
+        |note| {
+            note.revisions
+                .get(payload.revision)
+                .map(|revision| revision.content.clone())
+        },
+    )
+    .await
+}
+
+// Replaces the content of a note with what `content` picks for it, keeping
+// the old content in the note's history.
+async fn revise_note(
+    state: &crate::AppState,
+    session: &db::Session,
+    headers: &HeaderMap,
+    (id, note_type, note_id): (&str, &str, u32),
+    content: impl FnOnce(&db::Note) -> Option<String>,
+) -> Result<Response, String> {
     let db = state.db.read().await;
 
-    if !["logins", "notes", "behaviours"].contains(&note_type.as_str()) {
-        return Ok((
-            StatusCode::BAD_REQUEST,
-            "`note_type` must be one of ['logins', 'notes', 'behaviours']",
-        ));
+    match note_category(state, &db, note_type).await? {
+        None => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                "`note_type` must be a note category",
+            )
+                .into_response())
+        }
+        Some(category) if !category.editable => {
+            return Ok((
+                StatusCode::FORBIDDEN,
+                "Notes in this category can't be edited",
+            )
+                .into_response())
+        }
+        Some(_) => {}
     }
     let mut students = state.students.write().await;
-    let original_student = students.get(&db, id.as_str()).await;
-    if let Ok(Some(original_student)) = original_student {
-        let result = students
-            .diff_update(&db, &id.clone(), &original_student, |student| {
-                macro_rules! edit_note {
-                    ($b:ident) => {{
-                        if let Some(idx) = student.$b.iter().position(|v| v.id == payload.id) {
-                            if let Some(v) = student.logins.get_mut(idx) {
-                                v.content = payload.note;
-                            }
-                        }
-                    }};
-                }
+    let original_student = students.get(&db, id).await;
+    let Ok(Some(original_student)) = original_student else {
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note.").into_response());
+    };
+    if expected_revision(headers).is_some_and(|r| r != original_student.revision) {
+        return Ok(conflict_response(&mut students, &db, id).await);
+    }
+    let author = note_author(&original_student, note_type, note_id);
+    if !may_change_note(session, author, Permission::EditOthersNotes) {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    let Some(content) = original_student
+        .notes_in(note_type)
+        .iter()
+        .find(|note| note.id == note_id)
+        .and_then(content)
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
 
-                match note_type.as_str() {
-                    "logins" => edit_note!(logins),
-                    "notes" => edit_note!(notes),
-                    "behaviours" => edit_note!(behaviours),
-                    _ => {}
-                }
-            })
-            .await;
+    let result = students
+        .diff_update(&db, id, &original_student, |student| {
+            let note = student
+                .notes
+                .get_mut(note_type)
+                .and_then(|notes| notes.iter_mut().find(|note| note.id == note_id));
+            if let Some(note) = note {
+                let previous = std::mem::replace(&mut note.content, content);
+                note.edited = Some(Local::now());
+                note.revisions.push(db::NoteRevision {
+                    content: previous,
+                    editor: session.user.name.clone(),
+                    edited: Local::now(),
+                });
+            }
+        })
+        .await;
 
-        if result.is_err() {
-            Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note."))
-        } else {
-            Ok((StatusCode::OK, ""))
-        }
-    } else {
-        Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note."))
+    match result {
+        Ok(()) => Ok((StatusCode::OK, "").into_response()),
+        Err(db::WriteError::Conflict) => Ok(conflict_response(&mut students, &db, id).await),
+        Err(_) => Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note.").into_response()),
     }
 }
 
 async fn student_note_delete(
+    Extension(session): Extension<db::Session>,
     Path((id, note_type)): Path<(String, String)>,
     State(state): State<crate::AppState>,
+    headers: HeaderMap,
     Json(note_id): Json<u32>,
 ) -> Result<impl IntoResponse, String> {
     let db = state.db.read().await;
 
-    if !["logins", "notes", "behaviours", "assigned"].contains(&note_type.as_str()) {
+    if note_type != "assigned" && note_category(&state, &db, &note_type).await?.is_none() {
         return Ok((
             StatusCode::BAD_REQUEST,
-            "`note_type` needs to be one of ['logins', 'notes', 'behaviours', 'assigned']",
-        ));
+            "`note_type` needs to be a note category or 'assigned'",
+        )
+            .into_response());
     }
     let mut students = state.students.write().await;
     let original_student = students.get(&db, id.as_str()).await;
     if let Ok(Some(original_student)) = original_student {
-        if students
+        if expected_revision(&headers).is_some_and(|r| r != original_student.revision) {
+            return Ok(conflict_response(&mut students, &db, &id).await);
+        }
+        let allowed = if note_type == "assigned" {
+            session.can(Permission::AssignStudents)
+        } else {
+            let author = note_author(&original_student, &note_type, note_id);
+            may_change_note(&session, author, Permission::DeleteNotes)
+        };
+        if !allowed {
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+        // Kept so the note can be restored, see `tombstone_store.rs`.
+        let deleted = original_student
+            .notes_in(&note_type)
+            .iter()
+            .find(|note| note.id == note_id)
+            .cloned();
+        let result = students
             .diff_update(&db, &id.clone(), &original_student, |student| {
-                macro_rules! remove_val {
-                    ($b:ident, $v:expr) => {{
-                        if let Some(index) = student.$b.iter().position(|v| v.to_owned().id == $v) {
-                            student.$b.remove(index);
-                        }
-                    }};
+                if note_type == "assigned" {
+                    student.assigned = None;
+                } else if let Some(notes) = student.notes.get_mut(&note_type) {
+                    notes.retain(|note| note.id != note_id);
                 }
+            })
+            .await;
 
-                match note_type.as_str() {
-                    "logins" => remove_val!(logins, note_id),
-                    "notes" => remove_val!(notes, note_id),
-                    "behaviours" => remove_val!(behaviours, note_id),
-                    "assigned" => {
-                        student.assigned = None;
+        match result {
+            Ok(()) => {
+                if let Some(note) = deleted {
+                    let item = db::Deleted::Note {
+                        student: id.clone(),
+                        note_type: note_type.clone(),
+                        note,
+                    };
+                    let tombstones = state.tombstones.read().await;
+                    if let Err(e) = tombstones.bury(&db, &session.user.name, item).await {
+                        println!("{}", e);
                     }
-                    _ => {}
                 }
-            })
-            .await
-            .is_err()
-        {
-            Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note"))
-        } else {
-            Ok((StatusCode::OK, ""))
+                Ok((StatusCode::OK, "").into_response())
+            }
+            Err(db::WriteError::Conflict) => Ok(conflict_response(&mut students, &db, &id).await),
+            Err(_) => {
+                Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note").into_response())
+            }
         }
     } else {
-        Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note"))
+        Ok((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update note").into_response())
     }
 }
 
+// The student can be restored by an admin for a while, like deleted notes, and
+// isn't added back by the MyStudio sync until then.
+async fn student_delete(
+    Extension(session): Extension<db::Session>,
+    Path(id): Path<String>,
+    State(state): State<crate::AppState>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let mut students = state.students.write().await;
+    let Some(student) = students.get(&db, &id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let tombstones = state.tombstones.read().await;
+    let tombstone = tombstones
+        .bury(&db, &session.user.name, db::Deleted::Student(student))
+        .await?;
+    // Otherwise restoring the tombstone would overwrite the student.
+    if let Err(e) = students.delete(&db, &id).await {
+        tombstones.remove(&db, &tombstone.id).await?;
+        return Err(e);
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
 async fn senseis_get(State(state): State<crate::AppState>) -> Result<Json<Vec<String>>, String> {
     let db = state.db.read().await;
     let mut users = state.users.write().await;
@@ -233,12 +610,136 @@ async fn senseis_get(State(state): State<crate::AppState>) -> Result<Json<Vec<St
     ))
 }
 
+async fn categories_get(
+    State(state): State<crate::AppState>,
+) -> Result<Json<Vec<db::Category>>, String> {
+    let db = state.db.read().await;
+    let categories = state.categories.read().await;
+    Ok(Json(categories.list(&db).await?))
+}
+
+// Notes and names matching `q`, best first, including those of students only
+// known from the CSV import.
+async fn search_get(
+    State(state): State<crate::AppState>,
+    Query(query): Query<SearchQuery>,
+) -> Json<Vec<SearchHit>> {
+    let index = state.search.read().unwrap();
+    Json(index.search(&query.q, query.limit))
+}
+
+// Students from the CSV import who haven't been synced from MyStudio yet.
+// Their notes are numbered in the order they were imported.
+pub fn imported_student(info: db::StudentImportedInfo) -> db::Student {
+    let mut note_id = 0u32;
+    let name = info.name.clone();
+    let belt = info.belt.clone();
+    let notes = info
+        .into_categories()
+        .into_iter()
+        .map(|(category, notes)| {
+            let notes = notes
+                .into_iter()
+                .map(|content| {
+                    note_id += 1;
+                    db::Note {
+                        id: note_id,
+                        created: None,
+                        edited: None,
+                        user: "".to_string(),
+                        content,
+                        revisions: vec![],
+                    }
+                })
+                .collect::<Vec<db::Note>>();
+            (category.to_string(), notes)
+        })
+        .collect();
+    let (first_name, last_name) = name.split_at(name.find(" ").unwrap_or(0));
+    db::Student {
+        first_name: first_name.to_string(),
+        last_name: last_name.to_string(),
+        id: "".to_string(),
+        name: name.to_string(),
+        date: None,
+        time: None,
+        belt,
+        notes,
+        assigned: None,
+        note_counter: Counter::from(0),
+        check_ins: vec![],
+        revision: 0,
+    }
+}
+
+// Everything known about one student. Students only known from the CSV import
+// have no id yet, so they are looked up by name instead.
+async fn student_get(
+    Path(id): Path<String>,
+    State(state): State<crate::AppState>,
+    Query(paging): Query<NotePaging>,
+) -> Result<Response, String> {
+    let db = state.db.read().await;
+    let mut students = state.students.write().await;
+    let (student, source) = match students.get(&db, &id).await? {
+        Some(student) => (student, "mystudio"),
+        None => {
+            let mut imported = state.imported.write().await;
+            match imported.get(&db, &id.to_lowercase()).await? {
+                Some(info) => (imported_student(info), "imported"),
+                None => return Ok(StatusCode::NOT_FOUND.into_response()),
+            }
+        }
+    };
+
+    let limit = paging.limit.unwrap_or(NOTE_PAGE_SIZE);
+    let notes = student
+        .notes
+        .into_iter()
+        .filter(|(category, _)| paging.category.as_ref().is_none_or(|c| c == category))
+        .map(|(category, notes)| {
+            let page = NotePage {
+                total: notes.len(),
+                offset: paging.offset,
+                notes: notes.into_iter().skip(paging.offset).take(limit).collect(),
+            };
+            (category, page)
+        })
+        .collect();
+
+    Ok(Json(StudentDetail {
+        id: student.id,
+        name: student.name,
+        first_name: student.first_name,
+        last_name: student.last_name,
+        belt: student.belt,
+        assigned: student.assigned,
+        source,
+        date: student.date,
+        time: student.time,
+        check_ins: student.check_ins,
+        notes,
+        revision: student.revision,
+    })
+    .into_response())
+}
+
+// The students matching `query`, with the notes `range` picks. Students from
+// the CSV import are included unless only those present are asked for.
 async fn students_get(
     State(state): State<crate::AppState>,
-) -> Result<Json<Vec<db::Student>>, String> {
+    Query(range): Query<NoteRange>,
+    Query(query): Query<StudentQuery>,
+) -> Result<Response, String> {
+    let bounds = range
+        .bounds()
+        .and_then(|bounds| query.validate().map(|_| bounds));
+    let (from, to) = match bounds {
+        Ok(bounds) => bounds,
+        Err(e) => return Ok((StatusCode::BAD_REQUEST, e).into_response()),
+    };
     let db = state.db.read().await;
     let mut students = state.students.write().await;
-    let mut imported = state.imported.write().await;
 
     let mut students_map: HashMap<String, db::Student> = HashMap::new();
     students
@@ -254,75 +755,38 @@ async fn students_get(
             }
             students_map.insert(s.name.to_lowercase(), s);
         });
-    imported
-        .get_values(&db)
-        .await?
-        .iter()
-        .cloned()
-        .map(|v| {
-            let mut note_id = 0u32;
-            let logins = v
-                .logins
-                .iter()
-                .map(|note| {
-                    note_id += 1;
-                    db::Note {
-                        id: note_id,
-                        date: "".to_string(),
-                        user: "".to_string(),
-                        content: note.clone(),
-                    }
-                })
-                .collect::<Vec<db::Note>>();
-            let notes = v
-                .notes
-                .iter()
-                .map(|note| {
-                    note_id += 1;
-                    db::Note {
-                        id: note_id,
-                        date: "".to_string(),
-                        user: "".to_string(),
-                        content: note.clone(),
-                    }
-                })
-                .collect::<Vec<db::Note>>();
-            let behaviours = v
-                .behaviours
-                .iter()
-                .map(|note| {
-                    note_id += 1;
-                    db::Note {
-                        id: note_id,
-                        date: "".to_string(),
-                        user: "".to_string(),
-                        content: note.clone(),
-                    }
-                })
-                .collect::<Vec<db::Note>>();
-            let (first_name, last_name) = v.name.split_at(v.name.find(" ").unwrap_or(0));
-            db::Student {
-                first_name: first_name.to_string(),
-                last_name: last_name.to_string(),
-                id: "".to_string(),
-                name: v.name.to_string(),
-                date: None,
-                time: None,
-                belt: v.belt,
-                logins,
-                notes,
-                behaviours,
-                assigned: None,
-                note_counter: Counter::from(0),
-            }
-        })
-        .for_each(|s| {
-            if !students_map.contains_key(&s.name.to_lowercase()) {
-                students_map.insert(s.name.to_lowercase(), s);
-            }
-        });
+    if !query.only_present() {
+        let mut imported = state.imported.write().await;
+        imported
+            .get_values(&db)
+            .await?
+            .iter()
+            .cloned()
+            .map(imported_student)
+            .for_each(|s| {
+                if !students_map.contains_key(&s.name.to_lowercase()) {
+                    students_map.insert(s.name.to_lowercase(), s);
+                }
+            });
+    }
 
-    Ok(Json(students_map.values().cloned().collect()))
+    let matching = students_map
+        .into_values()
+        .filter(|student| query.matches(student))
+        .collect();
+    let (mut students, next) = query.page(matching)?;
+    if range.is_set() {
+        for student in &mut students {
+            for notes in student.notes.values_mut() {
+                notes.retain(|note| note.created.is_some_and(|c| from <= c && c <= to));
+            }
+        }
+    }
+    let mut res = Json(students).into_response();
+    if let Some(next) = next.and_then(|next| HeaderValue::from_str(&next).ok()) {
+        res.headers_mut().insert("x-next-cursor", next);
+    }
+    Ok(res)
 }
 
 async fn load_csv_post(
@@ -426,3 +890,572 @@ async fn load_csv_post(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, StatusCode},
+    };
+    use serde_json::json;
+
+    use crate::db::{self, Column, Database, UserRole};
+    use crate::test_harness::{student, TestApp};
+
+    #[tokio::test]
+    async fn note_put_patch_delete() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+
+        let res = app
+            .send_json(
+                Method::PUT,
+                "/api/students/s1/logins",
+                &json!({ "note": "first" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let s = app.get_student("s1").await.unwrap();
+        assert_eq!(s.notes_in("logins").len(), 1);
+        assert_eq!(s.notes_in("logins")[0].content, "first");
+        assert_eq!(s.notes_in("logins")[0].user, "Sensei");
+        let note_id = s.notes_in("logins")[0].id;
+
+        let res = app
+            .send_json(
+                Method::PATCH,
+                "/api/students/s1/logins",
+                &json!({ "id": note_id, "note": "edited" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let s = app.get_student("s1").await.unwrap();
+        assert_eq!(s.notes_in("logins")[0].content, "edited");
+
+        let res = app
+            .send_json(Method::DELETE, "/api/students/s1/logins", &note_id)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(app
+            .get_student("s1")
+            .await
+            .unwrap()
+            .notes_in("logins")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn edits_keep_the_note_history() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+        for (note_type, note) in [("logins", "signed in"), ("behaviours", "first")] {
+            app.send_json(
+                Method::PUT,
+                &format!("/api/students/s1/{}", note_type),
+                &json!({ "note": note }),
+            )
+            .await;
+        }
+        let note_id = app.get_student("s1").await.unwrap().notes_in("behaviours")[0].id;
+
+        // The edit lands on the behaviour, not on the login at the same index.
+        let res = app
+            .send_json(
+                Method::PATCH,
+                "/api/students/s1/behaviours",
+                &json!({ "id": note_id, "note": "second" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let s = app.get_student("s1").await.unwrap();
+        assert_eq!(s.notes_in("logins")[0].content, "signed in");
+        assert_eq!(s.notes_in("behaviours")[0].content, "second");
+
+        let history_uri = format!("/api/students/s1/behaviours/{}/history", note_id);
+        let history: Vec<db::NoteRevision> = app.get(&history_uri).await.json();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "first");
+        assert_eq!(history[0].editor, "Sensei");
+
+        let restore_uri = format!("/api/students/s1/behaviours/{}/restore", note_id);
+        let res = app
+            .send_json(Method::POST, &restore_uri, &json!({ "revision": 0 }))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let s = app.get_student("s1").await.unwrap();
+        assert_eq!(s.notes_in("behaviours")[0].content, "first");
+        assert_eq!(s.notes_in("behaviours")[0].revisions[1].content, "second");
+
+        let res = app
+            .send_json(Method::POST, &restore_uri, &json!({ "revision": 5 }))
+            .await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn stale_if_match_returns_current_record() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+        let revision = app.get_student("s1").await.unwrap().revision;
+
+        let res = app
+            .request(
+                Method::PUT,
+                "/api/students/s1/assigned",
+                Some("application/json"),
+                Body::from(r#"{"note":"Sensei"}"#),
+                &[("If-Match", &revision.to_string())],
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let res = app
+            .request(
+                Method::PUT,
+                "/api/students/s1/assigned",
+                Some("application/json"),
+                Body::from(r#"{"note":"Other"}"#),
+                &[("If-Match", &revision.to_string())],
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::CONFLICT);
+        let current = res.json::<db::Student>();
+        assert_eq!(current.assigned, Some("Sensei".to_string()));
+        assert_eq!(current.revision, revision + 1);
+    }
+
+    #[tokio::test]
+    async fn note_put_retries_on_top_of_newer_record() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+        let cached = app.get_student("s1").await.unwrap();
+
+        // Another instance adds a note behind this instance's cache.
+        {
+            let db = app.state.db.read().await;
+            #[allow(deprecated)]
+            let uncached = db.column("students");
+            let mut other = cached.clone();
+            other.notes.get_mut("notes").unwrap().push(db::Note {
+                id: other.note_counter.inc(),
+                created: None,
+                edited: None,
+                user: "Other".to_string(),
+                content: "from elsewhere".to_string(),
+                revisions: vec![],
+            });
+            uncached.put(&db, "s1", other).await.unwrap();
+        }
+
+        let res = app
+            .send_json(
+                Method::PUT,
+                "/api/students/s1/notes",
+                &json!({ "note": "from here" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let s = app.get_student("s1").await.unwrap();
+        let notes: Vec<(u32, &str)> = s
+            .notes_in("notes")
+            .iter()
+            .map(|n| (n.id, n.content.as_str()))
+            .collect();
+        assert_eq!(notes, vec![(1, "from elsewhere"), (2, "from here")]);
+    }
+
+    #[tokio::test]
+    async fn note_put_rejects_unknown_note_type() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+
+        let res = app
+            .send_json(
+                Method::PUT,
+                "/api/students/s1/homework",
+                &json!({ "note": "first" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn assign_student() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+
+        app.send_json(
+            Method::PUT,
+            "/api/students/s1/assigned",
+            &json!({ "note": "Sensei" }),
+        )
+        .await;
+        let s = app.get_student("s1").await.unwrap();
+        assert_eq!(s.assigned, Some("Sensei".to_string()));
+    }
+
+    #[tokio::test]
+    async fn load_csv_imports_students() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+
+        let csv = "Name,Belt,Logins,Notes,Behaviours\n\
+                   Grace Hopper,Yellow,\"login one\r\nlogin two\",a note,\n";
+        let res = app
+            .post_multipart(
+                "/api/load_csv",
+                &[("row_data", "[1,2,3,4,5]"), ("file", csv)],
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK, "{}", res.text());
+
+        let students = app.get("/api/students").await.json::<Vec<db::Student>>();
+        assert_eq!(students.len(), 1);
+        assert_eq!(students[0].name, "Grace Hopper");
+        assert_eq!(students[0].belt, "Yellow");
+        assert_eq!(students[0].notes_in("logins").len(), 2);
+        assert_eq!(students[0].notes_in("notes").len(), 1);
+        assert!(students[0].notes_in("behaviours").is_empty());
+    }
+
+    #[tokio::test]
+    async fn notes_can_be_filtered_by_date() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        let mut s1 = student("s1", "Ada Lovelace");
+        let notes = s1.notes.get_mut("notes").unwrap();
+        for (id, created) in [(1, "2024-03-01T18:00:00Z"), (2, "2024-03-14T09:30:00Z")] {
+            notes.push(db::Note {
+                id,
+                created: Some(created.parse().unwrap()),
+                edited: None,
+                user: "Sensei".to_string(),
+                content: format!("note {}", id),
+                revisions: vec![],
+            });
+        }
+        app.put_student(s1).await;
+
+        let res = app.get("/api/students").await;
+        assert!(res.text().contains("\"created\":\"2024-03-14T"));
+        let ids = |res: crate::test_harness::TestResponse| {
+            res.json::<Vec<db::Student>>()[0]
+                .notes_in("notes")
+                .iter()
+                .map(|n| n.id)
+                .collect::<Vec<u32>>()
+        };
+        assert_eq!(ids(app.get("/api/students?from=2024-03-10").await), vec![2]);
+        assert_eq!(
+            ids(app.get("/api/students?to=2024-03-14T09:00:00Z").await),
+            vec![1]
+        );
+        assert_eq!(
+            ids(app.get("/api/students?from=2024-03-01&to=2024-03-31").await),
+            vec![1, 2]
+        );
+        assert_eq!(
+            app.get("/api/students?from=03-14-24").await.status,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn student_detail_pages_through_notes() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        let mut s1 = student("s1", "Ada Lovelace");
+        s1.date = Some("2024-03-14T17:00:00Z".parse().unwrap());
+        s1.check_ins.push(db::CheckIn {
+            date: s1.date.unwrap(),
+            time: Some("5:00 PM".to_string()),
+        });
+        app.put_student(s1).await;
+        for i in 0..5 {
+            app.send_json(
+                Method::PUT,
+                "/api/students/s1/notes",
+                &json!({ "note": format!("note {}", i) }),
+            )
+            .await;
+        }
+
+        let res = app.get("/api/students/s1?offset=1&limit=2").await;
+        assert_eq!(res.status, StatusCode::OK);
+        let detail: serde_json::Value = res.json();
+        assert_eq!(detail["source"], "mystudio");
+        assert_eq!(detail["check_ins"][0]["time"], "5:00 PM");
+        // Unlike the student list, the last check-in is kept.
+        assert!(detail["date"].as_str().unwrap().starts_with("2024-03-14"));
+        assert_eq!(detail["notes"]["notes"]["total"], 5);
+        let page = detail["notes"]["notes"]["notes"].as_array().unwrap();
+        let contents: Vec<&str> = page
+            .iter()
+            .map(|n| n["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(contents, vec!["note 1", "note 2"]);
+        assert_eq!(page[0]["user"], "Sensei");
+
+        let detail: serde_json::Value = app.get("/api/students/s1?category=logins").await.json();
+        let categories: Vec<&String> = detail["notes"].as_object().unwrap().keys().collect();
+        assert_eq!(categories, vec!["logins"]);
+        assert_eq!(
+            app.get("/api/students/nobody").await.status,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn imported_students_are_looked_up_by_name() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let csv = "Name,Belt,Logins,Notes,Behaviours\nGrace Hopper,Yellow,,a note,\n";
+        app.post_multipart(
+            "/api/load_csv",
+            &[("row_data", "[1,2,3,4,5]"), ("file", csv)],
+        )
+        .await;
+
+        let res = app.get("/api/students/grace%20hopper").await;
+        assert_eq!(res.status, StatusCode::OK);
+        let detail: serde_json::Value = res.json();
+        assert_eq!(detail["source"], "imported");
+        assert_eq!(detail["notes"]["notes"]["notes"][0]["content"], "a note");
+    }
+
+    #[tokio::test]
+    async fn student_list_is_filtered_sorted_and_paged() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        for (id, name, time, belt, assigned) in [
+            (
+                "s1",
+                "Ada Lovelace",
+                Some("10:00 AM"),
+                "Yellow",
+                Some("Sensei"),
+            ),
+            ("s2", "Alan Turing", Some("9:00 AM"), "White", None),
+            (
+                "s3",
+                "Grace Hopper",
+                Some("9:00 AM"),
+                "Yellow",
+                Some("Other"),
+            ),
+            ("s4", "Edsger Dijkstra", None, "White", None),
+        ] {
+            let mut s = student(id, name);
+            s.date = time.map(|_| chrono::Local::now());
+            s.time = time.map(str::to_string);
+            s.belt = belt.to_string();
+            s.assigned = assigned.map(str::to_string);
+            app.put_student(s).await;
+        }
+        let ids = |res: crate::test_harness::TestResponse| {
+            res.json::<Vec<db::Student>>()
+                .into_iter()
+                .map(|s| s.id)
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            ids(app.get("/api/students?present=today").await),
+            vec!["s3", "s2", "s1"]
+        );
+        assert_eq!(
+            ids(app.get("/api/students?sort=name").await),
+            vec!["s4", "s3", "s1", "s2"]
+        );
+        assert_eq!(
+            ids(app
+                .get("/api/students?present=today&assigned=sensei,")
+                .await),
+            vec!["s2", "s1"]
+        );
+        assert_eq!(
+            ids(app.get("/api/students?belt=yellow&time=9:00%20AM").await),
+            vec!["s3"]
+        );
+        assert_eq!(ids(app.get("/api/students?q=TUR").await), vec!["s2"]);
+
+        let mut seen = vec![];
+        let mut uri = "/api/students?limit=3".to_string();
+        loop {
+            let res = app.get(&uri).await;
+            let next = res
+                .headers
+                .get("x-next-cursor")
+                .map(|c| c.to_str().unwrap().to_string());
+            seen.extend(ids(res));
+            match next {
+                Some(cursor) => uri = format!("/api/students?limit=3&cursor={}", cursor),
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec!["s3", "s2", "s1", "s4"]);
+
+        for bad in ["present=yesterday", "sort=age", "cursor=nope"] {
+            let res = app.get(&format!("/api/students?{}", bad)).await;
+            assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_require_login() {
+        let mut app = TestApp::new().await;
+
+        assert_eq!(
+            app.get("/api/students").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            app.get("/api/senseis").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn new_passwords_follow_the_policy() {
+        let mut admin = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let res = admin
+            .send_json(
+                Method::POST,
+                "/api/admin/settings",
+                &json!({ "min_password_length": 10, "password_needs_digit": true }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let mut app = TestApp::from_state(admin.state.clone());
+        app.create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        app.login("Sensei", "password").await;
+        for (new_pass, error) in [
+            ("new pass1", "Passwords need at least 10 characters"),
+            ("new password", "Passwords need at least one digit"),
+        ] {
+            let res = app
+                .send_json(
+                    Method::POST,
+                    "/api/change_pass",
+                    &json!({ "current_pass": "password", "new_pass": new_pass }),
+                )
+                .await;
+            assert_eq!(res.status, StatusCode::BAD_REQUEST);
+            assert_eq!(res.text(), error);
+        }
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/change_pass",
+                &json!({ "current_pass": "password", "new_pass": "new password1" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn changing_password_ends_other_sessions() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        let mut other = TestApp::from_state(app.state.clone());
+        other.login("Sensei", "password").await;
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/change_pass",
+                &json!({ "current_pass": "wrong", "new_pass": "new password" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert_eq!(other.get("/api/role").await.status, StatusCode::OK);
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/change_pass",
+                &json!({ "current_pass": "password", "new_pass": "new password" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        assert_eq!(app.get("/api/role").await.status, StatusCode::OK);
+        assert_eq!(
+            other.get("/api/role").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            other.login("Sensei", "new password").await.status,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn observers_can_only_look() {
+        let mut app = TestApp::logged_in_as("Front Desk", UserRole::Observer).await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+
+        assert_eq!(app.get("/api/students").await.status, StatusCode::OK);
+        assert_eq!(app.get("/api/senseis").await.status, StatusCode::OK);
+        for note_type in ["logins", "assigned"] {
+            let res = app
+                .send_json(
+                    Method::PUT,
+                    &format!("/api/students/s1/{}", note_type),
+                    &json!({ "note": "hello" }),
+                )
+                .await;
+            assert_eq!(res.status, StatusCode::FORBIDDEN);
+        }
+        assert_eq!(
+            app.post_multipart("/api/load_csv", &[("row_data", "[1,2,3,4,5]")])
+                .await
+                .status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            app.get("/api/permissions").await.json::<Vec<String>>(),
+            vec!["ViewStudents"]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admins_change_other_senseis_notes() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.create_user("Admin", "password", UserRole::Admin).await;
+        app.create_user("Other", "password", UserRole::Standard)
+            .await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+        app.send_json(
+            Method::PUT,
+            "/api/students/s1/notes",
+            &json!({ "note": "mine" }),
+        )
+        .await;
+        let note_id = app.get_student("s1").await.unwrap().notes_in("notes")[0].id;
+
+        let mut other = TestApp::from_state(app.state.clone());
+        other.login("Other", "password").await;
+        let res = other
+            .send_json(
+                Method::PATCH,
+                "/api/students/s1/notes",
+                &json!({ "id": note_id, "note": "theirs" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        let res = other
+            .send_json(Method::DELETE, "/api/students/s1/notes", &note_id)
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        let mut admin = TestApp::from_state(app.state.clone());
+        admin.login("Admin", "password").await;
+        let res = admin
+            .send_json(Method::DELETE, "/api/students/s1/notes", &note_id)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(app
+            .get_student("s1")
+            .await
+            .unwrap()
+            .notes_in("notes")
+            .is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+use axum::{
+    extract::State, http::StatusCode, response::IntoResponse, routing, Extension, Json, Router,
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{db, permissions::Permission};
+
+const MAX_DAYS: i64 = 3650;
+
+#[derive(Deserialize)]
+pub struct CreateTokenIn {
+    name: String,
+    scopes: Vec<Permission>,
+    // Tokens without an expiry last until they are revoked.
+    days: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeTokenIn {
+    id: String,
+}
+
+#[derive(Serialize)]
+pub struct TokenOut {
+    id: String,
+    name: String,
+    scopes: Vec<Permission>,
+    created: i64,
+    expires: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedTokenOut {
+    // Only ever shown here.
+    token: String,
+    #[serde(flatten)]
+    info: TokenOut,
+}
+
+impl From<db::ApiToken> for TokenOut {
+    fn from(api_token: db::ApiToken) -> Self {
+        Self {
+            id: api_token.id,
+            name: api_token.name,
+            scopes: api_token.scopes,
+            created: api_token.created,
+            expires: api_token.expires,
+        }
+    }
+}
+
+// The caller's own tokens. These routes can't be used with an API token, see
+// `auth_layer_fn`.
+pub fn routes() -> Router<crate::AppState> {
+    Router::new()
+        .route("/", routing::get(tokens_get).post(tokens_post))
+        .route("/revoke", routing::post(revoke_post))
+}
+
+async fn tokens_get(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+) -> Result<Json<Vec<TokenOut>>, String> {
+    let db = state.db.read().await;
+    let api_tokens = state.api_tokens.read().await;
+    let mut tokens = api_tokens.for_user(&db, &session.user.primary_key).await?;
+    tokens.sort_by_key(|api_token| api_token.created);
+    Ok(Json(tokens.into_iter().map(TokenOut::from).collect()))
+}
+
+async fn tokens_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    Json(payload): Json<CreateTokenIn>,
+) -> Result<impl IntoResponse, String> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, "Tokens need a name").into_response());
+    }
+    if payload.scopes.is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, "Tokens need at least one scope").into_response());
+    }
+    if let Some(scope) = payload.scopes.iter().find(|scope| !session.can(**scope)) {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            format!("You don't have the {:?} permission", scope),
+        )
+            .into_response());
+    }
+    let expires = match payload.days {
+        Some(days) if !(1..=MAX_DAYS).contains(&days) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                format!("`days` must be between 1 and {}", MAX_DAYS),
+            )
+                .into_response());
+        }
+        Some(days) => Some((Utc::now() + Duration::days(days)).timestamp()),
+        None => None,
+    };
+
+    let db = state.db.read().await;
+    let api_tokens = state.api_tokens.read().await;
+    let (token, api_token) = api_tokens
+        .create(
+            &db,
+            &session.user.primary_key,
+            name,
+            payload.scopes,
+            expires,
+        )
+        .await?;
+
+    Ok(Json(CreatedTokenOut {
+        token,
+        info: api_token.into(),
+    })
+    .into_response())
+}
+
+async fn revoke_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    Json(payload): Json<RevokeTokenIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let api_tokens = state.api_tokens.read().await;
+    if api_tokens
+        .revoke(&db, &session.user.primary_key, &payload.id)
+        .await?
+    {
+        Ok(StatusCode::OK)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::db::UserRole;
+    use crate::test_harness::TestApp;
+
+    async fn create_token(app: &mut TestApp, scopes: &[&str]) -> serde_json::Value {
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/tokens",
+                &json!({ "name": "nightly sync", "scopes": scopes }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        res.json()
+    }
+
+    #[tokio::test]
+    async fn tokens_authenticate_with_their_scopes() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let created = create_token(&mut app, &["ViewStudents"]).await;
+        let token = created["token"].as_str().unwrap().to_string();
+        let bearer = format!("Bearer {}", token);
+
+        // A client that only has the token, no cookies.
+        let mut script = TestApp::from_state(app.state.clone());
+        let auth = [("Authorization", bearer.as_str())];
+        let res = script
+            .request(
+                Method::GET,
+                "/api/permissions",
+                None,
+                Default::default(),
+                &auth,
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.json::<Vec<String>>(), vec!["ViewStudents"]);
+        let res = script
+            .request(
+                Method::GET,
+                "/api/students",
+                None,
+                Default::default(),
+                &auth,
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        // Admins can, but the token wasn't given the scope.
+        let res = script
+            .request(
+                Method::GET,
+                "/api/admin/users",
+                None,
+                Default::default(),
+                &auth,
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        // Nor can a token be used to make more tokens.
+        let res = script
+            .request(Method::GET, "/api/tokens", None, Default::default(), &auth)
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        {
+            let db = app.state.db.read().await;
+            let api_tokens = app.state.api_tokens.read().await;
+            let stored = api_tokens.for_user(&db, "admin").await.unwrap().remove(0);
+            assert_ne!(stored.hash, token);
+            assert!(!stored.hash.contains(&token));
+        }
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/tokens/revoke",
+                &json!({ "id": created["id"] }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = script
+            .request(
+                Method::GET,
+                "/api/students",
+                None,
+                Default::default(),
+                &auth,
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn tokens_cant_exceed_the_users_role() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/tokens",
+                &json!({ "name": "sync", "scopes": ["RunSync"] }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        create_token(&mut app, &["ViewStudents", "EditOwnNotes"]).await;
+        let tokens = app
+            .get("/api/tokens")
+            .await
+            .json::<Vec<serde_json::Value>>();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0]["name"], "nightly sync");
+        assert!(tokens[0].get("token").is_none());
+
+        for days in [0, super::MAX_DAYS + 1, i64::MAX] {
+            let res = app
+                .send_json(
+                    Method::POST,
+                    "/api/tokens",
+                    &json!({ "name": "sync", "scopes": ["ViewStudents"], "days": days }),
+                )
+                .await;
+            assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn token_writes_dont_need_a_csrf_token() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.put_student(crate::test_harness::student("1", "Ada Lovelace"))
+            .await;
+        let created = create_token(&mut app, &["EditOwnNotes"]).await;
+        let bearer = format!("Bearer {}", created["token"].as_str().unwrap());
+
+        let mut script = TestApp::from_state(app.state.clone());
+        let res = script
+            .request(
+                Method::PUT,
+                "/api/students/1/notes",
+                Some("application/json"),
+                serde_json::to_vec(&json!({ "note": "From a script" }))
+                    .unwrap()
+                    .into(),
+                &[("Authorization", &bearer)],
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let student = app.get_student("1").await.unwrap();
+        assert_eq!(student.notes_in("notes")[0].user, "Sensei");
+    }
+
+    #[tokio::test]
+    async fn deleting_students_isnt_deleting_notes() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        app.put_student(crate::test_harness::student("1", "Ada Lovelace"))
+            .await;
+        let mut script = TestApp::from_state(app.state.clone());
+        for (scope, status) in [
+            ("DeleteNotes", StatusCode::FORBIDDEN),
+            ("DeleteStudents", StatusCode::OK),
+        ] {
+            let created = create_token(&mut app, &[scope]).await;
+            let bearer = format!("Bearer {}", created["token"].as_str().unwrap());
+            let res = script
+                .request(
+                    Method::DELETE,
+                    "/api/students/1",
+                    None,
+                    Default::default(),
+                    &[("Authorization", &bearer)],
+                )
+                .await;
+            assert_eq!(res.status, status);
+        }
+        assert!(app.get_student("1").await.is_none());
+    }
+}
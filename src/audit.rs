@@ -0,0 +1,328 @@
+use std::cell::RefCell;
+
+use axum::{http::Request, middleware::Next, response::Response};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_diff::{Diff, SerdeDiff};
+use uuid::Uuid;
+
+use crate::db::{self, Column};
+
+// Fields whose values never end up in the log. That a password or TOTP secret
+// changed is still recorded, just not what it changed to.
+const SECRET_FIELDS: &[&str] = &[
+    "hash",
+    "password_hash",
+    "totp_secret",
+    "recovery_codes",
+    "pin_hash",
+];
+const REDACTED: &str = "[redacted]";
+
+// Who is making the writes of the current request.
+#[derive(Clone, Default)]
+struct Actor {
+    user: Option<String>,
+    route: String,
+}
+
+tokio::task_local! {
+    static ACTOR: RefCell<Actor>;
+}
+
+// Remembers which route the request is for, so writes it makes can be
+// recorded under it. Wraps the whole app, so writes made before anyone has
+// logged in are recorded too.
+pub async fn audit_layer_fn<B>(req: Request<B>, next: Next<B>) -> Response {
+    let actor = Actor {
+        user: None,
+        route: format!("{} {}", req.method(), req.uri().path()),
+    };
+    ACTOR.scope(RefCell::new(actor), next.run(req)).await
+}
+
+// Runs inside `login::auth_layer_fn`, once the request's session is known.
+pub async fn session_layer_fn<B>(req: Request<B>, next: Next<B>) -> Response {
+    if let Some(session) = req.extensions().get::<db::Session>() {
+        let name = session.user.name.clone();
+        ACTOR
+            .try_with(|actor| actor.borrow_mut().user = Some(name))
+            .ok();
+    }
+    next.run(req).await
+}
+
+// Writes made outside of a request, e.g. by the background sync, are
+// recorded as coming from the server itself.
+fn actor() -> Actor {
+    ACTOR
+        .try_with(|actor| actor.borrow().clone())
+        .unwrap_or(Actor {
+            user: None,
+            route: "server".to_string(),
+        })
+}
+
+// Replaces the values written to `SECRET_FIELDS` in a list of serde_diff
+// commands.
+fn redact(commands: &mut [serde_json::Value]) {
+    let mut secret_depth = None;
+    let mut depth = 0;
+    for cmd in commands.iter_mut() {
+        match cmd {
+            serde_json::Value::String(exit) if exit == "Exit" => {
+                depth -= 1;
+                if secret_depth.is_some_and(|d| depth < d) {
+                    secret_depth = None;
+                }
+            }
+            serde_json::Value::Object(cmd) if cmd.contains_key("Enter") => {
+                let field = cmd["Enter"].get("Field").and_then(|f| f.as_str());
+                if secret_depth.is_none() && field.is_some_and(|f| SECRET_FIELDS.contains(&f)) {
+                    secret_depth = Some(depth);
+                }
+                depth += 1;
+            }
+            serde_json::Value::Object(cmd) if secret_depth.is_some() => {
+                for value in cmd.values_mut() {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                }
+            }
+            // Whole records are written as a single value when they are
+            // created, e.g. a tombstone holding a deleted user.
+            serde_json::Value::Object(cmd) => cmd.values_mut().for_each(redact_value),
+            _ => {}
+        }
+    }
+}
+
+// Replaces `SECRET_FIELDS` anywhere inside a value.
+fn redact_value(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if SECRET_FIELDS.contains(&name.as_str()) {
+                    *field = serde_json::Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(field);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+// Adds an entry for a write to `table`. `CachingColumn` calls this for the
+// columns made with `audited`.
+pub async fn record<S: SerdeDiff + Serialize + for<'a> Deserialize<'a>>(
+    log: &db::BackendColumn,
+    db: &db::Backend,
+    table: &str,
+    record: &str,
+    before: &Option<S>,
+    after: &Option<S>,
+) -> Result<(), String> {
+    let diff =
+        serde_json::to_value(Diff::serializable(before, after)).map_err(|e| e.to_string())?;
+    let mut commands = diff.as_array().cloned().unwrap_or_default();
+    redact(&mut commands);
+
+    let actor = actor();
+    let now = Utc::now();
+    let entry = db::AuditEntry {
+        // Sorts in the order the entries were made.
+        id: format!("{:020}-{}", now.timestamp_micros(), Uuid::new_v4()),
+        timestamp: now.timestamp(),
+        user: actor.user,
+        route: actor.route,
+        table: table.to_string(),
+        record: record.to_string(),
+        diff: serde_json::to_string(&commands).map_err(|e| e.to_string())?,
+    };
+    log.put(db, &entry.id.clone(), entry)
+        .await
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[derive(Deserialize, Default)]
+pub struct AuditQuery {
+    // Primary key of a student, only their record's entries are returned.
+    pub student: Option<String>,
+    // Name of the user who made the writes.
+    pub user: Option<String>,
+    // Unix timestamps, both inclusive.
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &db::AuditEntry) -> bool {
+        self.student
+            .as_ref()
+            .is_none_or(|s| entry.table == "students" && entry.record == s.to_lowercase())
+            && self.user.as_ref().is_none_or(|u| {
+                entry
+                    .user
+                    .as_ref()
+                    .is_some_and(|name| name.to_lowercase() == u.to_lowercase())
+            })
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+    }
+}
+
+// Read straight from the database, since entries are only looked at by
+// admins.
+pub struct AuditLog {
+    internal: db::BackendColumn,
+}
+
+impl AuditLog {
+    pub fn from(col: db::BackendColumn) -> Self {
+        Self { internal: col }
+    }
+
+    // Newest first.
+    pub async fn query(
+        &self,
+        db: &db::Backend,
+        query: &AuditQuery,
+    ) -> Result<Vec<db::AuditEntry>, String> {
+        let mut entries: Vec<db::AuditEntry> = self
+            .internal
+            .get_values::<db::AuditEntry>(db)
+            .await?
+            .into_iter()
+            .filter(|entry| query.matches(entry))
+            .collect();
+        entries.sort_by(|a, b| b.id.cmp(&a.id));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::db::{AuditEntry, UserRole};
+    use crate::test_harness::{student, TestApp};
+
+    async fn entries(app: &mut TestApp, query: &str) -> Vec<AuditEntry> {
+        let res = app.get(&format!("/api/admin/audit{}", query)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        res.json()
+    }
+
+    #[tokio::test]
+    async fn note_writes_are_recorded_with_who_made_them() {
+        let mut admin = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        admin
+            .create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        admin.put_student(student("s1", "Ada Lovelace")).await;
+        let mut sensei = TestApp::from_state(admin.state.clone());
+        sensei.login("Sensei", "password").await;
+
+        let res = sensei
+            .send_json(
+                Method::PUT,
+                "/api/students/s1/notes",
+                &json!({ "note": "Good focus" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let note_id = admin.get_student("s1").await.unwrap().notes_in("notes")[0].id;
+        let res = admin
+            .send_json(Method::DELETE, "/api/students/s1/notes", &note_id)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let log = entries(&mut admin, "?student=s1").await;
+        // Adding the note took an id from the counter and then appended it.
+        assert_eq!(log.len(), 4);
+        assert_eq!(log[3].route, "server");
+        assert_eq!(log[0].user.as_deref(), Some("Admin"));
+        assert_eq!(log[0].route, "DELETE /api/students/s1/notes");
+        assert_eq!(log[1].user.as_deref(), Some("Sensei"));
+        assert!(log[1].diff.contains("Good focus"));
+        assert_eq!(log[2].user.as_deref(), Some("Sensei"));
+        assert!(log[2].diff.contains("note_counter"));
+
+        let by_sensei = entries(&mut admin, "?user=sensei").await;
+        assert!(by_sensei
+            .iter()
+            .all(|e| e.user.as_deref() == Some("Sensei")));
+        assert!(by_sensei.iter().any(|e| e.record == "s1"));
+        assert!(entries(&mut admin, "?student=s1&from=4102444800")
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn secrets_are_left_out_of_the_log() {
+        let mut admin = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let res = admin
+            .send_json(
+                Method::POST,
+                "/api/change_pass",
+                &json!({ "current_pass": "password", "new_pass": "correct horse" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let log = entries(&mut admin, "?user=admin").await;
+        let change = log
+            .iter()
+            .find(|e| e.route == "POST /api/change_pass")
+            .unwrap();
+        assert!(change.diff.contains("password_hash"));
+        assert!(!change.diff.contains("argon2"));
+
+        // Nor can anyone but admins read it.
+        admin
+            .create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        let mut sensei = TestApp::from_state(admin.state.clone());
+        sensei.login("Sensei", "password").await;
+        assert_eq!(
+            sensei.get("/api/admin/audit").await.status,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_to_every_table_are_recorded() {
+        let mut admin = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        admin
+            .create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        let res = admin
+            .send_json(
+                Method::POST,
+                "/api/admin/settings",
+                &json!({ "min_password_length": 10 }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = admin
+            .send_json(
+                Method::POST,
+                "/api/admin/delete_user",
+                &json!({ "name": "Sensei" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let log = entries(&mut admin, "?user=admin").await;
+        let settings = log.iter().find(|e| e.table == "settings").unwrap();
+        assert_eq!(settings.route, "POST /api/admin/settings");
+        assert!(settings.diff.contains("min_password_length"));
+        // The deleted user is kept in a tombstone, without their password.
+        let tombstone = log.iter().find(|e| e.table == "tombstones").unwrap();
+        assert!(tombstone.diff.contains("Sensei"));
+        assert!(tombstone.diff.contains("password_hash"));
+        assert!(!tombstone.diff.contains("argon2"));
+    }
+}
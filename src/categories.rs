@@ -0,0 +1,160 @@
+use crate::db;
+
+// The columns the grid had before categories could be changed. Students
+// stored back then have a list for each of them, see `migrations.rs`.
+pub const DEFAULTS: [(&str, &str); 3] = [
+    ("logins", "Logins"),
+    ("notes", "Notes"),
+    ("behaviours", "Behaviours"),
+];
+
+// Turns a category's name into the id used in routes, e.g. "Parent contact"
+// into "parent_contact".
+pub fn id_for(name: &str) -> String {
+    name.trim()
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+// Note categories, read straight from the database so changes show up on
+// every instance.
+pub struct CategoryStore {
+    internal: db::AuditedColumn,
+}
+
+impl CategoryStore {
+    pub fn from(col: db::AuditedColumn) -> Self {
+        Self { internal: col }
+    }
+
+    // In the order the grid shows them.
+    pub async fn list(&self, db: &db::Backend) -> Result<Vec<db::Category>, String> {
+        let mut categories = self.internal.get_values::<db::Category>(db).await?;
+        categories.sort_by(|a, b| a.order.cmp(&b.order).then(a.name.cmp(&b.name)));
+        Ok(categories)
+    }
+
+    pub async fn get(&self, db: &db::Backend, id: &str) -> Result<Option<db::Category>, String> {
+        self.internal.get::<db::Category>(db, id).await
+    }
+
+    pub async fn put(
+        &self,
+        db: &db::Backend,
+        category: db::Category,
+    ) -> Result<(), db::WriteError> {
+        self.internal.put(db, &category.id.clone(), category).await
+    }
+
+    pub async fn delete(&self, db: &db::Backend, id: &str) -> Result<(), String> {
+        self.internal.delete::<db::Category>(db, id).await
+    }
+
+    // Adds the default categories when there are none yet.
+    pub async fn seed_defaults(&self, db: &db::Backend) -> Result<(), String> {
+        if !self
+            .internal
+            .get_values::<db::Category>(db)
+            .await?
+            .is_empty()
+        {
+            return Ok(());
+        }
+        for (order, (id, name)) in DEFAULTS.iter().enumerate() {
+            let category = db::Category {
+                id: id.to_string(),
+                name: name.to_string(),
+                order: order as u32,
+                editable: true,
+                revision: 0,
+            };
+            self.put(db, category)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::id_for;
+    use crate::db::{self, UserRole};
+    use crate::test_harness::TestApp;
+
+    #[test]
+    fn ids_are_made_from_names() {
+        assert_eq!(id_for(" Parent contact "), "parent_contact");
+        assert_eq!(id_for("Project progress!"), "project_progress");
+    }
+
+    #[tokio::test]
+    async fn admins_manage_categories() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        app.put_student(crate::test_harness::student("s1", "Ada Lovelace"))
+            .await;
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/categories",
+                &json!({ "name": "Medical", "order": 5, "editable": false }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let categories: Vec<db::Category> = app.get("/api/categories").await.json();
+        let ids: Vec<&str> = categories.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["logins", "notes", "behaviours", "medical"]);
+
+        let res = app
+            .send_json(
+                Method::PUT,
+                "/api/students/s1/medical",
+                &json!({ "note": "Asthma" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let note_id = app.get_student("s1").await.unwrap().notes_in("medical")[0].id;
+        let res = app
+            .send_json(
+                Method::PATCH,
+                "/api/students/s1/medical",
+                &json!({ "id": note_id, "note": "No asthma" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        let res = app
+            .send_json(
+                Method::PUT,
+                "/api/students/s1/unknown",
+                &json!({ "note": "?" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+        // Categories still holding notes are kept.
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/delete_category",
+                &json!({ "id": "medical" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::CONFLICT);
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/delete_category",
+                &json!({ "id": "behaviours" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let categories: Vec<db::Category> = app.get("/api/categories").await.json();
+        assert_eq!(categories.len(), 3);
+    }
+}
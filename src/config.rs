@@ -0,0 +1,43 @@
+use chrono::Duration;
+
+use crate::db::UserRole;
+
+// Settings that can be changed from the command line. `AppArgs` fills these
+// in, tests use the defaults.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Failed logins for one username before it is locked out.
+    pub lockout_threshold: u32,
+    // Failed logins from one IP address, for any username, before it is locked out.
+    pub ip_lockout_threshold: u32,
+    pub lockout_duration: Duration,
+    // Single sign-on through an OpenID Connect provider, if configured.
+    pub oidc: Option<OidcConfig>,
+    // How long someone stays the user of a kiosk after their last change.
+    pub kiosk_idle_timeout: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    // The provider's issuer URL, e.g. `https://accounts.google.com`.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    // Where the provider sends users back to, ending in `/api/oidc/callback`.
+    pub redirect_url: String,
+    // Role given to users who log in for the first time. Without one, only
+    // existing users can log in this way.
+    pub default_role: Option<UserRole>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            lockout_threshold: 5,
+            ip_lockout_threshold: 20,
+            lockout_duration: Duration::minutes(15),
+            oidc: None,
+            kiosk_idle_timeout: Duration::minutes(5),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use axum::{
+    http::{Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_extra::extract::PrivateCookieJar;
+use cookie::{Cookie, SameSite};
+use uuid::Uuid;
+
+use crate::db;
+
+// Readable by the frontend, which sends the value back in `HEADER`. Another
+// site can make the browser send our cookies but can't read them.
+pub const COOKIE: &str = "csrf_token";
+pub const HEADER: &str = "x-csrf-token";
+// The invite and password reset forms are posted before there is a session,
+// so their tokens live in private cookies and are checked against a hidden
+// form field instead, see `form_token`.
+pub const INVITE_COOKIE: &str = "invite_csrf";
+pub const RESET_COOKIE: &str = "reset_csrf";
+
+pub fn generate() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+pub fn cookie(token: &str) -> Cookie<'static> {
+    Cookie::build(COOKIE, token.to_string())
+        .same_site(SameSite::Strict)
+        .path("/")
+        .finish()
+}
+
+pub fn removal_cookie() -> Cookie<'static> {
+    Cookie::build(COOKIE, "").path("/").finish()
+}
+
+// Returns the token for the form posted to `path`, which the page has to
+// send back in its `csrf` field, and the jar with the cookie holding it.
+pub fn form_token(
+    cookies: PrivateCookieJar,
+    name: &'static str,
+    path: &'static str,
+) -> (PrivateCookieJar, String) {
+    let token = match cookies.get(name) {
+        Some(cookie) => cookie.value().to_string(),
+        None => generate(),
+    };
+    let cookies = cookies.add(
+        Cookie::build(name, token.clone())
+            .same_site(SameSite::Strict)
+            .path(path)
+            .finish(),
+    );
+    (cookies, token)
+}
+
+pub fn form_matches(cookies: &PrivateCookieJar, name: &str, given: &str) -> bool {
+    let expected = cookies
+        .get(name)
+        .map(|cookie| cookie.value().to_string())
+        .unwrap_or_default();
+    matches(&expected, given)
+}
+
+// Compares in constant time so the token can't be guessed byte by byte.
+pub fn matches(expected: &str, given: &str) -> bool {
+    if expected.is_empty() || expected.len() != given.len() {
+        return false;
+    }
+    expected
+        .bytes()
+        .zip(given.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+pub fn is_safe(method: &Method) -> bool {
+    [Method::GET, Method::HEAD, Method::OPTIONS].contains(method)
+}
+
+// Runs inside `auth_layer_fn`. Every request that can change something has to
+// carry the session's token in the `X-CSRF-Token` header. API tokens are
+// exempt, since browsers don't send those on their own.
+pub async fn csrf_layer_fn<B>(
+    Extension(session): Extension<db::Session>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if session.credential == db::Credential::Cookie && !is_safe(req.method()) {
+        let given = req
+            .headers()
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !matches(&session.csrf_token, given) {
+            return (StatusCode::FORBIDDEN, "Missing or invalid CSRF token").into_response();
+        }
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        let token = generate();
+        assert!(matches(&token, &token.clone()));
+        assert!(!matches(&token, &token[1..]));
+        assert!(!matches(&token, &generate()));
+        // Sessions from before CSRF tokens existed have none.
+        assert!(!matches("", ""));
+    }
+}
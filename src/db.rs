@@ -1,44 +1,342 @@
 use aws_config::meta::region::RegionProviderChain;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::counter::Counter;
+use crate::{counter::Counter, permissions::Permission};
 use aws_sdk_dynamodb::{
     operation::scan::ScanOutput,
-    types::{AttributeDefinition, AttributeValue, KeySchemaElement, ProvisionedThroughput},
+    types::{
+        AttributeDefinition, AttributeValue, KeySchemaElement, ProvisionedThroughput, ReturnValue,
+        TimeToLiveSpecification, TimeToLiveStatus,
+    },
 };
 use chrono::{DateTime, Duration, Local};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_diff::{Diff, SerdeDiff};
+use serde_diff::{Apply, Diff, SerdeDiff};
+
+mod memory;
+mod sqlite;
+
+pub use memory::{MemoryColumn, MemoryDB};
+pub use sqlite::{SqliteColumn, SqliteDB};
 
 #[derive(SerdeDiff, Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
 pub enum UserRole {
     Standard,
     Admin,
+    // Can see students but not change anything, see `permissions`.
+    Observer,
 }
 
 #[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
 pub struct User {
     pub name: String,
     pub primary_key: String,
-    pub hash: u64,
+    // Unsalted `DefaultHasher` hash from before passwords were hashed with
+    // Argon2. It is replaced by `password_hash` on the user's next login.
+    #[serde(default)]
+    pub hash: Option<u64>,
+    // Argon2id hash in PHC string format.
+    #[serde(default)]
+    pub password_hash: String,
     pub role: UserRole,
+    #[serde(default)]
+    pub revision: u64,
+    // Base32 TOTP secret. It is only used for logins once `totp_enabled` is
+    // set, which happens when the user confirms a code after enrolling.
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    // The last TOTP time step used to log in, so codes can't be replayed.
+    #[serde(default)]
+    pub totp_last_step: u64,
+    // Argon2id hashes of the unused recovery codes.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    // Used to find the user when they log in through OpenID Connect.
+    #[serde(default)]
+    pub email: Option<String>,
+    // Argon2id hash of the short PIN used to switch to the user on a kiosk.
+    #[serde(default)]
+    pub pin_hash: Option<String>,
+}
+
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, PartialEq, Eq, Debug, Default)]
+pub enum SessionStage {
+    #[default]
+    Active,
+    // The password was right, but the TOTP code hasn't been entered yet.
+    AwaitingTotp,
+    // The user's role requires two-factor authentication and they haven't
+    // enrolled yet, so enrolling is all the session can be used for.
+    MustEnrollTotp,
+}
+
+// What the request authenticated with.
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Credential {
+    // The "token" cookie set by `login()`.
+    #[default]
+    Cookie,
+    // A personal API token sent as `Authorization: Bearer`.
+    ApiToken {
+        id: String,
+        name: String,
+    },
+}
+
+// Stored with the primary key of its user as `U`, see `StoredSession`. Requests
+// get the user itself, looked up again by `login::get_session`, so changes to
+// the user take effect straight away.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Session<U = User> {
+    pub token: String,
+    pub expires: i64,
+    pub user: U,
+    // Identifies the session to admins without handing out the token itself.
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub created: i64,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub stage: SessionStage,
+    // Sent back by the frontend on every write, see `csrf.rs`.
+    #[serde(default)]
+    pub csrf_token: String,
+    #[serde(default)]
+    pub credential: Credential,
+    // What an API token was limited to when it was created. `None` means
+    // everything the user's role allows.
+    #[serde(default)]
+    pub scopes: Option<Vec<Permission>>,
+    // Set on sessions of a shared device, see `kiosk.rs`.
+    #[serde(default)]
+    pub kiosk: Option<Kiosk>,
+}
+
+// What the sessions table holds. Kiosk sessions belong to their device rather
+// than a user and have an empty key.
+pub type StoredSession = Session<String>;
+
+impl<U> Session<U> {
+    pub fn with_user<V>(self, user: V) -> Session<V> {
+        Session {
+            token: self.token,
+            expires: self.expires,
+            user,
+            id: self.id,
+            created: self.created,
+            ip: self.ip,
+            user_agent: self.user_agent,
+            stage: self.stage,
+            csrf_token: self.csrf_token,
+            credential: self.credential,
+            scopes: self.scopes,
+            kiosk: self.kiosk,
+        }
+    }
+}
+
+impl Session {
+    pub fn stored(&self) -> StoredSession {
+        self.clone().with_user(self.user.primary_key.clone())
+    }
+}
+
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct Kiosk {
+    pub device: String,
+    // The admin who set the device up.
+    pub started_by: String,
+    // Primary key of whoever last entered their PIN. They stay the session's
+    // user until `holder_expires`.
+    pub holder: Option<String>,
+    pub holder_expires: i64,
+}
+
+// A personal API token. Only a hash of the token is stored, so it is shown
+// to the user once when it is created.
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
+pub struct ApiToken {
+    // SHA-256 of the token, in hex.
+    pub hash: String,
+    // Identifies the token when listing or revoking it.
+    pub id: String,
+    pub name: String,
+    // Primary key of the user the token belongs to.
+    pub user: String,
+    pub scopes: Vec<Permission>,
+    pub created: i64,
+    pub expires: Option<i64>,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+// Settings admins can change at runtime, stored as a single record.
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
+pub struct Settings {
+    pub key: String,
+    #[serde(default)]
+    pub require_admin_totp: bool,
+    // The password policy, see `password::check_policy`.
+    #[serde(default = "default_min_password_length")]
+    pub min_password_length: usize,
+    #[serde(default)]
+    pub password_needs_digit: bool,
+    #[serde(default)]
+    pub password_needs_symbol: bool,
+    // How long deleted notes, students and users can be restored, see
+    // `tombstone_store.rs`.
+    #[serde(default = "default_deleted_retention_days")]
+    pub deleted_retention_days: i64,
+    #[serde(default)]
+    pub revision: u64,
 }
 
+fn default_min_password_length() -> usize {
+    8
+}
+
+fn default_deleted_retention_days() -> i64 {
+    30
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            key: "settings".to_string(),
+            require_admin_totp: false,
+            min_password_length: default_min_password_length(),
+            password_needs_digit: false,
+            password_needs_symbol: false,
+            deleted_retention_days: default_deleted_retention_days(),
+            revision: 0,
+        }
+    }
+}
+
+// Something that was deleted and can still be restored.
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
+pub enum Deleted {
+    Note {
+        // Primary key of the student the note belonged to.
+        student: String,
+        note_type: String,
+        note: Note,
+    },
+    Student(Student),
+    User(User),
+}
+
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
+pub struct Tombstone {
+    pub id: String,
+    // Name of the user who deleted it.
+    pub deleted_by: String,
+    pub deleted: i64,
+    pub item: Deleted,
+}
+
+// One write to a table, see `audit.rs`.
 #[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
-pub struct Session {
+pub struct AuditEntry {
+    pub id: String,
+    pub timestamp: i64,
+    // Whoever was logged in, `None` for writes made without a session, e.g.
+    // when an invite is redeemed.
+    pub user: Option<String>,
+    // Method and path of the request that made the write.
+    pub route: String,
+    pub table: String,
+    pub record: String,
+    // The serde_diff commands, as JSON, that turn the old record into the new
+    // one. Both sides are optional, so creating or deleting a record is a diff
+    // from or to `None`.
+    pub diff: String,
+}
+
+// A link an admin hands to a user so they can choose a new password.
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
+pub struct PasswordReset {
     pub token: String,
+    // Primary key of the user whose password is reset.
+    pub user: String,
+    pub creator: String,
+    pub created: i64,
     pub expires: i64,
-    pub user: User,
+    pub used: bool,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
+pub struct Invite {
+    pub token: String,
+    pub creator: String,
+    pub created: i64,
+    pub expires: i64,
+    // When set, the invite can only be used to create a user with this name.
+    pub name: Option<String>,
+    pub role: UserRole,
+    pub used_by: Option<String>,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+pub enum LockoutKind {
+    User,
+    Ip,
+}
+
+// Failed logins for a username or an IP address.
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
+pub struct LoginAttempts {
+    pub key: String,
+    pub kind: LockoutKind,
+    pub subject: String,
+    pub failures: u32,
+    pub last_failure: i64,
+    pub locked_until: Option<i64>,
+    #[serde(default)]
+    pub revision: u64,
 }
 
 #[derive(SerdeDiff, Deserialize, Serialize, Clone, PartialEq, Debug)]
 pub struct Note {
     pub id: u32,
-    pub date: String,
+    // Unknown for notes from the CSV import and MyStudio.
+    #[serde(default)]
+    #[serde_diff(opaque)]
+    pub created: Option<DateTime<Local>>,
+    // When `content` was last changed, if ever.
+    #[serde(default)]
+    #[serde_diff(opaque)]
+    pub edited: Option<DateTime<Local>>,
     pub user: String,
     pub content: String,
+    // Earlier versions of `content`, oldest first.
+    #[serde(default)]
+    pub revisions: Vec<NoteRevision>,
+}
+
+// What a note said before someone edited it.
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct NoteRevision {
+    pub content: String,
+    // Who replaced `content`, and when.
+    pub editor: String,
+    #[serde_diff(opaque)]
+    pub edited: DateTime<Local>,
 }
 
+// Where `Student::note_counter` keeps its number, for `Column::increment`.
+pub const NOTE_COUNTER: &[&str] = &["note_counter", "n"];
+
 #[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
 pub struct Student {
     pub first_name: String,
@@ -49,13 +347,50 @@ pub struct Student {
     pub date: Option<DateTime<Local>>,
     pub time: Option<String>,
     pub belt: String,
-    pub logins: Vec<Note>,
-    pub notes: Vec<Note>,
-    pub behaviours: Vec<Note>,
+    // Keyed by the id of the note's `Category`.
+    pub notes: BTreeMap<String, Vec<Note>>,
     pub assigned: Option<String>,
     pub note_counter: Counter<u32>,
+    // Every day the student was seen at a class, oldest first. `date` and
+    // `time` are the latest of these.
+    #[serde(default)]
+    pub check_ins: Vec<CheckIn>,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct CheckIn {
+    #[serde_diff(opaque)]
+    pub date: DateTime<Local>,
+    // Start of the class, as MyStudio has it.
+    pub time: Option<String>,
 }
 
+impl Student {
+    pub fn notes_in(&self, category: &str) -> &[Note] {
+        self.notes
+            .get(category)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+// A kind of note, shown as a column of the student grid.
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
+pub struct Category {
+    // Used as the note type in routes, e.g. `/api/students/:id/logins`.
+    pub id: String,
+    pub name: String,
+    // Columns are shown from the lowest order to the highest.
+    pub order: u32,
+    // Whether notes can be changed after they were written.
+    pub editable: bool,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+// Notes from the CSV import, one column per default category.
 #[derive(SerdeDiff, Deserialize, Serialize, Clone, Debug)]
 pub struct StudentImportedInfo {
     pub name: String,
@@ -65,6 +400,45 @@ pub struct StudentImportedInfo {
     pub behaviours: Vec<String>,
 }
 
+impl StudentImportedInfo {
+    // The imported notes with the id of the category they belong in.
+    pub fn into_categories(self) -> [(&'static str, Vec<String>); 3] {
+        [
+            ("logins", self.logins),
+            ("notes", self.notes),
+            ("behaviours", self.behaviours),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteError {
+    // The stored record has a different revision than the one being written.
+    Conflict,
+    Failed(String),
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::Conflict => write!(f, "record was changed by someone else"),
+            WriteError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for WriteError {
+    fn from(value: String) -> Self {
+        WriteError::Failed(value)
+    }
+}
+
+impl From<WriteError> for String {
+    fn from(value: WriteError) -> Self {
+        value.to_string()
+    }
+}
+
 pub trait Database<'a, ColumnType> {
     fn save(&self);
     #[deprecated]
@@ -86,22 +460,39 @@ pub trait Column<DatabaseType, ColumnRefType> {
         db: &DatabaseType,
         k: &str,
         v: S,
-    ) -> Result<(), String>;
+    ) -> Result<(), WriteError>;
+    // Like `put`, but fails with `WriteError::Conflict` if there already is an
+    // item with the key, whatever its revision.
+    async fn create<S: Serialize + PrimaryKeyName>(
+        &self,
+        db: &DatabaseType,
+        k: &str,
+        v: S,
+    ) -> Result<(), WriteError>;
     #[deprecated]
     async fn get_update<S: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
         &self,
         db: &DatabaseType,
         k: &str,
         f: impl FnOnce(&mut S) -> (),
-    ) -> Result<(), String>;
+    ) -> Result<(), WriteError>;
     async fn diff_update<S: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
         &self,
         db: &DatabaseType,
         k: &str,
         v: &S,
         f: impl FnOnce(&mut S) -> (),
-    ) -> Result<(), String>;
+    ) -> Result<(), WriteError>;
     async fn delete<S: PrimaryKeyName>(&self, db: &DatabaseType, k: &str) -> Result<(), String>;
+    // Adds one to the number at `path` inside the stored item, and to its
+    // revision, in a single write that needs no expected revision. Returns the
+    // new number.
+    async fn increment<S: PrimaryKeyName>(
+        &self,
+        db: &DatabaseType,
+        k: &str,
+        path: &[&str],
+    ) -> Result<u64, WriteError>;
 }
 
 pub struct DynamoDB {
@@ -112,6 +503,18 @@ pub struct DynamoDBColumn {
     table_name: String,
 }
 
+pub enum Backend {
+    DynamoDB(DynamoDB),
+    Sqlite(SqliteDB),
+    Memory(MemoryDB),
+}
+
+pub enum BackendColumn {
+    DynamoDB(DynamoDBColumn),
+    Sqlite(SqliteColumn),
+    Memory(MemoryColumn),
+}
+
 pub trait PrimaryKeyName {
     fn get_primary_key_name() -> &'static str;
 }
@@ -132,18 +535,102 @@ impl PrimaryKeyValue<String> for User {
     }
 }
 
-impl PrimaryKeyName for Session {
+impl<U> PrimaryKeyName for Session<U> {
     fn get_primary_key_name() -> &'static str {
         return "token";
     }
 }
 
-impl PrimaryKeyValue<String> for Session {
+impl<U> PrimaryKeyValue<String> for Session<U> {
     fn get_primary_key_value(&self) -> String {
         self.token.clone()
     }
 }
 
+impl PrimaryKeyName for ApiToken {
+    fn get_primary_key_name() -> &'static str {
+        return "hash";
+    }
+}
+
+impl PrimaryKeyValue<String> for ApiToken {
+    fn get_primary_key_value(&self) -> String {
+        self.hash.clone()
+    }
+}
+
+impl PrimaryKeyName for PasswordReset {
+    fn get_primary_key_name() -> &'static str {
+        return "token";
+    }
+}
+
+impl PrimaryKeyValue<String> for PasswordReset {
+    fn get_primary_key_value(&self) -> String {
+        self.token.clone()
+    }
+}
+
+impl PrimaryKeyName for Category {
+    fn get_primary_key_name() -> &'static str {
+        return "id";
+    }
+}
+
+impl PrimaryKeyName for Tombstone {
+    fn get_primary_key_name() -> &'static str {
+        return "id";
+    }
+}
+
+impl PrimaryKeyName for AuditEntry {
+    fn get_primary_key_name() -> &'static str {
+        return "id";
+    }
+}
+
+impl PrimaryKeyValue<String> for AuditEntry {
+    fn get_primary_key_value(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl PrimaryKeyName for Invite {
+    fn get_primary_key_name() -> &'static str {
+        return "token";
+    }
+}
+
+impl PrimaryKeyValue<String> for Invite {
+    fn get_primary_key_value(&self) -> String {
+        self.token.clone()
+    }
+}
+
+impl PrimaryKeyName for LoginAttempts {
+    fn get_primary_key_name() -> &'static str {
+        return "key";
+    }
+}
+
+impl PrimaryKeyValue<String> for LoginAttempts {
+    fn get_primary_key_value(&self) -> String {
+        self.key.clone()
+    }
+}
+
+impl PrimaryKeyName for Settings {
+    fn get_primary_key_name() -> &'static str {
+        return "key";
+    }
+}
+
+impl PrimaryKeyValue<String> for Settings {
+    fn get_primary_key_value(&self) -> String {
+        self.key.clone()
+    }
+}
+
 impl PrimaryKeyName for Student {
     fn get_primary_key_name() -> &'static str {
         return "id";
@@ -168,6 +655,360 @@ impl PrimaryKeyValue<String> for StudentImportedInfo {
     }
 }
 
+// Reads the primary key out of the serialized item, the same way DynamoDB
+// picks the key attribute out of a `put_item` request.
+fn primary_key_of<S: PrimaryKeyName>(item: &serde_json::Value) -> Result<String, String> {
+    match item.get(S::get_primary_key_name()) {
+        Some(serde_json::Value::String(k)) => Ok(k.clone()),
+        Some(_) => Err(format!(
+            "primary key `{}` must be a string",
+            S::get_primary_key_name()
+        )),
+        None => Err(format!(
+            "item is missing primary key `{}`",
+            S::get_primary_key_name()
+        )),
+    }
+}
+
+// Records with this field are written conditionally: a write only goes through
+// if the stored revision still matches the one the record was read at, and
+// every successful write bumps it.
+const REVISION_FIELD: &str = "revision";
+
+fn revision_of<S: Serialize>(v: &S) -> Result<Option<u64>, String> {
+    Ok(serde_json::to_value(v)
+        .map_err(|e| e.to_string())?
+        .get(REVISION_FIELD)
+        .and_then(|r| r.as_u64()))
+}
+
+// Records written before revisions existed have no revision attribute at all.
+fn revision_condition(expected: u64) -> &'static str {
+    if expected == 0 {
+        "attribute_not_exists(#rev) OR #rev = :expected"
+    } else {
+        "#rev = :expected"
+    }
+}
+
+// `Column::increment` for backends that keep items as JSON.
+fn increment_at(item: &mut serde_json::Value, path: &[&str]) -> Result<u64, WriteError> {
+    let mut field = &mut *item;
+    for segment in path {
+        field = field
+            .get_mut(*segment)
+            .ok_or_else(|| format!("`{}` is missing", path.join(".")))?;
+    }
+    let n = field
+        .as_u64()
+        .ok_or_else(|| format!("`{}` is not a number", path.join(".")))?
+        + 1;
+    *field = serde_json::Value::from(n);
+    let revision = item
+        .get(REVISION_FIELD)
+        .and_then(|r| r.as_u64())
+        .unwrap_or(0);
+    item[REVISION_FIELD] = serde_json::Value::from(revision + 1);
+    Ok(n)
+}
+
+// Checks `item` against the stored copy and bumps its revision, for backends
+// that keep items as JSON.
+fn bump_revision(
+    item: &mut serde_json::Value,
+    stored: Option<&serde_json::Value>,
+) -> Result<(), WriteError> {
+    if let Some(revision) = item.get(REVISION_FIELD).and_then(|r| r.as_u64()) {
+        let stored_revision = stored
+            .and_then(|s| s.get(REVISION_FIELD))
+            .and_then(|r| r.as_u64())
+            .unwrap_or(0);
+        if stored_revision != revision {
+            return Err(WriteError::Conflict);
+        }
+        item[REVISION_FIELD] = serde_json::Value::from(revision + 1);
+    }
+    Ok(())
+}
+
+// A change to a single top-level attribute of a stored item, worked out from
+// the serde_diff between the original and the updated value.
+#[derive(PartialEq, Debug)]
+enum FieldChange {
+    Set(String),
+    // Only new elements were pushed onto the end of a list. The path is the
+    // field, followed by the key when the list is a value of a map field.
+    Append(Vec<String>, usize),
+}
+
+fn diff_fields<S: SerdeDiff + Serialize>(old: &S, new: &S) -> Result<Vec<FieldChange>, String> {
+    let commands = serde_json::to_value(Diff::serializable(old, new)).map_err(|e| e.to_string())?;
+    let new_value = serde_json::to_value(new).map_err(|e| e.to_string())?;
+    let commands = commands.as_array().cloned().unwrap_or_default();
+
+    let mut changes = vec![];
+    let mut iter = commands.iter();
+    while let Some(cmd) = iter.next() {
+        let field = cmd
+            .get("Enter")
+            .and_then(|e| e.get("Field"))
+            .and_then(|f| f.as_str())
+            .ok_or(format!("unexpected diff command {}", cmd))?;
+
+        // Walk to the end of this field's commands. `Value` and `Remove` close
+        // the path element they belong to, the same way serde_diff skips them.
+        let mut depth = 1;
+        // Lists of a map are entered with `EnterKey` first.
+        let mut key: Option<String> = None;
+        let mut appended: Vec<(Option<String>, usize)> = vec![];
+        let mut only_appends = true;
+        while depth > 0 {
+            let list_depth = if key.is_some() { 2 } else { 1 };
+            match iter.next() {
+                Some(serde_json::Value::String(exit)) if exit == "Exit" => {
+                    depth -= 1;
+                    if depth == 1 {
+                        key = None;
+                    }
+                }
+                Some(serde_json::Value::Object(cmd)) if cmd.contains_key("Enter") => {
+                    if depth != list_depth || cmd["Enter"] != "AddToCollection" {
+                        only_appends = false;
+                    }
+                    depth += 1;
+                }
+                Some(serde_json::Value::Object(cmd)) if cmd.contains_key("Value") => {
+                    if depth == list_depth + 1 {
+                        match appended.iter_mut().find(|(k, _)| *k == key) {
+                            Some((_, count)) => *count += 1,
+                            None => appended.push((key.clone(), 1)),
+                        }
+                    } else {
+                        only_appends = false;
+                    }
+                    depth -= 1;
+                }
+                Some(serde_json::Value::Object(cmd)) if cmd.contains_key("EnterKey") => {
+                    key = cmd["EnterKey"].as_str().map(str::to_string);
+                    if depth != 1 || key.is_none() {
+                        only_appends = false;
+                    }
+                    depth += 1;
+                }
+                Some(serde_json::Value::Object(cmd)) if cmd.contains_key("AddKey") => {
+                    only_appends = false;
+                    depth += 1;
+                }
+                Some(serde_json::Value::Object(cmd)) if cmd.contains_key("Remove") => {
+                    only_appends = false;
+                    depth -= 1;
+                }
+                Some(_) => only_appends = false,
+                None => break,
+            }
+        }
+
+        let list = |key: &Option<String>| match key {
+            Some(key) => &new_value[field][key],
+            None => &new_value[field],
+        };
+        if field == REVISION_FIELD {
+            continue;
+        } else if only_appends
+            && !appended.is_empty()
+            && appended.iter().all(|(key, _)| list(key).is_array())
+        {
+            for (key, count) in appended {
+                let path = std::iter::once(field.to_string()).chain(key).collect();
+                changes.push(FieldChange::Append(path, count));
+            }
+        } else {
+            changes.push(FieldChange::Set(field.to_string()));
+        }
+    }
+
+    Ok(changes)
+}
+
+// Replays the changes between `old` and `new` on top of `target`, so anything
+// written to `target` since `old` was read is kept.
+fn apply_diff<S: SerdeDiff>(old: &S, new: &S, target: &mut S) -> Result<(), String> {
+    let diff = serde_json::to_string(&Diff::serializable(old, new)).map_err(|e| e.to_string())?;
+    Apply::apply(&mut serde_json::Deserializer::from_str(&diff), target).map_err(|e| e.to_string())
+}
+
+// `diff_update` for backends that can't patch items in place: the changes are
+// replayed on top of the latest stored copy instead.
+async fn merge_update<
+    D,
+    C: Column<D, String>,
+    S: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName,
+>(
+    col: &C,
+    db: &D,
+    k: &str,
+    v: &S,
+    f: impl FnOnce(&mut S) -> (),
+) -> Result<(), WriteError> {
+    let mut val = v.clone();
+    f(&mut val);
+
+    let changes = diff_fields(v, &val)?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let appends_only = changes.iter().all(|c| matches!(c, FieldChange::Append(..)));
+    loop {
+        let result = match col.get::<S>(db, k).await? {
+            Some(mut current) => {
+                if !appends_only && revision_of(v)? != revision_of(&current)? {
+                    return Err(WriteError::Conflict);
+                }
+                apply_diff(v, &val, &mut current)?;
+                col.put(db, k, current).await
+            }
+            None => col.put(db, k, val.clone()).await,
+        };
+        // Appends can't overwrite anything, so if another write got in
+        // between reading and writing they are replayed on the newer copy.
+        match result {
+            Err(WriteError::Conflict) if appends_only => continue,
+            result => return result,
+        }
+    }
+}
+
+// An `UpdateItem` request that only touches what changed between `old` and
+// `new`.
+#[derive(Debug)]
+struct UpdateRequest {
+    expression: String,
+    condition: Option<&'static str>,
+    names: HashMap<String, String>,
+    values: HashMap<String, AttributeValue>,
+}
+
+fn update_request<S: SerdeDiff + Serialize + PrimaryKeyName>(
+    old: &S,
+    new: &S,
+) -> Result<Option<UpdateRequest>, WriteError> {
+    let changes = diff_fields(old, new)?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+
+    let item: HashMap<String, AttributeValue> =
+        serde_dynamo::to_item(new).map_err(|e| e.to_string())?;
+    let mut set: Vec<String> = vec![];
+    let mut remove: Vec<String> = vec![];
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut values: HashMap<String, AttributeValue> = HashMap::new();
+    for (i, change) in changes.iter().enumerate() {
+        let name = format!("#f{}", i);
+        let value = format!(":v{}", i);
+        match change {
+            FieldChange::Set(field) if field == S::get_primary_key_name() => continue,
+            FieldChange::Set(field) => {
+                names.insert(name.clone(), field.clone());
+                if let Some(attr) = item.get(field) {
+                    values.insert(value.clone(), attr.clone());
+                    set.push(format!("{} = {}", name, value));
+                } else {
+                    remove.push(name);
+                }
+            }
+            FieldChange::Append(path, count) => {
+                let mut attr = item.get(&path[0]);
+                for key in &path[1..] {
+                    attr = match attr {
+                        Some(AttributeValue::M(map)) => map.get(key),
+                        _ => None,
+                    };
+                }
+                let list = match attr {
+                    Some(AttributeValue::L(list)) => list,
+                    _ => return Err(format!("`{}` is not a list", path.join(".")).into()),
+                };
+                let mut path_names = vec![];
+                for (j, segment) in path.iter().enumerate() {
+                    let segment_name = format!("{}_{}", name, j);
+                    names.insert(segment_name.clone(), segment.clone());
+                    path_names.push(segment_name);
+                }
+                let path_name = path_names.join(".");
+                values.insert(
+                    value.clone(),
+                    AttributeValue::L(list[list.len().saturating_sub(*count)..].to_vec()),
+                );
+                values.insert(":empty".to_string(), AttributeValue::L(vec![]));
+                set.push(format!(
+                    "{} = list_append(if_not_exists({}, :empty), {})",
+                    path_name, path_name, value
+                ));
+            }
+        }
+    }
+
+    if set.is_empty() && remove.is_empty() {
+        return Ok(None);
+    }
+
+    let mut expression = String::new();
+    if !set.is_empty() {
+        expression += &format!("SET {}", set.join(", "));
+    }
+    if !remove.is_empty() {
+        expression += &format!(" REMOVE {}", remove.join(", "));
+    }
+
+    let mut condition = None;
+    if let Some(revision) = revision_of(old)? {
+        names.insert("#rev".to_string(), REVISION_FIELD.to_string());
+        values.insert(":one".to_string(), AttributeValue::N("1".to_string()));
+        expression += " ADD #rev :one";
+        // Appending to a list can't overwrite anyone else's changes, so
+        // only replaced attributes need the stored revision to match. New
+        // notes get their id from `Column::increment` rather than setting
+        // `note_counter`, so adding one is never conditional.
+        if changes.iter().any(|c| matches!(c, FieldChange::Set(_))) {
+            values.insert(
+                ":expected".to_string(),
+                AttributeValue::N(revision.to_string()),
+            );
+            condition = Some(revision_condition(revision));
+        }
+    }
+
+    Ok(Some(UpdateRequest {
+        expression: expression.trim().to_string(),
+        condition,
+        names,
+        values,
+    }))
+}
+
+// The `UpdateItem` request for `Column::increment`. The item has to exist
+// already, or DynamoDB would create one holding just the number.
+fn increment_request(key_name: &str, path: &[&str]) -> UpdateRequest {
+    let mut names = HashMap::from([
+        ("#key".to_string(), key_name.to_string()),
+        ("#rev".to_string(), REVISION_FIELD.to_string()),
+    ]);
+    let mut path_names = vec![];
+    for (i, segment) in path.iter().enumerate() {
+        names.insert(format!("#p{}", i), segment.to_string());
+        path_names.push(format!("#p{}", i));
+    }
+    UpdateRequest {
+        expression: format!("ADD {} :one, #rev :one", path_names.join(".")),
+        condition: Some("attribute_exists(#key)"),
+        names,
+        values: HashMap::from([(":one".to_string(), AttributeValue::N("1".to_string()))]),
+    }
+}
+
 impl<S: PrimaryKeyName> PrimaryKeyName for Diff<'_, '_, S> {
     fn get_primary_key_name() -> &'static str {
         S::get_primary_key_name()
@@ -192,7 +1033,7 @@ impl DynamoDB {
         }
     }
 
-    pub async fn create_table<S: PrimaryKeyName>(&self, table: &str) {
+    pub async fn create_table<S: PrimaryKeyName>(&self, table: &str) -> Result<(), String> {
         let result = self
             .internal
             .describe_table()
@@ -222,10 +1063,48 @@ impl DynamoDB {
                 .provisioned_throughput(pt)
                 .send()
                 .await;
-            if res.is_err() {
-                println!("{:?}", res)
+            if let Err(e) = res {
+                return Err(e.into_service_error().to_string());
             }
         }
+        Ok(())
+    }
+}
+
+impl DynamoDB {
+    pub async fn enable_ttl(&self, table: &str, attribute: &str) -> Result<(), String> {
+        let enabled = self
+            .internal
+            .describe_time_to_live()
+            .table_name(table)
+            .send()
+            .await
+            .ok()
+            .and_then(|res| res.time_to_live_description().cloned())
+            .and_then(|ttl| ttl.time_to_live_status().cloned())
+            .is_some_and(|status| {
+                status == TimeToLiveStatus::Enabled || status == TimeToLiveStatus::Enabling
+            });
+        if enabled {
+            return Ok(());
+        }
+
+        let res = self
+            .internal
+            .update_time_to_live()
+            .table_name(table)
+            .time_to_live_specification(
+                TimeToLiveSpecification::builder()
+                    .enabled(true)
+                    .attribute_name(attribute)
+                    .build(),
+            )
+            .send()
+            .await;
+        if let Err(e) = res {
+            return Err(e.into_service_error().to_string());
+        }
+        Ok(())
     }
 }
 
@@ -297,16 +1176,66 @@ impl Column<DynamoDB, String> for DynamoDBColumn {
         db: &DynamoDB,
         _k: &str,
         v: S,
-    ) -> Result<(), String> {
-        let item = serde_dynamo::to_item(v);
-        let put_item = db
+    ) -> Result<(), WriteError> {
+        let mut item: HashMap<String, AttributeValue> =
+            serde_dynamo::to_item(v).map_err(|e| e.to_string())?;
+        let mut put_item = db.internal.put_item().table_name(self.table_name.clone());
+        if let Some(AttributeValue::N(revision)) = item.get(REVISION_FIELD).cloned() {
+            let revision: u64 = revision
+                .parse()
+                .map_err(|_| "invalid revision".to_string())?;
+            item.insert(
+                REVISION_FIELD.to_string(),
+                AttributeValue::N((revision + 1).to_string()),
+            );
+            put_item = put_item
+                .condition_expression(revision_condition(revision))
+                .expression_attribute_names("#rev", REVISION_FIELD)
+                .expression_attribute_values(":expected", AttributeValue::N(revision.to_string()));
+        }
+        let res = put_item.set_item(Some(item)).send().await;
+        if let Err(e) = res {
+            let e = e.into_service_error();
+            if e.is_conditional_check_failed_exception() {
+                return Err(WriteError::Conflict);
+            }
+            return Err(WriteError::Failed(e.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn create<S: Serialize + PrimaryKeyName>(
+        &self,
+        db: &DynamoDB,
+        _k: &str,
+        v: S,
+    ) -> Result<(), WriteError> {
+        let mut item: HashMap<String, AttributeValue> =
+            serde_dynamo::to_item(v).map_err(|e| e.to_string())?;
+        if let Some(AttributeValue::N(revision)) = item.get(REVISION_FIELD).cloned() {
+            let revision: u64 = revision
+                .parse()
+                .map_err(|_| "invalid revision".to_string())?;
+            item.insert(
+                REVISION_FIELD.to_string(),
+                AttributeValue::N((revision + 1).to_string()),
+            );
+        }
+        let res = db
             .internal
             .put_item()
             .table_name(self.table_name.clone())
-            .set_item(Some(item.map_err(|e| e.to_string())?));
-        let res = put_item.send().await;
+            .condition_expression("attribute_not_exists(#key)")
+            .expression_attribute_names("#key", S::get_primary_key_name())
+            .set_item(Some(item))
+            .send()
+            .await;
         if let Err(e) = res {
-            println!("{:?}", e);
+            let e = e.into_service_error();
+            if e.is_conditional_check_failed_exception() {
+                return Err(WriteError::Conflict);
+            }
+            return Err(WriteError::Failed(e.to_string()));
         }
         Ok(())
     }
@@ -316,7 +1245,7 @@ impl Column<DynamoDB, String> for DynamoDBColumn {
         db: &DynamoDB,
         k: &str,
         f: impl FnOnce(&mut S) -> (),
-    ) -> Result<(), String> {
+    ) -> Result<(), WriteError> {
         let v: Option<S> = self.get::<S>(db, k).await?;
         if let Some(mut v) = v {
             f(&mut v);
@@ -332,13 +1261,42 @@ impl Column<DynamoDB, String> for DynamoDBColumn {
         k: &str,
         v: &S,
         f: impl FnOnce(&mut S) -> (),
-    ) -> Result<(), String>
+    ) -> Result<(), WriteError>
     where
         S: PrimaryKeyName,
     {
         let mut val = v.clone();
         f(&mut val);
-        self.put(&db, k, &val).await?;
+
+        let Some(update) = update_request(v, &val)? else {
+            return Ok(());
+        };
+
+        let res = db
+            .internal
+            .update_item()
+            .table_name(self.table_name.clone())
+            .key(
+                S::get_primary_key_name(),
+                serde_dynamo::to_attribute_value(k).map_err(|e| e.to_string())?,
+            )
+            .update_expression(update.expression)
+            .set_condition_expression(update.condition.map(|c| c.to_string()))
+            .set_expression_attribute_names(Some(update.names))
+            .set_expression_attribute_values(if update.values.is_empty() {
+                None
+            } else {
+                Some(update.values)
+            })
+            .send()
+            .await;
+        if let Err(e) = res {
+            let e = e.into_service_error();
+            if e.is_conditional_check_failed_exception() {
+                return Err(WriteError::Conflict);
+            }
+            return Err(WriteError::Failed(e.to_string()));
+        }
 
         Ok(())
     }
@@ -357,6 +1315,275 @@ impl Column<DynamoDB, String> for DynamoDBColumn {
 
         Ok(())
     }
+
+    async fn increment<S: PrimaryKeyName>(
+        &self,
+        db: &DynamoDB,
+        k: &str,
+        path: &[&str],
+    ) -> Result<u64, WriteError> {
+        let update = increment_request(S::get_primary_key_name(), path);
+        let res = db
+            .internal
+            .update_item()
+            .table_name(self.table_name.clone())
+            .key(
+                S::get_primary_key_name(),
+                serde_dynamo::to_attribute_value(k).map_err(|e| e.to_string())?,
+            )
+            .update_expression(update.expression)
+            .set_condition_expression(update.condition.map(|c| c.to_string()))
+            .set_expression_attribute_names(Some(update.names))
+            .set_expression_attribute_values(Some(update.values))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await
+            .map_err(|e| WriteError::Failed(e.into_service_error().to_string()))?;
+
+        let mut attr = res.attributes().and_then(|a| a.get(path[0]));
+        for segment in &path[1..] {
+            attr = match attr {
+                Some(AttributeValue::M(map)) => map.get(*segment),
+                _ => None,
+            };
+        }
+        match attr {
+            Some(AttributeValue::N(n)) => Ok(n
+                .parse()
+                .map_err(|_| format!("`{}` is not a number", path.join(".")))?),
+            _ => Err(format!("`{}` is missing", path.join(".")).into()),
+        }
+    }
+}
+
+impl Backend {
+    pub async fn create_table<S: PrimaryKeyName>(&self, table: &str) -> Result<(), String> {
+        match self {
+            Backend::DynamoDB(db) => db.create_table::<S>(table).await,
+            Backend::Sqlite(db) => db.create_table::<S>(table).await,
+            Backend::Memory(db) => db.create_table::<S>(table).await,
+        }
+    }
+}
+
+impl Backend {
+    // Lets the database drop items once the unix timestamp in `attribute` has
+    // passed. Only DynamoDB supports this.
+    pub async fn enable_ttl(&self, table: &str, attribute: &str) -> Result<(), String> {
+        match self {
+            Backend::DynamoDB(db) => db.enable_ttl(table, attribute).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a> Database<'a, BackendColumn> for Backend {
+    fn save(&self) {
+        match self {
+            Backend::DynamoDB(db) => db.save(),
+            Backend::Sqlite(db) => db.save(),
+            Backend::Memory(db) => db.save(),
+        }
+    }
+
+    #[allow(deprecated)]
+    fn column<'b: 'a>(&'b self, name: &str) -> BackendColumn {
+        match self {
+            Backend::DynamoDB(db) => BackendColumn::DynamoDB(db.column(name)),
+            Backend::Sqlite(db) => BackendColumn::Sqlite(db.column(name)),
+            Backend::Memory(db) => BackendColumn::Memory(db.column(name)),
+        }
+    }
+}
+
+const MISMATCHED_BACKEND: &str = "column does not belong to this database backend";
+
+impl Column<Backend, String> for BackendColumn {
+    async fn get_values<S: DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+    ) -> Result<Vec<S>, String> {
+        match (self, db) {
+            (BackendColumn::DynamoDB(col), Backend::DynamoDB(db)) => col.get_values(db).await,
+            (BackendColumn::Sqlite(col), Backend::Sqlite(db)) => col.get_values(db).await,
+            (BackendColumn::Memory(col), Backend::Memory(db)) => col.get_values(db).await,
+            _ => Err(MISMATCHED_BACKEND.to_string()),
+        }
+    }
+
+    async fn get<S: DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+    ) -> Result<Option<S>, String> {
+        match (self, db) {
+            (BackendColumn::DynamoDB(col), Backend::DynamoDB(db)) => col.get(db, k).await,
+            (BackendColumn::Sqlite(col), Backend::Sqlite(db)) => col.get(db, k).await,
+            (BackendColumn::Memory(col), Backend::Memory(db)) => col.get(db, k).await,
+            _ => Err(MISMATCHED_BACKEND.to_string()),
+        }
+    }
+
+    async fn put<S: Serialize + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+        v: S,
+    ) -> Result<(), WriteError> {
+        match (self, db) {
+            (BackendColumn::DynamoDB(col), Backend::DynamoDB(db)) => col.put(db, k, v).await,
+            (BackendColumn::Sqlite(col), Backend::Sqlite(db)) => col.put(db, k, v).await,
+            (BackendColumn::Memory(col), Backend::Memory(db)) => col.put(db, k, v).await,
+            _ => Err(MISMATCHED_BACKEND.to_string().into()),
+        }
+    }
+
+    async fn create<S: Serialize + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+        v: S,
+    ) -> Result<(), WriteError> {
+        match (self, db) {
+            (BackendColumn::DynamoDB(col), Backend::DynamoDB(db)) => col.create(db, k, v).await,
+            (BackendColumn::Sqlite(col), Backend::Sqlite(db)) => col.create(db, k, v).await,
+            (BackendColumn::Memory(col), Backend::Memory(db)) => col.create(db, k, v).await,
+            _ => Err(MISMATCHED_BACKEND.to_string().into()),
+        }
+    }
+
+    #[allow(deprecated)]
+    async fn get_update<S: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+        f: impl FnOnce(&mut S) -> (),
+    ) -> Result<(), WriteError> {
+        match (self, db) {
+            (BackendColumn::DynamoDB(col), Backend::DynamoDB(db)) => col.get_update(db, k, f).await,
+            (BackendColumn::Sqlite(col), Backend::Sqlite(db)) => col.get_update(db, k, f).await,
+            (BackendColumn::Memory(col), Backend::Memory(db)) => col.get_update(db, k, f).await,
+            _ => Err(MISMATCHED_BACKEND.to_string().into()),
+        }
+    }
+
+    async fn diff_update<S: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+        v: &S,
+        f: impl FnOnce(&mut S) -> (),
+    ) -> Result<(), WriteError> {
+        match (self, db) {
+            (BackendColumn::DynamoDB(col), Backend::DynamoDB(db)) => {
+                col.diff_update(db, k, v, f).await
+            }
+            (BackendColumn::Sqlite(col), Backend::Sqlite(db)) => col.diff_update(db, k, v, f).await,
+            (BackendColumn::Memory(col), Backend::Memory(db)) => col.diff_update(db, k, v, f).await,
+            _ => Err(MISMATCHED_BACKEND.to_string().into()),
+        }
+    }
+
+    async fn delete<S: PrimaryKeyName>(&self, db: &Backend, k: &str) -> Result<(), String> {
+        match (self, db) {
+            (BackendColumn::DynamoDB(col), Backend::DynamoDB(db)) => col.delete::<S>(db, k).await,
+            (BackendColumn::Sqlite(col), Backend::Sqlite(db)) => col.delete::<S>(db, k).await,
+            (BackendColumn::Memory(col), Backend::Memory(db)) => col.delete::<S>(db, k).await,
+            _ => Err(MISMATCHED_BACKEND.to_string()),
+        }
+    }
+
+    async fn increment<S: PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+        path: &[&str],
+    ) -> Result<u64, WriteError> {
+        match (self, db) {
+            (BackendColumn::DynamoDB(col), Backend::DynamoDB(db)) => {
+                col.increment::<S>(db, k, path).await
+            }
+            (BackendColumn::Sqlite(col), Backend::Sqlite(db)) => {
+                col.increment::<S>(db, k, path).await
+            }
+            (BackendColumn::Memory(col), Backend::Memory(db)) => {
+                col.increment::<S>(db, k, path).await
+            }
+            _ => Err(MISMATCHED_BACKEND.to_string().into()),
+        }
+    }
+}
+
+// A column whose writes are all recorded in the audit log, for the stores
+// that read straight from the database instead of through a `CachingColumn`.
+pub struct AuditedColumn {
+    internal: BackendColumn,
+    table: String,
+    log: BackendColumn,
+}
+
+impl AuditedColumn {
+    pub fn new(col: BackendColumn, table: &str, log: BackendColumn) -> Self {
+        Self {
+            internal: col,
+            table: table.to_string(),
+            log,
+        }
+    }
+
+    // The write itself already happened, so failing to record it is only
+    // logged.
+    async fn record_write<S: SerdeDiff + Serialize + DeserializeOwned>(
+        &self,
+        db: &Backend,
+        k: &str,
+        before: Option<S>,
+        after: Option<S>,
+    ) {
+        if let Err(e) = crate::audit::record(&self.log, db, &self.table, k, &before, &after).await {
+            println!("{}", e);
+        }
+    }
+
+    pub async fn get_values<S: DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+    ) -> Result<Vec<S>, String> {
+        self.internal.get_values::<S>(db).await
+    }
+
+    pub async fn get<S: DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+    ) -> Result<Option<S>, String> {
+        self.internal.get::<S>(db, k).await
+    }
+
+    pub async fn put<S: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+        v: S,
+    ) -> Result<(), WriteError> {
+        let before = self.internal.get::<S>(db, k).await?;
+        self.internal.put::<S>(db, k, v.clone()).await?;
+        self.record_write(db, k, before, Some(v)).await;
+        Ok(())
+    }
+
+    pub async fn delete<S: SerdeDiff + Serialize + DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &Backend,
+        k: &str,
+    ) -> Result<(), String> {
+        let before = self.internal.get::<S>(db, k).await?;
+        self.internal.delete::<S>(db, k).await?;
+        if before.is_some() {
+            self.record_write::<S>(db, k, before, None).await;
+        }
+        Ok(())
+    }
 }
 
 struct CachedValue<DT: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName> {
@@ -375,30 +1602,68 @@ impl<DT: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName> From
     }
 }
 
-pub struct CachingDynamoDBColumn<
-    DT: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName,
-> {
-    internal: DynamoDBColumn,
+pub struct CachingColumn<DT: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName> {
+    internal: BackendColumn,
     cached: HashMap<String, CachedValue<DT>>,
     got_values: Option<DateTime<Local>>,
     expiration_time: Duration,
+    // Where writes are recorded and the table name they are recorded under.
+    audit: Option<(String, BackendColumn)>,
+    on_write: Option<WriteHook<DT>>,
 }
 
+// Called with the key and the stored record after each write, or `None` once
+// the record was deleted.
+pub type WriteHook<DT> = std::sync::Arc<dyn Fn(&str, Option<&DT>) + Send + Sync>;
+
 #[allow(dead_code)]
-impl<DT> CachingDynamoDBColumn<DT>
+impl<DT> CachingColumn<DT>
 where
     DT: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName + PrimaryKeyValue<String>,
 {
-    pub fn from(col: DynamoDBColumn) -> Self {
+    pub fn from(col: BackendColumn) -> Self {
         Self {
             internal: col,
             cached: HashMap::new(),
             got_values: None,
             expiration_time: Duration::minutes(30),
+            audit: None,
+            on_write: None,
+        }
+    }
+
+    // Records every write to the column in `log`, see `audit.rs`.
+    pub fn audited(mut self, table: &str, log: BackendColumn) -> Self {
+        self.audit = Some((table.to_string(), log));
+        self
+    }
+
+    // Lets other parts of the app follow changes to the column, e.g. the search
+    // index in `search.rs`.
+    pub fn on_write(mut self, hook: WriteHook<DT>) -> Self {
+        self.on_write = Some(hook);
+        self
+    }
+
+    // The stored record before a write, only needed when writes are audited.
+    async fn before_write(&self, db: &Backend, k: &str) -> Result<Option<DT>, String> {
+        match self.audit {
+            Some(_) => self.internal.get::<DT>(db, k).await,
+            None => Ok(None),
         }
     }
 
-    pub async fn get_values(&mut self, db: &DynamoDB) -> Result<Vec<DT>, String> {
+    // The write itself already happened, so failing to record it is only
+    // logged.
+    async fn record_write(&self, db: &Backend, k: &str, before: Option<DT>, after: Option<DT>) {
+        if let Some((table, log)) = &self.audit
+            && let Err(e) = crate::audit::record(log, db, table, k, &before, &after).await
+        {
+            println!("{}", e);
+        }
+    }
+
+    pub async fn get_values(&mut self, db: &Backend) -> Result<Vec<DT>, String> {
         let result = self.internal.get_values::<DT>(&db).await;
         if self
             .got_values
@@ -417,7 +1682,7 @@ where
         }
     }
 
-    pub async fn get(&mut self, db: &DynamoDB, k: &str) -> Result<Option<DT>, String> {
+    pub async fn get(&mut self, db: &Backend, k: &str) -> Result<Option<DT>, String> {
         let v = self.cached.get(k);
         if v.is_some_and(|v| Local::now() - v.cached_at < self.expiration_time) {
             return Ok(Some(v.unwrap().value.clone()));
@@ -430,28 +1695,79 @@ where
         }
     }
 
-    pub async fn put(&mut self, db: &DynamoDB, _k: &str, v: DT) -> Result<(), String> {
+    pub async fn put(&mut self, db: &Backend, _k: &str, v: DT) -> Result<(), WriteError> {
+        let before = self.before_write(db, _k).await?;
         let result = self.internal.put::<DT>(&db, _k, v.clone()).await;
         if result.is_ok() {
+            self.record_write(db, _k, before, Some(v.clone())).await;
+            if let Some(hook) = &self.on_write {
+                hook(_k, Some(&v));
+            }
+        }
+        if result.is_ok() && revision_of(&v)?.is_none() {
             self.cached.insert(_k.to_string(), v.clone().into());
+        } else {
+            // Either the write failed or it bumped the stored revision, so the
+            // local copy is out of date.
+            self.cached.remove(_k);
+        }
+        result
+    }
+
+    // See `Column::increment`.
+    pub async fn increment(
+        &mut self,
+        db: &Backend,
+        k: &str,
+        path: &[&str],
+    ) -> Result<u64, WriteError> {
+        let before = self.before_write(db, k).await?;
+        let result = self.internal.increment::<DT>(db, k, path).await;
+        self.cached.remove(k);
+        if result.is_ok() && self.audit.is_some() {
+            match self.internal.get::<DT>(db, k).await {
+                Ok(after) => self.record_write(db, k, before, after).await,
+                Err(e) => println!("{}", e),
+            }
+        }
+        result
+    }
+
+    // Adds a record that must not exist yet, see `Column::create`.
+    pub async fn create(&mut self, db: &Backend, k: &str, v: DT) -> Result<(), WriteError> {
+        let result = self.internal.create::<DT>(db, k, v.clone()).await;
+        if result.is_ok() {
+            self.record_write(db, k, None, Some(v.clone())).await;
+            if let Some(hook) = &self.on_write {
+                hook(k, Some(&v));
+            }
         }
+        // The stored revision was bumped, so the local copy is out of date.
+        self.cached.remove(k);
         result
     }
 
-    pub async fn delete(&mut self, db: &DynamoDB, k: &str) -> Result<(), String> {
+    pub async fn delete(&mut self, db: &Backend, k: &str) -> Result<(), String> {
+        let before = self.before_write(db, k).await?;
         let result = self.internal.delete::<DT>(&db, k).await;
         if result.is_ok() {
             self.cached.remove(k);
+            if before.is_some() {
+                self.record_write(db, k, before, None).await;
+            }
+            if let Some(hook) = &self.on_write {
+                hook(k, None);
+            }
         }
         result
     }
 
     pub async fn get_update(
         &mut self,
-        db: &DynamoDB,
+        db: &Backend,
         k: &str,
         f: impl FnOnce(&mut DT) -> (),
-    ) -> Result<(), String> {
+    ) -> Result<(), WriteError> {
         let v = self.get(&db, k).await?;
         if let Some(mut v) = v {
             f(&mut v);
@@ -462,15 +1778,269 @@ where
 
     pub async fn diff_update(
         &mut self,
-        db: &DynamoDB,
+        db: &Backend,
         k: &str,
         v: &DT,
         f: impl FnOnce(&mut DT) -> (),
-    ) -> Result<(), String> {
-        let mut mv = v.clone();
-        f(&mut mv);
-        self.put(&db, k, mv.clone()).await?;
-        
-        Ok(())
+    ) -> Result<(), WriteError> {
+        // The backend merges the difference between `v` and the changed copy
+        // into the stored record, so that difference is what gets recorded.
+        let mut changed = v.clone();
+        f(&mut changed);
+        let result = self
+            .internal
+            .diff_update(db, k, v, |u| *u = changed.clone())
+            .await;
+        if result.is_ok() && self.audit.is_some() && !diff_fields(v, &changed)?.is_empty() {
+            self.record_write(db, k, Some(v.clone()), Some(changed))
+                .await;
+        }
+        // The hook gets the merged record, not just this instance's changes.
+        if result.is_ok()
+            && let Some(hook) = &self.on_write
+        {
+            match self.internal.get::<DT>(db, k).await {
+                Ok(stored) => hook(k, stored.as_ref()),
+                Err(e) => println!("{}", e),
+            }
+        }
+        // The stored item may now hold changes made elsewhere, so fetch it
+        // again next time instead of trusting the local copy.
+        self.cached.remove(k);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_harness::student;
+
+    fn note(id: u32, content: &str) -> Note {
+        Note {
+            id,
+            created: None,
+            edited: None,
+            user: "Sensei".to_string(),
+            content: content.to_string(),
+            revisions: vec![],
+        }
+    }
+
+    #[test]
+    fn appended_notes_become_list_appends() {
+        let old = student("s1", "Ada Lovelace");
+        let mut new = old.clone();
+        let notes = new.notes.get_mut("notes").unwrap();
+        notes.push(note(1, "first"));
+        notes.push(note(2, "second"));
+        new.notes.get_mut("logins").unwrap().push(note(3, "third"));
+        new.note_counter.add(3);
+
+        assert_eq!(
+            diff_fields(&old, &new).unwrap(),
+            vec![
+                FieldChange::Append(vec!["notes".to_string(), "logins".to_string()], 1),
+                FieldChange::Append(vec!["notes".to_string(), "notes".to_string()], 2),
+                FieldChange::Set("note_counter".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn edited_and_removed_notes_replace_the_list() {
+        let mut old = student("s1", "Ada Lovelace");
+        old.notes.get_mut("logins").unwrap().push(note(1, "first"));
+        old.notes
+            .get_mut("behaviours")
+            .unwrap()
+            .push(note(2, "second"));
+        let mut new = old.clone();
+        new.notes.get_mut("logins").unwrap()[0].content = "edited".to_string();
+        new.notes.get_mut("behaviours").unwrap().clear();
+        new.assigned = Some("Sensei".to_string());
+
+        assert_eq!(
+            diff_fields(&old, &new).unwrap(),
+            vec![
+                FieldChange::Set("notes".to_string()),
+                FieldChange::Set("assigned".to_string()),
+            ]
+        );
+
+        // Nor can notes in a new category be appended.
+        let mut new = old.clone();
+        new.notes
+            .insert("medical".to_string(), vec![note(3, "allergies")]);
+        assert_eq!(
+            diff_fields(&old, &new).unwrap(),
+            vec![FieldChange::Set("notes".to_string())]
+        );
+    }
+
+    #[test]
+    fn unchanged_values_have_no_changes() {
+        let old = student("s1", "Ada Lovelace");
+        assert!(diff_fields(&old, &old.clone()).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_edits_conflict() {
+        let db = Backend::Memory(MemoryDB::new());
+        db.create_table::<Student>("students").await.unwrap();
+        #[allow(deprecated)]
+        let mut students: CachingColumn<Student> = CachingColumn::from(db.column("students"));
+        students
+            .put(&db, "s1", student("s1", "Ada Lovelace"))
+            .await
+            .unwrap();
+        let original = students.get(&db, "s1").await.unwrap().unwrap();
+        assert_eq!(original.revision, 1);
+
+        students
+            .diff_update(&db, "s1", &original, |s| s.assigned = Some("A".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            students
+                .diff_update(&db, "s1", &original, |s| s.assigned = Some("B".to_string()))
+                .await,
+            Err(WriteError::Conflict)
+        );
+        assert_eq!(
+            students.put(&db, "s1", original).await,
+            Err(WriteError::Conflict)
+        );
+
+        let stored = students.get(&db, "s1").await.unwrap().unwrap();
+        assert_eq!(stored.assigned, Some("A".to_string()));
+        assert_eq!(stored.revision, 2);
+    }
+
+    #[test]
+    fn new_notes_are_appended_in_the_update_expression() {
+        let mut old = student("s1", "Ada Lovelace");
+        old.revision = 4;
+        let mut new = old.clone();
+        let added = note(1, "first");
+        new.notes.get_mut("notes").unwrap().push(added.clone());
+
+        let update = update_request(&old, &new).unwrap().unwrap();
+        assert_eq!(
+            update.expression,
+            "SET #f0_0.#f0_1 = list_append(if_not_exists(#f0_0.#f0_1, :empty), :v0) \
+             ADD #rev :one"
+        );
+        assert_eq!(update.condition, None);
+        assert_eq!(
+            update.names,
+            HashMap::from([
+                ("#f0_0".to_string(), "notes".to_string()),
+                ("#f0_1".to_string(), "notes".to_string()),
+                ("#rev".to_string(), "revision".to_string()),
+            ])
+        );
+        assert_eq!(
+            update.values,
+            HashMap::from([
+                (
+                    ":v0".to_string(),
+                    AttributeValue::L(vec![serde_dynamo::to_attribute_value(&added).unwrap()])
+                ),
+                (":empty".to_string(), AttributeValue::L(vec![])),
+                (":one".to_string(), AttributeValue::N("1".to_string())),
+            ])
+        );
+    }
+
+    #[test]
+    fn note_ids_are_added_to_the_stored_counter() {
+        let update = increment_request("id", NOTE_COUNTER);
+        assert_eq!(update.expression, "ADD #p0.#p1 :one, #rev :one");
+        assert_eq!(update.condition, Some("attribute_exists(#key)"));
+        assert_eq!(
+            update.names,
+            HashMap::from([
+                ("#key".to_string(), "id".to_string()),
+                ("#rev".to_string(), "revision".to_string()),
+                ("#p0".to_string(), "note_counter".to_string()),
+                ("#p1".to_string(), "n".to_string()),
+            ])
+        );
+        assert_eq!(
+            update.values,
+            HashMap::from([(":one".to_string(), AttributeValue::N("1".to_string()))])
+        );
+    }
+
+    #[test]
+    fn replaced_fields_are_set_in_the_update_expression() {
+        let mut old = student("s1", "Ada Lovelace");
+        old.assigned = Some("Sensei".to_string());
+        let mut new = old.clone();
+        new.assigned = None;
+        new.belt = "Black".to_string();
+
+        let update = update_request(&old, &new).unwrap().unwrap();
+        assert_eq!(update.expression, "SET #f0 = :v0, #f1 = :v1 ADD #rev :one");
+        assert_eq!(
+            update.condition,
+            Some("attribute_not_exists(#rev) OR #rev = :expected")
+        );
+        assert_eq!(update.names["#f0"], "belt");
+        assert_eq!(update.names["#f1"], "assigned");
+        assert_eq!(update.values[":v0"], AttributeValue::S("Black".to_string()));
+        assert_eq!(update.values[":v1"], AttributeValue::Null(true));
+        assert_eq!(
+            update.values[":expected"],
+            AttributeValue::N("0".to_string())
+        );
+
+        assert!(update_request(&old, &old.clone()).unwrap().is_none());
+    }
+
+    // Every writer read the same copy, but as each takes its id from the
+    // stored counter and only appends, none of the notes are lost.
+    #[tokio::test]
+    async fn concurrent_notes_are_all_added() {
+        let db = Backend::Memory(MemoryDB::new());
+        db.create_table::<Student>("students").await.unwrap();
+        #[allow(deprecated)]
+        let mut students: CachingColumn<Student> = CachingColumn::from(db.column("students"));
+        students
+            .put(&db, "s1", student("s1", "Ada Lovelace"))
+            .await
+            .unwrap();
+        let original = students.get(&db, "s1").await.unwrap().unwrap();
+
+        for i in 1..=5 {
+            let id = students.increment(&db, "s1", NOTE_COUNTER).await.unwrap() as u32;
+            let content = format!("note {}", i);
+            students
+                .diff_update(&db, "s1", &original, |s| {
+                    s.notes.get_mut("notes").unwrap().push(note(id, &content))
+                })
+                .await
+                .unwrap();
+        }
+
+        let stored = students.get(&db, "s1").await.unwrap().unwrap();
+        let notes: Vec<(u32, &str)> = stored
+            .notes_in("notes")
+            .iter()
+            .map(|n| (n.id, n.content.as_str()))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (1, "note 1"),
+                (2, "note 2"),
+                (3, "note 3"),
+                (4, "note 4"),
+                (5, "note 5"),
+            ]
+        );
+        assert_eq!(stored.note_counter.get(), 5);
+        assert_eq!(stored.revision, 11);
     }
 }
@@ -0,0 +1,162 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_diff::SerdeDiff;
+
+use super::{
+    bump_revision, increment_at, merge_update, primary_key_of, Column, Database, PrimaryKeyName,
+    WriteError,
+};
+
+pub struct MemoryDB {
+    internal: Mutex<HashMap<String, HashMap<String, serde_json::Value>>>,
+}
+
+pub struct MemoryColumn {
+    table_name: String,
+}
+
+impl MemoryDB {
+    pub fn new() -> Self {
+        Self {
+            internal: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn create_table<S: PrimaryKeyName>(&self, table: &str) -> Result<(), String> {
+        self.internal
+            .lock()
+            .unwrap()
+            .entry(table.to_string())
+            .or_default();
+        Ok(())
+    }
+}
+
+impl<'a> Database<'a, MemoryColumn> for MemoryDB {
+    fn save(&self) {}
+
+    fn column<'b: 'a>(&'b self, name: &str) -> MemoryColumn {
+        MemoryColumn {
+            table_name: name.to_string(),
+        }
+    }
+}
+
+impl MemoryColumn {
+    fn with_table<R, E: From<String>>(
+        &self,
+        db: &MemoryDB,
+        f: impl FnOnce(&mut HashMap<String, serde_json::Value>) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let mut tables = db.internal.lock().unwrap();
+        match tables.get_mut(&self.table_name) {
+            Some(table) => f(table),
+            None => Err(format!("table `{}` does not exist", self.table_name).into()),
+        }
+    }
+}
+
+impl Column<MemoryDB, String> for MemoryColumn {
+    async fn get_values<S: DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &MemoryDB,
+    ) -> Result<Vec<S>, String> {
+        self.with_table(db, |table| {
+            table
+                .values()
+                .map(|v| serde_json::from_value(v.clone()).map_err(|e| e.to_string()))
+                .collect()
+        })
+    }
+
+    async fn get<S: DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &MemoryDB,
+        k: &str,
+    ) -> Result<Option<S>, String> {
+        self.with_table(db, |table| match table.get(k) {
+            Some(v) => Ok(Some(
+                serde_json::from_value(v.clone()).map_err(|e| e.to_string())?,
+            )),
+            None => Ok(None),
+        })
+    }
+
+    async fn put<S: Serialize + PrimaryKeyName>(
+        &self,
+        db: &MemoryDB,
+        _k: &str,
+        v: S,
+    ) -> Result<(), WriteError> {
+        let mut item = serde_json::to_value(v).map_err(|e| e.to_string())?;
+        let key = primary_key_of::<S>(&item)?;
+        self.with_table(db, |table| {
+            bump_revision(&mut item, table.get(&key))?;
+            table.insert(key, item);
+            Ok(())
+        })
+    }
+
+    async fn create<S: Serialize + PrimaryKeyName>(
+        &self,
+        db: &MemoryDB,
+        _k: &str,
+        v: S,
+    ) -> Result<(), WriteError> {
+        let mut item = serde_json::to_value(v).map_err(|e| e.to_string())?;
+        let key = primary_key_of::<S>(&item)?;
+        self.with_table(db, |table| {
+            if table.contains_key(&key) {
+                return Err(WriteError::Conflict);
+            }
+            bump_revision(&mut item, None)?;
+            table.insert(key, item);
+            Ok(())
+        })
+    }
+
+    async fn get_update<S: Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
+        &self,
+        db: &MemoryDB,
+        k: &str,
+        f: impl FnOnce(&mut S) -> (),
+    ) -> Result<(), WriteError> {
+        let v: Option<S> = self.get::<S>(db, k).await?;
+        if let Some(mut v) = v {
+            f(&mut v);
+            self.put(db, k, v).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn diff_update<S: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
+        &self,
+        db: &MemoryDB,
+        k: &str,
+        v: &S,
+        f: impl FnOnce(&mut S) -> (),
+    ) -> Result<(), WriteError> {
+        merge_update(self, db, k, v, f).await
+    }
+
+    async fn delete<S: PrimaryKeyName>(&self, db: &MemoryDB, k: &str) -> Result<(), String> {
+        self.with_table(db, |table| {
+            table.remove(k);
+            Ok(())
+        })
+    }
+
+    async fn increment<S: PrimaryKeyName>(
+        &self,
+        db: &MemoryDB,
+        k: &str,
+        path: &[&str],
+    ) -> Result<u64, WriteError> {
+        self.with_table(db, |table| match table.get_mut(k) {
+            Some(item) => increment_at(item, path),
+            None => Err(format!("no item `{}`", k).into()),
+        })
+    }
+}
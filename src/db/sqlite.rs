@@ -0,0 +1,312 @@
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_diff::SerdeDiff;
+
+use super::{
+    bump_revision, increment_at, merge_update, primary_key_of, Column, Database, PrimaryKeyName,
+    WriteError,
+};
+
+pub struct SqliteDB {
+    internal: Arc<Mutex<Connection>>,
+}
+
+pub struct SqliteColumn {
+    table_name: String,
+}
+
+impl SqliteDB {
+    pub fn new(path: &str) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            internal: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // rusqlite blocks, so statements run on tokio's blocking threads rather than
+    // holding up the runtime's workers while waiting for the disk or the lock.
+    async fn run<T, E>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, E> + Send + 'static,
+    ) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<String> + Send + 'static,
+    {
+        let conn = self.internal.clone();
+        tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+            .await
+            .map_err(|e| E::from(e.to_string()))?
+    }
+
+    pub async fn create_table<S: PrimaryKeyName>(&self, table: &str) -> Result<(), String> {
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS \"{}\" (\"{}\" TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL)",
+            table,
+            S::get_primary_key_name()
+        );
+        self.run(move |conn| conn.execute(&sql, []).map_err(|e| e.to_string()))
+            .await?;
+        Ok(())
+    }
+}
+
+impl<'a> Database<'a, SqliteColumn> for SqliteDB {
+    // Not awaited, the checkpoint finishes in the background.
+    fn save(&self) {
+        let conn = self.internal.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            if let Err(e) = conn.pragma_update(None, "wal_checkpoint", "TRUNCATE") {
+                println!("{:?}", e);
+            }
+        });
+    }
+
+    fn column<'b: 'a>(&'b self, name: &str) -> SqliteColumn {
+        SqliteColumn {
+            table_name: name.to_string(),
+        }
+    }
+}
+
+fn read(
+    conn: &Connection,
+    table_name: &str,
+    key_name: &str,
+    k: &str,
+) -> Result<Option<String>, String> {
+    conn.query_row(
+        &format!(
+            "SELECT value FROM \"{}\" WHERE \"{}\" = ?1",
+            table_name, key_name
+        ),
+        params![k],
+        |row| row.get::<_, String>(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+impl Column<SqliteDB, String> for SqliteColumn {
+    async fn get_values<S: DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &SqliteDB,
+    ) -> Result<Vec<S>, String> {
+        let sql = format!("SELECT value FROM \"{}\"", self.table_name);
+        let rows = db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+                let rows = stmt
+                    .query_map([], |row| row.get::<_, String>(0))
+                    .map_err(|e| e.to_string())?;
+                rows.collect::<Result<Vec<String>, _>>()
+                    .map_err(|e| e.to_string())
+            })
+            .await?;
+
+        let mut results: Vec<S> = vec![];
+        for row in rows {
+            results.push(serde_json::from_str(&row).map_err(|e| e.to_string())?);
+        }
+
+        Ok(results)
+    }
+
+    async fn get<S: DeserializeOwned + PrimaryKeyName>(
+        &self,
+        db: &SqliteDB,
+        k: &str,
+    ) -> Result<Option<S>, String> {
+        let (table_name, key_name, k) = (
+            self.table_name.clone(),
+            S::get_primary_key_name(),
+            k.to_string(),
+        );
+        let result = db
+            .run(move |conn| read(conn, &table_name, key_name, &k))
+            .await?;
+
+        if let Some(item) = result {
+            let item: S = serde_json::from_str(&item).map_err(|e| e.to_string())?;
+            Ok(Some(item))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn put<S: Serialize + PrimaryKeyName>(
+        &self,
+        db: &SqliteDB,
+        _k: &str,
+        v: S,
+    ) -> Result<(), WriteError> {
+        let mut item = serde_json::to_value(v).map_err(|e| e.to_string())?;
+        let key = primary_key_of::<S>(&item)?;
+        let table_name = self.table_name.clone();
+        let key_name = S::get_primary_key_name();
+        // Reading the stored revision and writing happen under one lock, so no
+        // other write can come in between.
+        db.run(move |conn| {
+            let stored = match read(conn, &table_name, key_name, &key)? {
+                Some(stored) => Some(serde_json::from_str(&stored).map_err(|e| e.to_string())?),
+                None => None,
+            };
+            bump_revision(&mut item, stored.as_ref())?;
+            conn.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO \"{}\" (\"{}\", value) VALUES (?1, ?2)",
+                    table_name, key_name
+                ),
+                params![key, item.to_string()],
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn create<S: Serialize + PrimaryKeyName>(
+        &self,
+        db: &SqliteDB,
+        _k: &str,
+        v: S,
+    ) -> Result<(), WriteError> {
+        let mut item = serde_json::to_value(v).map_err(|e| e.to_string())?;
+        let key = primary_key_of::<S>(&item)?;
+        bump_revision(&mut item, None)?;
+        let sql = format!(
+            "INSERT INTO \"{}\" (\"{}\", value) VALUES (?1, ?2)",
+            self.table_name,
+            S::get_primary_key_name()
+        );
+        db.run(
+            move |conn| match conn.execute(&sql, params![key, item.to_string()]) {
+                Ok(_) => Ok(()),
+                // The key is taken.
+                Err(rusqlite::Error::SqliteFailure(e, _))
+                    if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+                {
+                    Err(WriteError::Conflict)
+                }
+                Err(e) => Err(WriteError::Failed(e.to_string())),
+            },
+        )
+        .await
+    }
+
+    async fn get_update<S: Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
+        &self,
+        db: &SqliteDB,
+        k: &str,
+        f: impl FnOnce(&mut S) -> (),
+    ) -> Result<(), WriteError> {
+        let v: Option<S> = self.get::<S>(db, k).await?;
+        if let Some(mut v) = v {
+            f(&mut v);
+            self.put(db, k, v).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn diff_update<S: SerdeDiff + Serialize + DeserializeOwned + Clone + PrimaryKeyName>(
+        &self,
+        db: &SqliteDB,
+        k: &str,
+        v: &S,
+        f: impl FnOnce(&mut S) -> (),
+    ) -> Result<(), WriteError> {
+        merge_update(self, db, k, v, f).await
+    }
+
+    async fn delete<S: PrimaryKeyName>(&self, db: &SqliteDB, k: &str) -> Result<(), String> {
+        let sql = format!(
+            "DELETE FROM \"{}\" WHERE \"{}\" = ?1",
+            self.table_name,
+            S::get_primary_key_name()
+        );
+        let k = k.to_string();
+        db.run(move |conn| {
+            conn.execute(&sql, params![k]).map_err(|e| e.to_string())?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn increment<S: PrimaryKeyName>(
+        &self,
+        db: &SqliteDB,
+        k: &str,
+        path: &[&str],
+    ) -> Result<u64, WriteError> {
+        let table_name = self.table_name.clone();
+        let key_name = S::get_primary_key_name();
+        let k = k.to_string();
+        let path: Vec<String> = path.iter().map(|s| s.to_string()).collect();
+        // Read and written under one lock, like `put`.
+        db.run(move |conn| {
+            let Some(stored) = read(conn, &table_name, key_name, &k)? else {
+                return Err(format!("no item `{}`", k).into());
+            };
+            let mut item: serde_json::Value =
+                serde_json::from_str(&stored).map_err(|e| e.to_string())?;
+            let path: Vec<&str> = path.iter().map(String::as_str).collect();
+            let n = increment_at(&mut item, &path)?;
+            conn.execute(
+                &format!(
+                    "UPDATE \"{}\" SET value = ?2 WHERE \"{}\" = ?1",
+                    table_name, key_name
+                ),
+                params![k, item.to_string()],
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(n)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Backend, BackendColumn, CachingColumn, User, UserRole};
+
+    #[tokio::test]
+    async fn stores_items_by_primary_key() {
+        let db = Backend::Sqlite(SqliteDB::new(":memory:").unwrap());
+        db.create_table::<User>("users").await.unwrap();
+        #[allow(deprecated)]
+        let mut users: CachingColumn<User> = CachingColumn::from(db.column("users"));
+
+        let user = User {
+            name: "Sensei".to_string(),
+            primary_key: "sensei".to_string(),
+            hash: None,
+            password_hash: "$argon2id$hash".to_string(),
+            role: UserRole::Admin,
+            revision: 0,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: 0,
+            recovery_codes: vec![],
+            email: None,
+            pin_hash: None,
+        };
+        users.put(&db, "sensei", user).await.unwrap();
+
+        #[allow(deprecated)]
+        let uncached: BackendColumn = db.column("users");
+        let stored: User = uncached.get(&db, "sensei").await.unwrap().unwrap();
+        assert_eq!(stored.name, "Sensei");
+        assert_eq!(stored.password_hash, "$argon2id$hash");
+        assert_eq!(uncached.get_values::<User>(&db).await.unwrap().len(), 1);
+
+        users.delete(&db, "sensei").await.unwrap();
+        assert!(uncached.get::<User>(&db, "sensei").await.unwrap().is_none());
+    }
+}
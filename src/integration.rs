@@ -1,5 +1,6 @@
 use crate::counter::Counter;
 use crate::db;
+use crate::tombstone_store;
 use axum::{
     extract::State,
     response::{IntoResponse, Response},
@@ -8,7 +9,7 @@ use lazy_static::lazy_static;
 use reqwest::StatusCode;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(RustEmbed)]
 #[folder = "embed/"]
@@ -107,6 +108,16 @@ pub async fn load_students_post(State(state): State<crate::AppState>) -> Result<
         .build()
         .unwrap();
 
+    // Students deleted on the site stay deleted until they are restored, even
+    // if they still check in.
+    let retention = tombstone_store::retention(&state.settings.read().await.get(&db).await?);
+    let deleted_ids = state
+        .tombstones
+        .read()
+        .await
+        .deleted_student_ids(&db, retention)
+        .await?;
+
     let mut old_student_ids: Vec<String> = vec![];
     for student in student_col.get_values(&db).await? {
         if student.date.is_some() {
@@ -149,6 +160,13 @@ pub async fn load_students_post(State(state): State<crate::AppState>) -> Result<
 
     for (_, students) in all_participants_out.student_detail {
         for student in students {
+            if deleted_ids.contains(&student.participant_id) {
+                println!(
+                    "Skipping {} {}, who was deleted",
+                    student.participant_first_name, student.participant_last_name
+                );
+                continue;
+            }
             println!(
                 "Grabbing attendance details for {} {}",
                 student.participant_first_name, student.participant_last_name
@@ -205,21 +223,22 @@ pub async fn load_students_post(State(state): State<crate::AppState>) -> Result<
                         .iter()
                         .map(|note| db::Note {
                             id: counter.inc(),
-                            date: "".to_string(),
+                            created: None,
+                            edited: None,
                             user: "".to_string(),
                             content: note.clone(),
+                            revisions: vec![],
                         })
                         .collect::<Vec<db::Note>>()
                 }
 
-                let mut logins: Option<Vec<db::Note>> = None;
-                let mut notes: Option<Vec<db::Note>> = None;
-                let mut behaviours: Option<Vec<db::Note>> = None;
+                let mut imported_notes: BTreeMap<String, Vec<db::Note>> = BTreeMap::new();
                 let mut note_id = Counter::<u32>::new();
                 if let Some(info) = student_info {
-                    logins = Some(convert_notes(info.logins, &mut note_id));
-                    notes = Some(convert_notes(info.notes, &mut note_id));
-                    behaviours = Some(convert_notes(info.behaviours, &mut note_id));
+                    for (category, notes) in info.into_categories() {
+                        imported_notes
+                            .insert(category.to_string(), convert_notes(notes, &mut note_id));
+                    }
                 }
 
                 let update_student = match student_col.get(&db, &student.participant_id).await {
@@ -227,21 +246,26 @@ pub async fn load_students_post(State(state): State<crate::AppState>) -> Result<
                         let mut val = val.clone();
                         val.date = Some(chrono::Local::now());
                         val.time = Some(time.to_string());
+                        // Students are synced several times a day.
+                        let today = chrono::Local::now().date_naive();
+                        if val
+                            .check_ins
+                            .last()
+                            .is_none_or(|c| c.date.date_naive() != today)
+                        {
+                            val.check_ins.push(db::CheckIn {
+                                date: chrono::Local::now(),
+                                time: Some(time.to_string()),
+                            });
+                        }
                         val.belt = student.rank_name.replace(" Belt", "");
                         if has_info {
                             println!("Integrating missing notes for {}", name.clone());
-                            if val.logins.is_empty() {
-                                val.note_counter.add(logins.as_ref().unwrap().len() as u32);
-                                val.logins = logins.unwrap();
-                            }
-                            if val.notes.is_empty() {
-                                val.note_counter.add(notes.as_ref().unwrap().len() as u32);
-                                val.notes = notes.unwrap();
-                            }
-                            if val.behaviours.is_empty() {
-                                val.note_counter
-                                    .add(behaviours.as_ref().unwrap().len() as u32);
-                                val.behaviours = behaviours.unwrap();
+                            for (category, notes) in imported_notes {
+                                if val.notes_in(&category).is_empty() {
+                                    val.note_counter.add(notes.len() as u32);
+                                    val.notes.insert(category, notes);
+                                }
                             }
 
                             imported_col.delete(&db, &name).await?;
@@ -261,11 +285,14 @@ pub async fn load_students_post(State(state): State<crate::AppState>) -> Result<
                             first_name: student.participant_first_name,
                             last_name: student.participant_last_name,
                             belt: student.rank_name.replace(" Belt", ""),
-                            logins: logins.unwrap_or(vec![]),
-                            notes: notes.unwrap_or(vec![]),
-                            behaviours: behaviours.unwrap_or(vec![]),
+                            notes: imported_notes,
                             assigned: None,
                             note_counter: note_id,
+                            check_ins: vec![db::CheckIn {
+                                date: chrono::Local::now(),
+                                time: Some(time.to_string()),
+                            }],
+                            revision: 0,
                         })
                     }
                     Err(err) => {
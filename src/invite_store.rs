@@ -0,0 +1,146 @@
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::db;
+
+// Used and expired invites are kept around for a while so that someone
+// opening an old link is told why it no longer works.
+const RETENTION_DAYS: i64 = 7;
+
+#[derive(Debug, PartialEq)]
+pub enum InviteError {
+    NotFound,
+    Expired,
+    Used,
+    WrongName(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for InviteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InviteError::NotFound => write!(f, "This invite does not exist or was revoked"),
+            InviteError::Expired => write!(f, "This invite has expired"),
+            InviteError::Used => write!(f, "This invite has already been used"),
+            InviteError::WrongName(name) => write!(f, "This invite is for the user {}", name),
+            InviteError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for InviteError {
+    fn from(value: String) -> Self {
+        InviteError::Failed(value)
+    }
+}
+
+// Like sessions, invites are read straight from the database so a token
+// can't be redeemed twice by going through two instances.
+pub struct InviteStore {
+    internal: db::AuditedColumn,
+}
+
+impl InviteStore {
+    pub fn from(col: db::AuditedColumn) -> Self {
+        Self { internal: col }
+    }
+
+    pub async fn create(
+        &self,
+        db: &db::Backend,
+        creator: &str,
+        role: db::UserRole,
+        name: Option<String>,
+        valid_for: Duration,
+    ) -> Result<db::Invite, String> {
+        let created = Utc::now();
+        let invite = db::Invite {
+            token: Uuid::new_v4().to_string(),
+            creator: creator.to_string(),
+            created: created.timestamp(),
+            expires: (created + valid_for).timestamp(),
+            name,
+            role,
+            used_by: None,
+            revision: 0,
+        };
+        self.internal
+            .put(db, &invite.token.clone(), invite.clone())
+            .await?;
+        Ok(invite)
+    }
+
+    // Returns the invite if it can still be used to create a user.
+    pub async fn check(&self, db: &db::Backend, token: &str) -> Result<db::Invite, InviteError> {
+        match self.internal.get::<db::Invite>(db, token).await? {
+            None => Err(InviteError::NotFound),
+            Some(invite) if invite.used_by.is_some() => Err(InviteError::Used),
+            Some(invite) if invite.expires < Utc::now().timestamp() => Err(InviteError::Expired),
+            Some(invite) => Ok(invite),
+        }
+    }
+
+    // Marks the invite as used by `name`. The write is conditional on the
+    // invite's revision, so only one of two concurrent redemptions succeeds.
+    pub async fn redeem(
+        &self,
+        db: &db::Backend,
+        token: &str,
+        name: &str,
+    ) -> Result<db::Invite, InviteError> {
+        let invite = self.check(db, token).await?;
+        if let Some(expected) = &invite.name
+            && expected.to_lowercase() != name.to_lowercase()
+        {
+            return Err(InviteError::WrongName(expected.clone()));
+        }
+
+        let used = db::Invite {
+            used_by: Some(name.to_string()),
+            ..invite.clone()
+        };
+        match self.internal.put(db, token, used).await {
+            Ok(()) => Ok(invite),
+            Err(db::WriteError::Conflict) => Err(InviteError::Used),
+            Err(db::WriteError::Failed(e)) => Err(InviteError::Failed(e)),
+        }
+    }
+
+    // Hands a redeemed invite back, e.g. when the user couldn't be created.
+    pub async fn release(&self, db: &db::Backend, token: &str) -> Result<(), String> {
+        if let Some(invite) = self.internal.get::<db::Invite>(db, token).await? {
+            let invite = db::Invite {
+                used_by: None,
+                ..invite
+            };
+            self.internal.put(db, token, invite).await?;
+        }
+        Ok(())
+    }
+
+    // Invites that are neither used nor expired.
+    pub async fn outstanding(&self, db: &db::Backend) -> Result<Vec<db::Invite>, String> {
+        let now = Utc::now().timestamp();
+        Ok(self
+            .internal
+            .get_values::<db::Invite>(db)
+            .await?
+            .into_iter()
+            .filter(|invite| invite.used_by.is_none() && invite.expires >= now)
+            .collect())
+    }
+
+    pub async fn revoke(&self, db: &db::Backend, token: &str) -> Result<(), String> {
+        self.internal.delete::<db::Invite>(db, token).await
+    }
+
+    pub async fn purge_expired(&self, db: &db::Backend) -> Result<(), String> {
+        let cutoff = (Utc::now() - Duration::days(RETENTION_DAYS)).timestamp();
+        for invite in self.internal.get_values::<db::Invite>(db).await? {
+            if invite.expires < cutoff {
+                self.revoke(db, &invite.token).await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use std::{fs, io, path::Path};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::extract::{cookie::Key, PrivateCookieJar};
+use chrono::Utc;
+use cookie::{Cookie, SameSite};
+use data_encoding::BASE64;
+
+use crate::csrf;
+
+// The keys private cookies are encrypted with. New cookies always use
+// `primary`, the retired keys are only there so cookies from before a
+// rotation keep working until they are re-issued.
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub primary: Key,
+    pub retired: Vec<Key>,
+}
+
+impl From<Key> for SessionKeys {
+    fn from(primary: Key) -> Self {
+        Self {
+            primary,
+            retired: vec![],
+        }
+    }
+}
+
+impl SessionKeys {
+    pub fn generate() -> Self {
+        Key::generate().into()
+    }
+
+    // Reads a key file written by `save`: one base64 key per line, the
+    // primary one first. Files from before rotation hold a single raw key.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        if contents.len() == 64 {
+            return Key::try_from(contents.as_slice())
+                .map(Self::from)
+                .map_err(|e| invalid(e.to_string()));
+        }
+
+        let mut keys = String::from_utf8(contents)
+            .map_err(|e| invalid(e.to_string()))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let bytes = BASE64
+                    .decode(line.as_bytes())
+                    .map_err(|e| invalid(e.to_string()))?;
+                Key::try_from(bytes.as_slice()).map_err(|e| invalid(e.to_string()))
+            })
+            .collect::<io::Result<Vec<Key>>>()?;
+        if keys.is_empty() {
+            return Err(invalid("the key file is empty".to_string()));
+        }
+        let primary = keys.remove(0);
+        Ok(Self {
+            primary,
+            retired: keys,
+        })
+    }
+
+    // Only the owner can read the file, since anyone with a key can forge
+    // sessions.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let contents: String = std::iter::once(&self.primary)
+            .chain(&self.retired)
+            .map(|key| BASE64.encode(key.master()) + "\n")
+            .collect();
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(&mut options.open(path)?, contents.as_bytes())
+    }
+
+    // Makes a new primary key and retires the current one.
+    pub fn rotate(self) -> Self {
+        let mut retired = vec![self.primary];
+        retired.extend(self.retired);
+        Self {
+            primary: Key::generate(),
+            retired,
+        }
+    }
+}
+
+// How each private cookie is set, so one that was encrypted with a retired
+// key can be sent back the same way under the primary key.
+async fn reissued(state: &crate::AppState, cookie: &Cookie<'static>) -> Option<Cookie<'static>> {
+    let (path, same_site) = match cookie.name() {
+        "token" => ("/", SameSite::Lax),
+        "oidc" => ("/api/oidc", SameSite::Lax),
+        csrf::INVITE_COOKIE => ("/api/invite", SameSite::Strict),
+        csrf::RESET_COOKIE => ("/api/reset", SameSite::Strict),
+        _ => return None,
+    };
+    let mut reissued = Cookie::build(cookie.name().to_string(), cookie.value().to_string())
+        .path(path)
+        .same_site(same_site)
+        .finish();
+    // Session cookies last as long as their session.
+    if cookie.name() == "token" {
+        let db = state.db.read().await;
+        let sessions = state.sessions.read().await;
+        if let Ok(Some(session)) = sessions.get(&db, cookie.value()).await {
+            let left = session.expires - Utc::now().timestamp();
+            reissued.set_max_age(cookie::time::Duration::seconds(left));
+        }
+    }
+    Some(reissued)
+}
+
+// Re-encrypts cookies from before a key rotation under the primary key. The
+// request goes on as if the browser had sent the new ones, and the response
+// hands them to the browser.
+pub async fn rotation_layer_fn<B>(
+    State(state): State<crate::AppState>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if state.retired_keys.is_empty() {
+        return next.run(req).await;
+    }
+
+    let primary = PrivateCookieJar::new(state.key.clone());
+    let retired: Vec<PrivateCookieJar> = state
+        .retired_keys
+        .iter()
+        .map(|key| PrivateCookieJar::new(key.clone()))
+        .collect();
+
+    let mut cookies = vec![];
+    let mut reissue = vec![];
+    let mut rewritten = false;
+    let sent = req
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| Cookie::parse_encoded(cookie.trim().to_string()).ok())
+        .collect::<Vec<_>>();
+    for cookie in sent {
+        let plain = match primary.decrypt(cookie.clone()) {
+            Some(_) => None,
+            None => retired.iter().find_map(|jar| jar.decrypt(cookie.clone())),
+        };
+        let Some(plain) = plain else {
+            cookies.push(cookie.encoded().to_string());
+            continue;
+        };
+        let mut jar = cookie::CookieJar::new();
+        jar.private_mut(&state.key).add(plain.clone());
+        let Some(encrypted) = jar
+            .get(plain.name())
+            .map(|cookie| cookie.value().to_string())
+        else {
+            continue;
+        };
+        rewritten = true;
+        cookies.push(
+            Cookie::new(plain.name().to_string(), encrypted.clone())
+                .encoded()
+                .to_string(),
+        );
+        if let Some(mut cookie) = reissued(&state, &plain).await {
+            cookie.set_value(encrypted);
+            reissue.push(cookie);
+        }
+    }
+
+    if rewritten {
+        req.headers_mut().remove(header::COOKIE);
+        if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+            req.headers_mut().insert(header::COOKIE, value);
+        }
+    }
+    let mut res = next.run(req).await;
+
+    // Unless the handler set the cookie itself, e.g. when logging in again.
+    let set = res
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| Cookie::parse_encoded(value.to_string()).ok())
+        .map(|cookie| cookie.name().to_string())
+        .collect::<Vec<_>>();
+    for cookie in reissue {
+        if !set.iter().any(|name| name == cookie.name())
+            && let Ok(value) = HeaderValue::from_str(&cookie.encoded().to_string())
+        {
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum_extra::extract::cookie::Key;
+
+    use super::SessionKeys;
+    use crate::db::UserRole;
+    use crate::test_harness::TestApp;
+
+    #[test]
+    fn key_files_keep_retired_keys() {
+        let dir = std::env::temp_dir().join(format!("notes-keys-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("session_key");
+        // As written before keys could be rotated.
+        let legacy = Key::generate();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, legacy.master()).unwrap();
+
+        let keys = SessionKeys::load(&path).unwrap().rotate();
+        keys.save(&path).unwrap();
+        let loaded = SessionKeys::load(&path).unwrap();
+        assert_eq!(loaded.primary.master(), keys.primary.master());
+        assert_eq!(loaded.retired.len(), 1);
+        assert_eq!(loaded.retired[0].master(), legacy.master());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn cookies_from_retired_keys_are_reissued() {
+        let app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        let old_cookie = app.cookie("token").unwrap();
+
+        let rotated = SessionKeys::from(app.state.key.clone()).rotate();
+        let mut restarted = TestApp::from_state(crate::AppState {
+            key: rotated.primary.clone(),
+            retired_keys: Arc::new(rotated.retired),
+            ..app.state.clone()
+        });
+        app.share_cookies(&mut restarted);
+        assert_eq!(restarted.get("/api/role").await.status, StatusCode::OK);
+        let new_cookie = restarted.cookie("token").unwrap();
+        assert_ne!(new_cookie, old_cookie);
+
+        // Once the old key is dropped, only the re-issued cookie works.
+        let dropped = crate::AppState {
+            key: rotated.primary,
+            retired_keys: Arc::new(vec![]),
+            ..app.state.clone()
+        };
+        let mut reissued = TestApp::from_state(dropped.clone());
+        restarted.share_cookies(&mut reissued);
+        assert_eq!(reissued.get("/api/role").await.status, StatusCode::OK);
+        let mut stale = TestApp::from_state(dropped);
+        app.share_cookies(&mut stale);
+        assert_eq!(
+            stale.get("/api/role").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,564 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing, Extension, Json, Router,
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    csrf, db,
+    login::{self, Client},
+    password,
+    permissions::{self, Permission},
+};
+
+// How long a device stays a kiosk before an admin has to set it up again.
+const LIFETIME_DAYS: i64 = 30;
+
+#[derive(Deserialize)]
+pub struct StartIn {
+    device: String,
+}
+
+#[derive(Deserialize)]
+pub struct SwitchIn {
+    name: String,
+    pin: String,
+}
+
+#[derive(Deserialize)]
+pub struct PinIn {
+    // Clears the PIN when missing.
+    pin: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct KioskOut {
+    device: String,
+    // Whoever entered their PIN, while they haven't timed out.
+    user: Option<String>,
+    expires: Option<i64>,
+}
+
+// A kiosk is a long lived session on a shared device, like the tablet at the
+// front desk. On its own it can only view students. Senseis switch to
+// themselves with their PIN, and stay the session's user until they have been
+// idle for `AppState::kiosk_idle_timeout`.
+pub fn routes() -> Router<crate::AppState> {
+    Router::new()
+        .route("/", routing::get(status_get))
+        .route("/switch", routing::post(switch_post))
+        .route("/release", routing::post(release_post))
+        .route("/pin", routing::post(pin_post))
+        .merge(
+            Router::new()
+                .route("/start", routing::post(start_post))
+                .route_layer(middleware::from_fn_with_state(
+                    Permission::ManageUsers,
+                    permissions::require_layer,
+                )),
+        )
+}
+
+// What a kiosk session can do without anyone's PIN.
+pub fn device_user(kiosk: &db::Kiosk) -> db::User {
+    db::User {
+        name: kiosk.device.clone(),
+        primary_key: String::new(),
+        hash: None,
+        password_hash: String::new(),
+        role: db::UserRole::Observer,
+        revision: 0,
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: 0,
+        recovery_codes: vec![],
+        email: None,
+        pin_hash: None,
+    }
+}
+
+fn holder(kiosk: &db::Kiosk, now: i64) -> Option<&str> {
+    kiosk
+        .holder
+        .as_deref()
+        .filter(|_| kiosk.holder_expires >= now)
+}
+
+// A PIN only stands in for a password at the device, so nothing that
+// manages an account can be done from a kiosk.
+pub fn allows(path: &str) -> bool {
+    !(path.starts_with("/api/2fa")
+        || path.starts_with("/api/tokens")
+        || ["/api/change_pass", "/api/kiosk/start", "/api/kiosk/pin"].contains(&path))
+}
+
+// Makes the PIN holder the session's user, unless they timed out. Only
+// `active` requests, i.e. writes, keep them from timing out, since the grid
+// keeps polling for students on its own.
+pub async fn resolve(
+    state: &crate::AppState,
+    mut session: db::Session,
+    active: bool,
+) -> Result<db::Session, String> {
+    let now = Utc::now().timestamp();
+    let Some(mut kiosk) = session.kiosk.clone() else {
+        return Ok(session);
+    };
+    let Some(key) = holder(&kiosk, now) else {
+        return Ok(session);
+    };
+    let db = state.db.read().await;
+    let Some(user) = state.users.write().await.get(&db, key).await? else {
+        return Ok(session);
+    };
+
+    if active {
+        kiosk.holder_expires = now + state.kiosk_idle_timeout.num_seconds();
+        let sessions = state.sessions.read().await;
+        sessions
+            .insert(
+                &db,
+                db::Session {
+                    kiosk: Some(kiosk.clone()),
+                    ..session.stored()
+                },
+            )
+            .await?;
+        session.kiosk = Some(kiosk);
+    }
+    session.scopes = Some(holder_scopes(&user.role));
+    session.user = user;
+    Ok(session)
+}
+
+// A PIN is no password and never asks for a second factor, so whoever holds
+// the kiosk gets what their role allows apart from managing users.
+fn holder_scopes(role: &db::UserRole) -> Vec<Permission> {
+    role.permissions()
+        .iter()
+        .copied()
+        .filter(|permission| *permission != Permission::ManageUsers)
+        .collect()
+}
+
+fn kiosk_out(kiosk: &db::Kiosk, user: Option<String>) -> KioskOut {
+    KioskOut {
+        device: kiosk.device.clone(),
+        expires: user.as_ref().map(|_| kiosk.holder_expires),
+        user,
+    }
+}
+
+fn not_a_kiosk() -> Response {
+    (StatusCode::NOT_FOUND, "This session isn't a kiosk").into_response()
+}
+
+// `null` for ordinary sessions.
+async fn status_get(Extension(session): Extension<db::Session>) -> Json<Option<KioskOut>> {
+    let now = Utc::now().timestamp();
+    Json(session.kiosk.as_ref().map(|kiosk| {
+        let user = holder(kiosk, now).map(|_| session.user.name.clone());
+        kiosk_out(kiosk, user)
+    }))
+}
+
+// Turns the admin's session on this device into a kiosk.
+async fn start_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    cookies: PrivateCookieJar,
+    Json(payload): Json<StartIn>,
+) -> Result<Response, String> {
+    let device = payload.device.trim();
+    if device.is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, "Kiosks need a name").into_response());
+    }
+
+    let db = state.db.read().await;
+    let sessions = state.sessions.read().await;
+    sessions.remove(&db, &session.token).await?;
+
+    let kiosk = db::Kiosk {
+        device: device.to_string(),
+        started_by: session.user.name.clone(),
+        holder: None,
+        holder_expires: 0,
+    };
+    let client = Client::from(connect_info, &headers);
+    let lifetime = Duration::days(LIFETIME_DAYS);
+    let token = Uuid::new_v4().to_string();
+    let csrf_token = csrf::generate();
+    let created = Utc::now();
+    sessions
+        .insert(
+            &db,
+            db::Session {
+                token: token.clone(),
+                expires: (created + lifetime).timestamp(),
+                user: String::new(),
+                id: Uuid::new_v4().to_string(),
+                created: created.timestamp(),
+                ip: client.ip.map(|ip| ip.to_string()),
+                user_agent: client.user_agent,
+                stage: db::SessionStage::Active,
+                csrf_token: csrf_token.clone(),
+                credential: db::Credential::Cookie,
+                scopes: None,
+                kiosk: Some(kiosk.clone()),
+            },
+        )
+        .await?;
+
+    let (cookies, csrf_cookies) = login::session_cookies(&cookies, &token, &csrf_token, lifetime);
+    Ok((cookies, csrf_cookies, Json(kiosk_out(&kiosk, None))).into_response())
+}
+
+async fn switch_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    Json(payload): Json<SwitchIn>,
+) -> Result<Response, String> {
+    let Some(kiosk) = session.kiosk.clone() else {
+        return Ok(not_a_kiosk());
+    };
+    let db = state.db.read().await;
+    let throttle = state.throttle.read().await;
+    // Kept apart from password failures, so guessing PINs can't lock anyone
+    // out of logging in, and not counted per address since every attempt
+    // comes from the same device.
+    let subject = format!("kiosk:{}", payload.name);
+    if let Some(wait) = throttle.check(&db, &subject, None).await? {
+        return Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait.to_string())],
+            format!("Too many wrong PINs, try again in {} seconds", wait),
+        )
+            .into_response());
+    }
+
+    let user = state
+        .users
+        .write()
+        .await
+        .get(&db, &payload.name.to_lowercase())
+        .await?;
+    let Some(user) = user.filter(|user| {
+        user.pin_hash
+            .as_ref()
+            .is_some_and(|hash| password::matches(hash, &payload.pin))
+    }) else {
+        throttle.record_failure(&db, &subject, None).await?;
+        return Ok((StatusCode::UNAUTHORIZED, "Wrong name or PIN").into_response());
+    };
+    throttle.record_success(&db, &subject).await?;
+
+    let sessions = state.sessions.read().await;
+    let Some(stored) = sessions.get(&db, &session.token).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let kiosk = db::Kiosk {
+        holder: Some(user.primary_key.clone()),
+        holder_expires: Utc::now().timestamp() + state.kiosk_idle_timeout.num_seconds(),
+        ..kiosk
+    };
+    sessions
+        .insert(
+            &db,
+            db::Session {
+                kiosk: Some(kiosk.clone()),
+                ..stored
+            },
+        )
+        .await?;
+
+    Ok(Json(kiosk_out(&kiosk, Some(user.name))).into_response())
+}
+
+// Lets a sensei hand the device back before they time out.
+async fn release_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+) -> Result<Response, String> {
+    let Some(kiosk) = session.kiosk.clone() else {
+        return Ok(not_a_kiosk());
+    };
+    let db = state.db.read().await;
+    let sessions = state.sessions.read().await;
+    let Some(stored) = sessions.get(&db, &session.token).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let kiosk = db::Kiosk {
+        holder: None,
+        holder_expires: 0,
+        ..kiosk
+    };
+    sessions
+        .insert(
+            &db,
+            db::Session {
+                kiosk: Some(kiosk.clone()),
+                ..stored
+            },
+        )
+        .await?;
+
+    Ok(Json(kiosk_out(&kiosk, None)).into_response())
+}
+
+// Sets the caller's own PIN, from a normal session.
+async fn pin_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    Json(payload): Json<PinIn>,
+) -> Result<Response, String> {
+    let pin_hash = match payload.pin {
+        Some(pin) if !(4..=8).contains(&pin.len()) || !pin.chars().all(|c| c.is_ascii_digit()) => {
+            return Ok((StatusCode::BAD_REQUEST, "PINs are 4 to 8 digits").into_response());
+        }
+        Some(pin) => Some(password::hash(&pin)?),
+        None => None,
+    };
+
+    let db = state.db.read().await;
+    let mut users = state.users.write().await;
+    let Some(user) = users.get(&db, &session.user.primary_key).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    users
+        .diff_update(&db, &user.primary_key, &user, |u| {
+            u.pin_hash = pin_hash.clone()
+        })
+        .await?;
+
+    Ok(StatusCode::OK.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use chrono::Duration;
+    use serde_json::json;
+
+    use crate::config::Config;
+    use crate::db::UserRole;
+    use crate::test_harness::{student, TestApp};
+
+    async fn set_pin(app: &TestApp, name: &str, pass: &str, pin: &str) {
+        let mut own = TestApp::from_state(app.state.clone());
+        own.login(name, pass).await;
+        let res = own
+            .send_json(Method::POST, "/api/kiosk/pin", &json!({ "pin": pin }))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    // An admin sets up the kiosk, and two senseis have PINs.
+    async fn kiosk(config: Config) -> TestApp {
+        let mut app = TestApp::with_config(config).await;
+        app.create_user("Admin", "password", UserRole::Admin).await;
+        app.create_user("Alice", "password", UserRole::Standard)
+            .await;
+        app.create_user("Bob", "password", UserRole::Standard).await;
+        set_pin(&app, "Alice", "password", "1234").await;
+        set_pin(&app, "Bob", "password", "5678").await;
+        app.login("Admin", "password").await;
+        app.put_student(student("1", "Ada Lovelace")).await;
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/kiosk/start",
+                &json!({ "device": "Front desk" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        app
+    }
+
+    async fn add_note(app: &mut TestApp, note: &str) -> StatusCode {
+        app.send_json(
+            Method::PUT,
+            "/api/students/1/notes",
+            &json!({ "note": note }),
+        )
+        .await
+        .status
+    }
+
+    #[tokio::test]
+    async fn notes_are_written_by_the_pin_holder() {
+        let mut app = kiosk(Config::default()).await;
+        // Nobody has entered a PIN, so the admin's login doesn't carry over.
+        assert_eq!(app.get("/api/students").await.status, StatusCode::OK);
+        assert_eq!(add_note(&mut app, "Anonymous").await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            app.get("/api/admin/users").await.status,
+            StatusCode::FORBIDDEN
+        );
+
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/kiosk/switch",
+                &json!({ "name": "alice", "pin": "5678" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/kiosk/switch",
+                &json!({ "name": "alice", "pin": "1234" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(add_note(&mut app, "From Alice").await, StatusCode::OK);
+
+        app.send_json(
+            Method::POST,
+            "/api/kiosk/switch",
+            &json!({ "name": "Bob", "pin": "5678" }),
+        )
+        .await;
+        let status = app.get("/api/kiosk").await.json::<serde_json::Value>();
+        assert_eq!(status["device"], "Front desk");
+        assert_eq!(status["user"], "Bob");
+        assert_eq!(add_note(&mut app, "From Bob").await, StatusCode::OK);
+
+        let student = app.get_student("1").await.unwrap();
+        let authors: Vec<&str> = student
+            .notes_in("notes")
+            .iter()
+            .map(|n| n.user.as_str())
+            .collect();
+        assert_eq!(authors, ["Alice", "Bob"]);
+
+        // Bob can't manage his account from the kiosk.
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/change_pass",
+                &json!({ "new_pass": "taken over" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        app.send_json(Method::POST, "/api/kiosk/release", &json!({}))
+            .await;
+        assert_eq!(add_note(&mut app, "Anonymous").await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn admin_pin_holders_cant_manage_users() {
+        let mut app = kiosk(Config::default()).await;
+        set_pin(&app, "Admin", "password", "4321").await;
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/kiosk/switch",
+                &json!({ "name": "Admin", "pin": "4321" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(add_note(&mut app, "From Admin").await, StatusCode::OK);
+
+        assert_eq!(
+            app.get("/api/admin/users").await.status,
+            StatusCode::FORBIDDEN
+        );
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/gen_token",
+                &json!({ "role": "Admin" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/kiosk/start",
+                &json!({ "device": "Back office" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn pin_holders_time_out() {
+        let mut app = kiosk(Config {
+            kiosk_idle_timeout: Duration::seconds(-1),
+            ..Config::default()
+        })
+        .await;
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/kiosk/switch",
+                &json!({ "name": "Alice", "pin": "1234" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        assert_eq!(add_note(&mut app, "Too late").await, StatusCode::FORBIDDEN);
+        assert!(app.get("/api/kiosk").await.json::<serde_json::Value>()["user"].is_null());
+    }
+
+    #[tokio::test]
+    async fn wrong_pins_are_throttled() {
+        let mut app = kiosk(Config::default()).await;
+        for _ in 0..5 {
+            app.send_json(
+                Method::POST,
+                "/api/kiosk/switch",
+                &json!({ "name": "Alice", "pin": "0000" }),
+            )
+            .await;
+        }
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/kiosk/switch",
+                &json!({ "name": "Alice", "pin": "1234" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+
+        // Her password still works everywhere else.
+        let mut other = TestApp::from_state(app.state.clone());
+        assert_eq!(
+            other.login("Alice", "password").await.status,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn pins_must_be_digits() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        for pin in ["12", "abcd", "123456789"] {
+            let res = app
+                .send_json(Method::POST, "/api/kiosk/pin", &json!({ "pin": pin }))
+                .await;
+            assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        }
+        // Only admins set up kiosks.
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/kiosk/start",
+                &json!({ "device": "Front desk" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+    }
+}
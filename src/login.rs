@@ -1,22 +1,22 @@
-use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
-};
+use std::net::{IpAddr, SocketAddr};
 
 use axum::{
-    extract::State,
-    http::{Request, StatusCode},
+    extract::{ConnectInfo, OriginalUri, Query, State},
+    http::{header, HeaderMap, Request, StatusCode},
     middleware::Next,
     response::{IntoResponse, Redirect, Response},
     Extension, Json,
 };
-use axum_extra::extract::{Form, PrivateCookieJar};
-use chrono::{Duration, Local, Utc};
+use axum_extra::extract::{CookieJar, Form, PrivateCookieJar};
+use chrono::{Duration, Utc};
 use cookie::{Cookie, SameSite};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::db;
+use crate::{
+    csrf, db, invite_store::InviteError, kiosk, password, permissions::Permission,
+    session_store::SessionStore,
+};
 
 #[derive(Deserialize)]
 pub struct LoginData {
@@ -29,56 +29,153 @@ pub struct InstallUserData {
     name: String,
     pass: String,
     token: String,
+    #[serde(default)]
+    csrf: String,
+}
+
+// Where a request came from, recorded on new sessions so admins can tell them
+// apart.
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl Client {
+    pub fn from(connect_info: Option<ConnectInfo<SocketAddr>>, headers: &HeaderMap) -> Self {
+        Self {
+            ip: connect_info.map(|ConnectInfo(addr)| addr.ip()),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|agent| agent.to_str().ok())
+                .map(|agent| agent.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InviteQuery {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct InviteOut {
+    name: Option<String>,
+    role: db::UserRole,
+    expires: i64,
 }
 
 pub async fn user_role_get(Extension(session): Extension<db::Session>) -> Json<db::UserRole> {
     Json(session.user.role)
 }
 
+pub async fn permissions_get(Extension(session): Extension<db::Session>) -> Json<Vec<Permission>> {
+    Json(session.permissions())
+}
+
+// Also hands out the token the invite form has to be posted with, in the
+// `X-CSRF-Token` header.
+pub async fn invite_get(
+    State(state): State<crate::AppState>,
+    cookies: PrivateCookieJar,
+    Query(query): Query<InviteQuery>,
+) -> impl IntoResponse {
+    let db = state.db.read().await;
+    let invites = state.invites.read().await;
+
+    let (cookies, csrf_token) = csrf::form_token(cookies, csrf::INVITE_COOKIE, "/api/invite");
+    let parts = (cookies, [(csrf::HEADER, csrf_token)]);
+
+    match invites.check(&db, &query.token).await {
+        Ok(invite) => (
+            parts,
+            Json(InviteOut {
+                name: invite.name,
+                role: invite.role,
+                expires: invite.expires,
+            }),
+        )
+            .into_response(),
+        Err(InviteError::Failed(e)) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, parts, e).into_response()
+        }
+        Err(e) => (StatusCode::UNAUTHORIZED, parts, e.to_string()).into_response(),
+    }
+}
+
 pub async fn install_user_post(
     State(state): State<crate::AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     cookies: PrivateCookieJar,
     Form(form): Form<InstallUserData>,
 ) -> impl IntoResponse {
-    let mut invites = state.invites.write().await;
+    if !csrf::form_matches(&cookies, csrf::INVITE_COOKIE, &form.csrf) {
+        return (StatusCode::FORBIDDEN, "Missing or invalid CSRF token").into_response();
+    }
+
     let db = state.db.read().await;
-    let role = invites.remove(&form.token);
+    let invites = state.invites.read().await;
     let name = form.name.clone();
 
-    if role.is_none() {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
+    let role = match invites.redeem(&db, &form.token, &name).await {
+        Ok(invite) => invite.role,
+        Err(InviteError::Failed(e)) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+        Err(e) => return (StatusCode::UNAUTHORIZED, e.to_string()).into_response(),
+    };
 
-    let mut hasher = DefaultHasher::new();
-    form.pass.hash(&mut hasher);
+    let password_hash = match password::hash(&form.pass) {
+        Ok(hash) => hash,
+        Err(e) => {
+            if let Err(e) = invites.release(&db, &form.token).await {
+                println!("{}", e);
+            }
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+    };
     {
         let mut users = state.users.write().await;
         let new_user = db::User {
             name: name.clone(),
             primary_key: name.to_lowercase(),
-            hash: hasher.finish(),
-            role: role.clone().unwrap(),
+            hash: None,
+            password_hash,
+            role: role.clone(),
+            revision: 0,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: 0,
+            recovery_codes: vec![],
+            email: None,
+            pin_hash: None,
         };
-        if let Err(e) = users.put(&db, &name.to_lowercase(), new_user).await {
-            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        if let Err(e) = users.create(&db, &name.to_lowercase(), new_user).await {
+            // The invite wasn't used up, so let it be tried again with another name.
+            if let Err(e) = invites.release(&db, &form.token).await {
+                println!("{}", e);
+            }
+            return match e {
+                db::WriteError::Conflict => {
+                    (StatusCode::CONFLICT, "A user with that name already exists").into_response()
+                }
+                e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+            };
         }
     }
 
-    if role.unwrap() == db::UserRole::Admin {
-        return (
-            login(
-                &state,
-                &cookies,
-                &LoginData {
-                    name: name.clone(),
-                    pass: form.pass.clone(),
-                },
-            )
-            .await
-            .1,
-            Redirect::to("/"),
+    if role == db::UserRole::Admin {
+        let (_, cookies, csrf_cookies) = login(
+            &state,
+            &cookies,
+            &LoginData {
+                name: name.clone(),
+                pass: form.pass.clone(),
+            },
+            &Client::from(connect_info, &headers),
         )
-            .into_response();
+        .await;
+        return (cookies, csrf_cookies, Redirect::to("/")).into_response();
     }
 
     Redirect::to("/").into_response()
@@ -86,37 +183,154 @@ pub async fn install_user_post(
 
 pub async fn login_post(
     State(state): State<crate::AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     cookies: PrivateCookieJar,
     Json(payload): Json<LoginData>,
 ) -> impl IntoResponse {
-    login(&state, &cookies, &payload).await.into_response()
+    let client = Client::from(connect_info, &headers);
+    let ip = client.ip;
+    {
+        let db = state.db.read().await;
+        let throttle = state.throttle.read().await;
+        match throttle.check(&db, &payload.name, ip).await {
+            Ok(None) => {}
+            Ok(Some(wait)) => {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, wait.to_string())],
+                    format!("Too many failed logins, try again in {} seconds", wait),
+                )
+                    .into_response();
+            }
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        }
+    }
+
+    let (status, cookies, csrf_cookies) = login(&state, &cookies, &payload, &client).await;
+
+    let db = state.db.read().await;
+    let throttle = state.throttle.read().await;
+    // A right password only clears earlier failures once the second factor,
+    // if any, was right too.
+    let recorded = match status {
+        StatusCode::OK => throttle.record_success(&db, &payload.name).await,
+        StatusCode::UNAUTHORIZED => throttle.record_failure(&db, &payload.name, ip).await,
+        _ => Ok(()),
+    };
+    if let Err(e) = recorded {
+        println!("{}", e);
+    }
+
+    (status, cookies, csrf_cookies).into_response()
 }
 
 pub async fn logout_post(
     State(state): State<crate::AppState>,
     cookies: PrivateCookieJar,
+    csrf_cookies: CookieJar,
 ) -> impl IntoResponse {
-    let mut sessions = state.sessions.write().await;
+    let db = state.db.read().await;
+    let sessions = state.sessions.read().await;
     if let Some(token) = cookies.get("token") {
-        sessions.remove(token.value());
-        (StatusCode::OK, cookies.remove(token)).into_response()
+        if let Err(e) = sessions.remove(&db, token.value()).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+        (
+            StatusCode::OK,
+            cookies.remove(token),
+            csrf_cookies.remove(csrf::removal_cookie()),
+        )
+            .into_response()
     } else {
         StatusCode::OK.into_response()
     }
 }
 
+fn request_path<B>(req: &Request<B>) -> String {
+    req.extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.path().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string())
+}
+
+fn bearer_token<B>(req: &Request<B>) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+// Routes that manage the account itself, which need the user to have logged
+// in rather than hold an API token.
+fn is_account_path(path: &str) -> bool {
+    ["/api/2fa", "/api/tokens", "/api/kiosk"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+        || ["/api/change_pass", "/api/logout"].contains(&path)
+}
+
 pub async fn auth_layer_fn<B>(
     State(state): State<crate::AppState>,
     cookies: PrivateCookieJar,
     mut req: Request<B>,
     next: Next<B>,
 ) -> Response {
-    if let Some(token) = cookies.get("token") {
-        match get_session(token.value(), &state).await {
+    if let Some(token) = bearer_token(&req) {
+        return match get_token_session(&token, &state).await {
+            Ok(Some(_)) if is_account_path(&request_path(&req)) => (
+                StatusCode::FORBIDDEN,
+                "API tokens can't be used to manage your account",
+            )
+                .into_response(),
             Ok(Some(session)) => {
                 req.extensions_mut().insert(session);
                 next.run(req).await
             }
+            Ok(None) => StatusCode::UNAUTHORIZED.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+    }
+
+    if let Some(token) = cookies.get("token") {
+        match get_session(token.value(), &state).await {
+            Ok(Some(session)) => match session.stage {
+                db::SessionStage::Active if session.kiosk.is_some() => {
+                    if !kiosk::allows(&request_path(&req)) {
+                        return (StatusCode::FORBIDDEN, "This can't be done on a kiosk")
+                            .into_response();
+                    }
+                    let active = !csrf::is_safe(req.method());
+                    match kiosk::resolve(&state, session, active).await {
+                        Ok(session) => {
+                            req.extensions_mut().insert(session);
+                            next.run(req).await
+                        }
+                        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                    }
+                }
+                db::SessionStage::Active => {
+                    req.extensions_mut().insert(session);
+                    next.run(req).await
+                }
+                db::SessionStage::AwaitingTotp => StatusCode::UNAUTHORIZED.into_response(),
+                db::SessionStage::MustEnrollTotp => {
+                    let path = request_path(&req);
+                    if path.starts_with("/api/2fa")
+                        || ["/api/role", "/api/logout"].contains(&path.as_str())
+                    {
+                        req.extensions_mut().insert(session);
+                        next.run(req).await
+                    } else {
+                        (
+                            StatusCode::FORBIDDEN,
+                            "Two-factor authentication has to be set up first",
+                        )
+                            .into_response()
+                    }
+                }
+            },
             Ok(None) => (StatusCode::UNAUTHORIZED, cookies.remove(token)).into_response(),
             Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
         }
@@ -129,56 +343,652 @@ async fn login(
     state: &crate::AppState,
     cookies: &PrivateCookieJar,
     data: &LoginData,
-) -> (StatusCode, PrivateCookieJar) {
+    client: &Client,
+) -> (StatusCode, PrivateCookieJar, CookieJar) {
     let db = state.db.read().await;
-    let mut users = state.users.write().await;
-    let mut sessions = state.sessions.write().await;
-
-    let mut hasher = DefaultHasher::new();
-    data.pass.hash(&mut hasher);
-
-    let user = users.get(&db, &data.name.to_lowercase()).await;
-    if let Ok(Some(user)) = user && user.hash == hasher.finish() {
-        let token = Uuid::new_v4().to_string();
-        let expires = Utc::now()
-            .checked_add_signed(Duration::hours(4))
-            .unwrap()
-            .timestamp();
-        sessions.insert(
-            token.clone(),
-            db::Session {
-                token: token.clone(),
-                expires,
-                user: user.clone(),
-            },
-        );
-        let cookie = Cookie::build("token", token)
-            .max_age(cookie::time::Duration::hours(4))
-            .same_site(SameSite::Lax)
-            .path("/")
-            .finish();
-        let mut cookies = cookies.clone();
-        if let Some(cookie) = cookies.get("token") {
-            cookies = cookies.remove(cookie);
-        }
-        cookies = cookies.add(cookie);
-        (StatusCode::OK, cookies)
+    // Every request takes the users lock to load its session, so it is only
+    // held for the lookup, not while the password is checked.
+    let user = state
+        .users
+        .write()
+        .await
+        .get(&db, &data.name.to_lowercase())
+        .await;
+    let Ok(Some(mut user)) = user else {
+        return (StatusCode::UNAUTHORIZED, cookies.clone(), CookieJar::new());
+    };
+    // Argon2 is slow on purpose, so it runs on a blocking thread.
+    let verified = {
+        let (user, pass) = (user.clone(), data.pass.clone());
+        tokio::task::spawn_blocking(move || password::verify(&user, &pass))
+            .await
+            .unwrap_or(false)
+    };
+    if verified {
+        if password::needs_upgrade(&user) {
+            match upgrade_hash(state, &db, &user, &data.pass).await {
+                Ok(upgraded) => user = upgraded,
+                Err(e) => println!("{}", e),
+            }
+        }
+
+        let sessions = state.sessions.read().await;
+        let Ok(settings) = state.settings.read().await.get(&db).await else {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                cookies.clone(),
+                CookieJar::new(),
+            );
+        };
+        let stage = session_stage(&user, &settings);
+        // The client has to send a TOTP code to `/api/login/totp` next.
+        let status = if stage == db::SessionStage::AwaitingTotp {
+            StatusCode::ACCEPTED
+        } else {
+            StatusCode::OK
+        };
+
+        match start_session(&db, &sessions, cookies, user, client, stage).await {
+            Ok((cookies, csrf_cookies)) => (status, cookies, csrf_cookies),
+            Err(e) => {
+                println!("{}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    cookies.clone(),
+                    CookieJar::new(),
+                )
+            }
+        }
+    } else {
+        (StatusCode::UNAUTHORIZED, cookies.clone(), CookieJar::new())
+    }
+}
+
+// How far a user is logged in once their password, or their identity
+// provider, checked out.
+pub fn session_stage(user: &db::User, settings: &db::Settings) -> db::SessionStage {
+    if user.totp_enabled {
+        db::SessionStage::AwaitingTotp
+    } else if settings.require_admin_totp && user.role == db::UserRole::Admin {
+        db::SessionStage::MustEnrollTotp
     } else {
-        (StatusCode::UNAUTHORIZED, cookies.clone())
+        db::SessionStage::Active
     }
 }
 
+// Returns the private jar with the session token, and a plain one with the
+// CSRF token the frontend has to send back.
+pub async fn start_session(
+    db: &db::Backend,
+    sessions: &SessionStore,
+    cookies: &PrivateCookieJar,
+    user: db::User,
+    client: &Client,
+    stage: db::SessionStage,
+) -> Result<(PrivateCookieJar, CookieJar), String> {
+    // Half logged in sessions only need to last long enough to type a code.
+    let lifetime = if stage == db::SessionStage::AwaitingTotp {
+        Duration::minutes(5)
+    } else {
+        Duration::hours(4)
+    };
+    let token = Uuid::new_v4().to_string();
+    let csrf_token = csrf::generate();
+    let created = Utc::now();
+    let session = db::StoredSession {
+        token: token.clone(),
+        expires: (created + lifetime).timestamp(),
+        user: user.primary_key,
+        id: Uuid::new_v4().to_string(),
+        created: created.timestamp(),
+        ip: client.ip.map(|ip| ip.to_string()),
+        user_agent: client.user_agent.clone(),
+        stage,
+        csrf_token: csrf_token.clone(),
+        credential: db::Credential::Cookie,
+        scopes: None,
+        kiosk: None,
+    };
+    sessions.insert(db, session).await?;
+
+    Ok(session_cookies(cookies, &token, &csrf_token, lifetime))
+}
+
+// Replaces any session token the browser had with `token`.
+pub fn session_cookies(
+    cookies: &PrivateCookieJar,
+    token: &str,
+    csrf_token: &str,
+    lifetime: Duration,
+) -> (PrivateCookieJar, CookieJar) {
+    let cookie = Cookie::build("token", token.to_string())
+        .max_age(cookie::time::Duration::seconds(lifetime.num_seconds()))
+        .same_site(SameSite::Lax)
+        .path("/")
+        .finish();
+    let mut cookies = cookies.clone();
+    if let Some(cookie) = cookies.get("token") {
+        cookies = cookies.remove(cookie);
+    }
+    (
+        cookies.add(cookie),
+        CookieJar::new().add(csrf::cookie(csrf_token)),
+    )
+}
+
+// Replaces a legacy `DefaultHasher` hash with an Argon2id one, now that we
+// know the plain password.
+async fn upgrade_hash(
+    state: &crate::AppState,
+    db: &db::Backend,
+    user: &db::User,
+    pass: &str,
+) -> Result<db::User, String> {
+    let pass = pass.to_string();
+    let password_hash = tokio::task::spawn_blocking(move || password::hash(&pass))
+        .await
+        .map_err(|e| e.to_string())??;
+    state
+        .users
+        .write()
+        .await
+        .diff_update(db, &user.primary_key, user, |u| {
+            u.hash = None;
+            u.password_hash = password_hash.clone();
+        })
+        .await?;
+    Ok(db::User {
+        hash: None,
+        password_hash,
+        ..user.clone()
+    })
+}
+
+// Looks the session's user up again, so a session ends when its user is
+// deleted, follows role changes, and has to enroll in two-factor
+// authentication as soon as it becomes required.
 async fn get_session(token: &str, state: &crate::AppState) -> Result<Option<db::Session>, String> {
-    let mut sessions = state.sessions.write().await;
+    let db = state.db.read().await;
+    let sessions = state.sessions.read().await;
+    let Some(stored) = sessions.get(&db, token).await? else {
+        return Ok(None);
+    };
+    if let Some(kiosk) = &stored.kiosk {
+        let user = kiosk::device_user(kiosk);
+        return Ok(Some(stored.with_user(user)));
+    }
+    let Some(user) = state.users.write().await.get(&db, &stored.user).await? else {
+        return Ok(None);
+    };
 
-    if let Some(session) = sessions.get(token.clone()) {
-        if session.expires >= Local::now().timestamp() {
-            Ok(Some(session.clone()))
-        } else {
-            sessions.remove(token.clone());
-            Ok(None)
+    let mut session = stored.with_user(user);
+    if session.stage == db::SessionStage::Active {
+        let settings = state.settings.read().await.get(&db).await?;
+        if session_stage(&session.user, &settings) == db::SessionStage::MustEnrollTotp {
+            session.stage = db::SessionStage::MustEnrollTotp;
         }
-    } else {
-        Ok(None)
+    }
+    Ok(Some(session))
+}
+
+// Builds the session an API token stands for. The user is looked up again so
+// a token stops working when its user is deleted, and follows role changes.
+async fn get_token_session(
+    token: &str,
+    state: &crate::AppState,
+) -> Result<Option<db::Session>, String> {
+    let db = state.db.read().await;
+    let api_tokens = state.api_tokens.read().await;
+    let Some(api_token) = api_tokens.get(&db, token).await? else {
+        return Ok(None);
+    };
+    let mut users = state.users.write().await;
+    let Some(user) = users.get(&db, &api_token.user).await? else {
+        return Ok(None);
+    };
+
+    Ok(Some(db::Session {
+        token: String::new(),
+        expires: api_token.expires.unwrap_or(i64::MAX),
+        user,
+        id: api_token.id.clone(),
+        created: api_token.created,
+        ip: None,
+        user_agent: None,
+        stage: db::SessionStage::Active,
+        csrf_token: String::new(),
+        credential: db::Credential::ApiToken {
+            id: api_token.id,
+            name: api_token.name,
+        },
+        scopes: Some(api_token.scopes),
+        kiosk: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use axum_extra::extract::cookie::Key;
+    use chrono::Local;
+
+    use crate::config::Config;
+    use crate::db::{self, Column, Database, UserRole};
+    use crate::test_harness::TestApp;
+
+    #[tokio::test]
+    async fn login_with_valid_credentials() {
+        let mut app = TestApp::new().await;
+        app.create_user("Sensei", "hunter2", UserRole::Standard)
+            .await;
+
+        assert_eq!(app.login("sensei", "hunter2").await.status, StatusCode::OK);
+        let role = app.get("/api/role").await;
+        assert_eq!(role.status, StatusCode::OK);
+        assert_eq!(role.json::<UserRole>(), UserRole::Standard);
+    }
+
+    #[tokio::test]
+    async fn login_with_wrong_password() {
+        let mut app = TestApp::new().await;
+        app.create_user("Sensei", "hunter2", UserRole::Standard)
+            .await;
+
+        assert_eq!(
+            app.login("Sensei", "hunter3").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(app.get("/api/role").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn invite_tokens_are_single_use() {
+        let mut app = TestApp::new().await;
+        let token = app.create_invite(UserRole::Standard, None).await.token;
+
+        assert_eq!(
+            app.redeem_invite(&token, "Sensei", "hunter2").await.status,
+            StatusCode::SEE_OTHER
+        );
+        let res = app.redeem_invite(&token, "Sensei", "hunter2").await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.text(), "This invite has already been used");
+    }
+
+    #[tokio::test]
+    async fn invites_cant_replace_existing_users() {
+        // Stored before records had a revision.
+        #[derive(serde::Serialize)]
+        struct OldUser {
+            name: &'static str,
+            primary_key: &'static str,
+            password_hash: String,
+            role: UserRole,
+        }
+        impl db::PrimaryKeyName for OldUser {
+            fn get_primary_key_name() -> &'static str {
+                "primary_key"
+            }
+        }
+
+        let mut app = TestApp::new().await;
+        {
+            let db = app.state.db.read().await;
+            #[allow(deprecated)]
+            let users: db::BackendColumn = db.column("users");
+            let admin = OldUser {
+                name: "Admin",
+                primary_key: "admin",
+                password_hash: crate::password::hash("password").unwrap(),
+                role: UserRole::Admin,
+            };
+            users.put(&db, "admin", admin).await.unwrap();
+        }
+        let token = app.create_invite(UserRole::Standard, None).await.token;
+
+        let res = app.redeem_invite(&token, "ADMIN", "hunter2").await;
+        assert_eq!(res.status, StatusCode::CONFLICT);
+        assert_eq!(
+            app.login("Admin", "hunter2").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(app.login("Admin", "password").await.status, StatusCode::OK);
+        assert_eq!(
+            app.get("/api/role").await.json::<UserRole>(),
+            UserRole::Admin
+        );
+
+        // The invite can still be used with another name.
+        let res = app.redeem_invite(&token, "Sensei", "hunter2").await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn expired_invites_are_rejected() {
+        let mut app = TestApp::new().await;
+        let invite = app.create_invite(UserRole::Standard, None).await;
+        {
+            let db = app.state.db.read().await;
+            #[allow(deprecated)]
+            let invites: db::BackendColumn = db.column("invites");
+            let stored: db::Invite = invites.get(&db, &invite.token).await.unwrap().unwrap();
+            let invite = db::Invite {
+                expires: Local::now().timestamp() - 1,
+                ..stored
+            };
+            invites
+                .put(&db, &invite.token.clone(), invite)
+                .await
+                .unwrap();
+        }
+
+        let res = app.redeem_invite(&invite.token, "Sensei", "hunter2").await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.text(), "This invite has expired");
+        assert_eq!(
+            app.login("Sensei", "hunter2").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn named_invites_prefill_and_require_the_name() {
+        let mut app = TestApp::new().await;
+        let token = app
+            .create_invite(UserRole::Standard, Some("Sensei"))
+            .await
+            .token;
+
+        let invite = app.get(&format!("/api/invite?token={}", token)).await;
+        assert_eq!(invite.status, StatusCode::OK);
+        assert_eq!(invite.json::<serde_json::Value>()["name"], "Sensei");
+
+        let res = app.redeem_invite(&token, "Someone", "hunter2").await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.text(), "This invite is for the user Sensei");
+
+        let res = app.redeem_invite(&token, "sensei", "hunter2").await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn invite_form_needs_the_csrf_token() {
+        let mut app = TestApp::new().await;
+        let token = app.create_invite(UserRole::Standard, None).await.token;
+
+        let fields = [("name", "Sensei"), ("pass", "hunter2"), ("token", &token)];
+        let res = app.post_form("/api/invite", &fields).await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        // A token from another browser doesn't match this one's cookie.
+        let mut other = TestApp::from_state(app.state.clone());
+        let res = other.get(&format!("/api/invite?token={}", token)).await;
+        let csrf_token = res.headers[crate::csrf::HEADER].to_str().unwrap();
+        app.get(&format!("/api/invite?token={}", token)).await;
+        let res = app
+            .post_form(
+                "/api/invite",
+                &[fields[0], fields[1], fields[2], ("csrf", csrf_token)],
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        // The invite wasn't used up by the rejected attempts.
+        let res = app.redeem_invite(&token, "Sensei", "hunter2").await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn writes_need_the_csrf_token() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        let other = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        let other_token = other.cookie(crate::csrf::COOKIE).unwrap();
+
+        let res = app
+            .request(
+                Method::POST,
+                "/api/logout",
+                None,
+                Default::default(),
+                &[(crate::csrf::HEADER, &other_token)],
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert_eq!(app.get("/api/role").await.status, StatusCode::OK);
+
+        let token = app.cookie(crate::csrf::COOKIE).unwrap();
+        app.forget_cookie(crate::csrf::COOKIE);
+        let res = app
+            .request(Method::POST, "/api/logout", None, Default::default(), &[])
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert_eq!(app.get("/api/role").await.status, StatusCode::OK);
+
+        let res = app
+            .request(
+                Method::POST,
+                "/api/logout",
+                None,
+                Default::default(),
+                &[(crate::csrf::HEADER, &token)],
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(app.get("/api/role").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn logout_ends_session() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+
+        let res = app
+            .request(Method::POST, "/api/logout", None, Default::default(), &[])
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(app.get("/api/role").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn sessions_survive_restart() {
+        let path = std::env::temp_dir().join(format!("notes-{}.db", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let key = Key::generate();
+
+        let state = crate::AppState::new(
+            db::Backend::Sqlite(db::SqliteDB::new(path).unwrap()),
+            key.clone().into(),
+            Config::default(),
+        )
+        .await;
+        let mut app = TestApp::from_state(state);
+        app.create_user("Sensei", "hunter2", UserRole::Standard)
+            .await;
+        assert_eq!(app.login("Sensei", "hunter2").await.status, StatusCode::OK);
+
+        let state = crate::AppState::new(
+            db::Backend::Sqlite(db::SqliteDB::new(path).unwrap()),
+            key.into(),
+            Config::default(),
+        )
+        .await;
+        let mut restarted = TestApp::from_state(state);
+        app.share_cookies(&mut restarted);
+        assert_eq!(restarted.get("/api/role").await.status, StatusCode::OK);
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_removed() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        assert_eq!(app.get("/api/role").await.status, StatusCode::OK);
+
+        {
+            let db = app.state.db.read().await;
+            let sessions = app.state.sessions.read().await;
+            let session = sessions.get_values(&db).await.unwrap().remove(0);
+            sessions
+                .insert(
+                    &db,
+                    db::Session {
+                        expires: Local::now().timestamp() - 1,
+                        ..session
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(app.get("/api/role").await.status, StatusCode::UNAUTHORIZED);
+        let db = app.state.db.read().await;
+        let sessions = app.state.sessions.read().await;
+        assert!(sessions.get_values(&db).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sessions_follow_changes_to_their_user() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        {
+            let db = app.state.db.read().await;
+            let sessions = app.state.sessions.read().await;
+            let session = sessions.get_values(&db).await.unwrap().remove(0);
+            assert_eq!(session.user, "admin");
+        }
+
+        let state = app.state.clone();
+        let set_role = |role: UserRole| {
+            let state = state.clone();
+            async move {
+                let db = state.db.read().await;
+                let mut users = state.users.write().await;
+                let user = users.get(&db, "admin").await.unwrap().unwrap();
+                users
+                    .diff_update(&db, "admin", &user, |u| u.role = role)
+                    .await
+                    .unwrap();
+            }
+        };
+        set_role(UserRole::Standard).await;
+        assert_eq!(
+            app.get("/api/role").await.json::<UserRole>(),
+            UserRole::Standard
+        );
+        assert_eq!(
+            app.get("/api/admin/users").await.status,
+            StatusCode::FORBIDDEN
+        );
+
+        set_role(UserRole::Admin).await;
+        {
+            let db = app.state.db.read().await;
+            let settings = app.state.settings.read().await;
+            let mut current = settings.get(&db).await.unwrap();
+            current.require_admin_totp = true;
+            settings.put(&db, current).await.unwrap();
+        }
+        assert_eq!(
+            app.get("/api/admin/users").await.status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(app.get("/api/2fa").await.status, StatusCode::OK);
+
+        {
+            let db = app.state.db.read().await;
+            let mut users = app.state.users.write().await;
+            users.delete(&db, "admin").await.unwrap();
+        }
+        assert_eq!(app.get("/api/role").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn legacy_hashes_are_upgraded_on_login() {
+        use std::hash::{Hash, Hasher};
+
+        let mut app = TestApp::new().await;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        "hunter2".hash(&mut hasher);
+        {
+            let db = app.state.db.read().await;
+            let mut users = app.state.users.write().await;
+            let user = db::User {
+                name: "Sensei".to_string(),
+                primary_key: "sensei".to_string(),
+                hash: Some(hasher.finish()),
+                password_hash: String::new(),
+                role: UserRole::Standard,
+                revision: 0,
+                totp_secret: None,
+                totp_enabled: false,
+                totp_last_step: 0,
+                recovery_codes: vec![],
+                email: None,
+                pin_hash: None,
+            };
+            users.put(&db, "sensei", user).await.unwrap();
+        }
+
+        assert_eq!(
+            app.login("Sensei", "hunter3").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(app.login("Sensei", "hunter2").await.status, StatusCode::OK);
+
+        let stored: db::User = {
+            let db = app.state.db.read().await;
+            let mut users = app.state.users.write().await;
+            users.get(&db, "sensei").await.unwrap().unwrap()
+        };
+        assert_eq!(stored.hash, None);
+        assert!(stored.password_hash.starts_with("$argon2id$"));
+
+        assert_eq!(app.login("Sensei", "hunter2").await.status, StatusCode::OK);
+        assert_eq!(
+            app.login("Sensei", "hunter3").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_back_off_and_lock_out() {
+        let mut app = TestApp::new().await;
+        app.create_user("Sensei", "hunter2", UserRole::Standard)
+            .await;
+
+        for _ in 0..3 {
+            assert_eq!(
+                app.login("Sensei", "wrong").await.status,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            app.login("Sensei", "wrong").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        // The fourth failure means waiting before the next attempt, even with
+        // the right password.
+        assert_eq!(
+            app.login("Sensei", "hunter2").await.status,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn addresses_are_locked_out_across_usernames() {
+        let mut app = TestApp::new().await;
+        app.create_user("Sensei", "hunter2", UserRole::Standard)
+            .await;
+
+        for i in 0..20 {
+            app.login(&format!("user{}", i), "wrong").await;
+        }
+        let res = app.login("Sensei", "hunter2").await;
+        assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+
+        let mut other = TestApp::from_state(app.state.clone());
+        other.addr = "10.0.0.2:50000".parse().unwrap();
+        assert_eq!(
+            other.login("Sensei", "hunter2").await.status,
+            StatusCode::OK
+        );
     }
 }
@@ -8,30 +8,198 @@ extern crate core;
 
 mod admin_routes;
 mod api_routes;
+mod api_tokens;
+mod audit;
+mod categories;
+mod config;
 mod counter;
+mod csrf;
 mod db;
 mod embed_routes;
 mod integration;
+mod invite_store;
+mod keys;
+mod kiosk;
 mod login;
+mod migrations;
+mod oidc;
+mod password;
+mod password_reset;
+mod permissions;
+mod reset_store;
+mod search;
+mod session_store;
+mod settings_store;
+mod student_query;
+#[cfg(test)]
+mod test_harness;
+mod throttle;
+mod token_store;
+mod tombstone_store;
+mod totp;
+mod two_factor;
 
-use std::{collections::HashMap, fs, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
 
 use axum::{extract::FromRef, middleware, routing, Router, Server};
 use axum_extra::extract::cookie::Key;
 use clap::Parser;
-use db::{Database, UserRole};
+use db::{Column, Database, UserRole};
 use tokio::sync::RwLock;
-use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct AppState {
     key: Key,
-    invites: Arc<RwLock<HashMap<String, UserRole>>>,
-    db: Arc<RwLock<db::DynamoDB>>,
-    students: Arc<RwLock<db::CachingDynamoDBColumn<db::Student>>>,
-    users: Arc<RwLock<db::CachingDynamoDBColumn<db::User>>>,
-    imported: Arc<RwLock<db::CachingDynamoDBColumn<db::StudentImportedInfo>>>,
-    sessions: Arc<RwLock<HashMap<String, db::Session>>>,
+    // Keys from before the last rotations, see `keys::rotation_layer_fn`.
+    retired_keys: Arc<Vec<Key>>,
+    invites: Arc<RwLock<invite_store::InviteStore>>,
+    db: Arc<RwLock<db::Backend>>,
+    students: Arc<RwLock<db::CachingColumn<db::Student>>>,
+    users: Arc<RwLock<db::CachingColumn<db::User>>>,
+    imported: Arc<RwLock<db::CachingColumn<db::StudentImportedInfo>>>,
+    sessions: Arc<RwLock<session_store::SessionStore>>,
+    throttle: Arc<RwLock<throttle::LoginThrottle>>,
+    settings: Arc<RwLock<settings_store::SettingsStore>>,
+    api_tokens: Arc<RwLock<token_store::ApiTokenStore>>,
+    password_resets: Arc<RwLock<reset_store::PasswordResetStore>>,
+    audit: Arc<RwLock<audit::AuditLog>>,
+    tombstones: Arc<RwLock<tombstone_store::TombstoneStore>>,
+    categories: Arc<RwLock<categories::CategoryStore>>,
+    // Behind a blocking lock, since `CachingColumn` updates it from a hook.
+    search: Arc<std::sync::RwLock<search::SearchIndex>>,
+    oidc: Option<Arc<config::OidcConfig>>,
+    kiosk_idle_timeout: chrono::Duration,
+}
+
+impl AppState {
+    pub async fn new(db: db::Backend, keys: keys::SessionKeys, config: config::Config) -> Self {
+        #[allow(deprecated)]
+        let students = db.column("students");
+        #[allow(deprecated)]
+        let users = db.column("users");
+        #[allow(deprecated)]
+        let imported = db.column("imported");
+        #[allow(deprecated)]
+        let sessions = db.column("sessions");
+        // The stores read straight from the database, so their writes are
+        // recorded by `AuditedColumn` instead of a `CachingColumn`.
+        #[allow(deprecated)]
+        let audited =
+            |table: &str| db::AuditedColumn::new(db.column(table), table, db.column("audit"));
+        let invites = audited("invites");
+        #[allow(deprecated)]
+        let login_attempts = db.column("login_attempts");
+        let settings = audited("settings");
+        let api_tokens = audited("api_tokens");
+        let password_resets = audited("password_resets");
+        let tombstones = audited("tombstones");
+        let categories = audited("categories");
+        #[allow(deprecated)]
+        let audit = db.column("audit");
+        // Each audited column writes to the log through its own handle.
+        #[allow(deprecated)]
+        let students_audit = db.column("audit");
+        #[allow(deprecated)]
+        let users_audit = db.column("audit");
+        #[allow(deprecated)]
+        let imported_audit = db.column("audit");
+
+        let tables = async {
+            db.create_table::<db::User>("users").await?;
+            db.create_table::<db::Student>("students").await?;
+            db.create_table::<db::StudentImportedInfo>("imported")
+                .await?;
+            db.create_table::<db::StoredSession>("sessions").await?;
+            db.enable_ttl("sessions", "expires").await?;
+            db.create_table::<db::Invite>("invites").await?;
+            db.create_table::<db::LoginAttempts>("login_attempts")
+                .await?;
+            db.create_table::<db::Settings>("settings").await?;
+            db.create_table::<db::ApiToken>("api_tokens").await?;
+            db.create_table::<db::PasswordReset>("password_resets")
+                .await?;
+            db.create_table::<db::Tombstone>("tombstones").await?;
+            db.create_table::<db::AuditEntry>("audit").await?;
+            db.create_table::<db::Category>("categories").await?;
+            Ok::<(), String>(())
+        };
+        tables.await.expect("failed to create the database tables");
+
+        let categories = categories::CategoryStore::from(categories);
+        if let Err(e) = categories.seed_defaults(&db).await {
+            println!("Failed to add the default note categories: {}", e);
+        }
+        #[allow(deprecated)]
+        match migrations::run(
+            &db,
+            &db.column("students"),
+            &db.column("tombstones"),
+            &db.column("sessions"),
+        )
+        .await
+        {
+            Ok(0) => {}
+            Ok(changed) => println!("Migrated {} stored records", changed),
+            Err(e) => println!("Failed to migrate stored records: {}", e),
+        }
+
+        let search = Arc::new(std::sync::RwLock::new(search::SearchIndex::default()));
+        let indexed = (
+            students.get_values::<db::Student>(&db).await,
+            imported.get_values::<db::StudentImportedInfo>(&db).await,
+        );
+        match indexed {
+            (Ok(students), Ok(imported)) => search.write().unwrap().rebuild(&students, &imported),
+            (Err(e), _) | (_, Err(e)) => println!("Failed to build the search index: {}", e),
+        }
+        let index = search.clone();
+        let update_index: db::WriteHook<db::Student> = Arc::new(move |id, student| {
+            index.write().unwrap().update(id, student);
+        });
+        let index = search.clone();
+        let update_imported_index: db::WriteHook<db::StudentImportedInfo> =
+            Arc::new(move |key, info| {
+                index.write().unwrap().update_imported(key, info);
+            });
+
+        Self {
+            key: keys.primary,
+            retired_keys: Arc::new(keys.retired),
+            invites: Arc::new(RwLock::new(invite_store::InviteStore::from(invites))),
+            db: Arc::new(RwLock::new(db)),
+            students: Arc::new(RwLock::new(
+                db::CachingColumn::from(students)
+                    .audited("students", students_audit)
+                    .on_write(update_index),
+            )),
+            users: Arc::new(RwLock::new(
+                db::CachingColumn::from(users).audited("users", users_audit),
+            )),
+            imported: Arc::new(RwLock::new(
+                db::CachingColumn::from(imported)
+                    .audited("imported", imported_audit)
+                    .on_write(update_imported_index),
+            )),
+            sessions: Arc::new(RwLock::new(session_store::SessionStore::from(sessions))),
+            oidc: config.oidc.clone().map(Arc::new),
+            kiosk_idle_timeout: config.kiosk_idle_timeout,
+            throttle: Arc::new(RwLock::new(throttle::LoginThrottle::from(
+                login_attempts,
+                config,
+            ))),
+            settings: Arc::new(RwLock::new(settings_store::SettingsStore::from(settings))),
+            api_tokens: Arc::new(RwLock::new(token_store::ApiTokenStore::from(api_tokens))),
+            password_resets: Arc::new(RwLock::new(reset_store::PasswordResetStore::from(
+                password_resets,
+            ))),
+            audit: Arc::new(RwLock::new(audit::AuditLog::from(audit))),
+            tombstones: Arc::new(RwLock::new(tombstone_store::TombstoneStore::from(
+                tombstones,
+            ))),
+            categories: Arc::new(RwLock::new(categories)),
+            search,
+        }
+    }
 }
 
 impl FromRef<AppState> for Key {
@@ -43,7 +211,11 @@ impl FromRef<AppState> for Key {
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about = "Notes Portal")]
 struct AppArgs {
-    #[arg(long, help = "Creates an admin invite token", default_value_t = true)]
+    #[arg(
+        long,
+        help = "Creates an admin invite token if there is no admin yet",
+        default_value_t = true
+    )]
     create_token: bool,
     #[arg(
         short,
@@ -52,8 +224,110 @@ struct AppArgs {
         default_value_t = false
     )]
     test_db: bool,
+    #[arg(
+        long,
+        help = "Stores data in a SQLite database at the given path instead of DynamoDB"
+    )]
+    sqlite: Option<String>,
+    #[arg(
+        long,
+        help = "Keeps all data in memory; everything is lost when the server stops",
+        default_value_t = false
+    )]
+    memory_db: bool,
     #[arg(short, long, default_value_t = 12000)]
     port: u16,
+    #[arg(
+        long,
+        help = "Failed logins for one username before it is locked out",
+        default_value_t = 5
+    )]
+    lockout_threshold: u32,
+    #[arg(
+        long,
+        help = "Failed logins from one IP address before it is locked out",
+        default_value_t = 20
+    )]
+    ip_lockout_threshold: u32,
+    #[arg(
+        long,
+        help = "How long a lockout lasts, in minutes",
+        default_value_t = 15
+    )]
+    lockout_minutes: i64,
+    #[arg(
+        long,
+        help = "Issuer URL of an OpenID Connect provider to offer single sign-on with",
+        requires_all = ["oidc_client_id", "oidc_client_secret", "oidc_redirect_url"]
+    )]
+    oidc_issuer: Option<String>,
+    #[arg(long)]
+    oidc_client_id: Option<String>,
+    #[arg(long, env = "NOTES_OIDC_CLIENT_SECRET")]
+    oidc_client_secret: Option<String>,
+    #[arg(
+        long,
+        help = "The public URL of `/api/oidc/callback`, as registered with the provider"
+    )]
+    oidc_redirect_url: Option<String>,
+    #[arg(
+        long,
+        help = "Creates users with this role when someone logs in through OpenID Connect for the first time",
+        value_parser = ["Observer", "Standard", "Admin"]
+    )]
+    oidc_default_role: Option<String>,
+    #[arg(
+        long,
+        help = "How long a sensei stays logged in on a kiosk after their last change, in minutes",
+        default_value_t = 5
+    )]
+    kiosk_idle_minutes: i64,
+    #[arg(
+        long,
+        env = "NOTES_KEY_FILE",
+        help = "Where the keys that encrypt session cookies are kept",
+        default_value = "session_key"
+    )]
+    key_file: String,
+    #[arg(
+        long,
+        help = "Makes a new session key, keeping the old one for existing cookies, and exits",
+        default_value_t = false
+    )]
+    rotate_session_key: bool,
+    #[arg(
+        long,
+        help = "Forgets the keys replaced by `--rotate-session-key` and exits. Cookies only they can read stop working",
+        default_value_t = false
+    )]
+    drop_retired_keys: bool,
+}
+
+// Clap only lets through the names in `value_parser`.
+fn role_from_arg(role: &str) -> UserRole {
+    match role {
+        "Observer" => UserRole::Observer,
+        "Admin" => UserRole::Admin,
+        _ => UserRole::Standard,
+    }
+}
+
+// Changes the key file for `--rotate-session-key` and `--drop-retired-keys`.
+// Running servers use the new keys once they are restarted.
+fn manage_keys(args: &AppArgs, path: &Path) {
+    let keys = keys::SessionKeys::load(path).expect("failed to read the session key file");
+    let keys = if args.rotate_session_key {
+        keys.rotate()
+    } else {
+        keys.primary.into()
+    };
+    keys.save(path)
+        .expect("failed to write the session key file");
+    println!(
+        "{} has a primary key and {} retired keys",
+        path.display(),
+        keys.retired.len()
+    );
 }
 
 #[tokio::main]
@@ -62,76 +336,89 @@ async fn main() {
 
     let args = AppArgs::parse();
 
-    let key_path = Path::new("session_key");
-    let db = db::DynamoDB::new(args.test_db).await;
-    #[allow(deprecated)]
-    let students = db.column("students");
-    #[allow(deprecated)]
-    let users = db.column("users");
-    #[allow(deprecated)]
-    let imported = db.column("imported");
-    let state = AppState {
-        key: if key_path.exists() {
-            Key::from(fs::read(key_path).unwrap().as_slice())
-        } else {
-            Key::generate()
-        },
-        invites: Arc::new(RwLock::new(HashMap::new())),
-        db: Arc::new(RwLock::new(db)),
-        students: Arc::new(RwLock::new(db::CachingDynamoDBColumn::from(students))),
-        users: Arc::new(RwLock::new(db::CachingDynamoDBColumn::from(users))),
-        imported: Arc::new(RwLock::new(db::CachingDynamoDBColumn::from(imported))),
-        sessions: Arc::new(RwLock::new(HashMap::new())),
-    };
-
-    {
-        let db = state.db.read().await;
-        db.create_table::<db::User>("users").await;
-        db.create_table::<db::Student>("students").await;
-        db.create_table::<db::StudentImportedInfo>("imported").await;
+    let key_path = Path::new(&args.key_file);
+    if args.rotate_session_key || args.drop_retired_keys {
+        manage_keys(&args, key_path);
+        return;
     }
+    let keys = if key_path.exists() {
+        keys::SessionKeys::load(key_path).expect("failed to read the session key file")
+    } else {
+        let keys = keys::SessionKeys::generate();
+        if let Err(e) = keys.save(key_path) {
+            println!("{}", e);
+        }
+        keys
+    };
 
-    if !Path::new("session_key").exists() {
-        fs::write("session_key", state.key.master()).ok();
-    }
+    let db = if args.memory_db {
+        db::Backend::Memory(db::MemoryDB::new())
+    } else if let Some(path) = &args.sqlite {
+        db::Backend::Sqlite(db::SqliteDB::new(path).expect("failed to open SQLite database"))
+    } else {
+        db::Backend::DynamoDB(db::DynamoDB::new(args.test_db).await)
+    };
+    let config = config::Config {
+        lockout_threshold: args.lockout_threshold,
+        ip_lockout_threshold: args.ip_lockout_threshold,
+        lockout_duration: chrono::Duration::minutes(args.lockout_minutes),
+        oidc: args.oidc_issuer.clone().map(|issuer| config::OidcConfig {
+            issuer,
+            client_id: args.oidc_client_id.clone().unwrap_or_default(),
+            client_secret: args.oidc_client_secret.clone().unwrap_or_default(),
+            redirect_url: args.oidc_redirect_url.clone().unwrap_or_default(),
+            default_role: args.oidc_default_role.as_deref().map(role_from_arg),
+        }),
+        kiosk_idle_timeout: chrono::Duration::minutes(args.kiosk_idle_minutes),
+    };
+    let state = AppState::new(db, keys, config).await;
 
-    let app = Router::new()
-        .nest(
-            "/api",
-            api_routes::routes()
-                .nest("/admin", admin_routes::routes())
-                .route("/role", routing::get(login::user_role_get))
-                .route("/logout", routing::post(login::logout_post))
-                .route_layer(middleware::from_fn_with_state(
-                    state.clone(),
-                    login::auth_layer_fn,
-                ))
-                .route("/invite", routing::post(login::install_user_post))
-                .route("/login", routing::post(login::login_post)),
-        )
-        .merge(embed_routes::routes())
-        .with_state(state.clone());
+    let app = app(state.clone());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
     println!("Server started on port {}", args.port);
 
     if args.create_token {
-        let invites = state.invites.clone();
+        let state = state.clone();
         tokio::spawn(async move {
-            let token = Uuid::new_v4().to_string();
-            invites.write().await.insert(token.clone(), UserRole::Admin);
-
-            println!(
-                "Create administrator link: http://127.0.0.1:{}/create_user?token={}",
-                addr.port(),
-                token
-            );
+            let db = state.db.read().await;
+            // Invites are kept in the database, so only make one while nobody
+            // could make it from the admin page instead.
+            match state.users.write().await.get_values(&db).await {
+                Ok(users) if users.iter().any(|u| u.role == UserRole::Admin) => return,
+                Ok(_) => {}
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            }
+            let invite = state
+                .invites
+                .read()
+                .await
+                .create(
+                    &db,
+                    "command line",
+                    UserRole::Admin,
+                    None,
+                    chrono::Duration::days(1),
+                )
+                .await;
+
+            match invite {
+                Ok(invite) => println!(
+                    "Create administrator link: http://127.0.0.1:{}/create_user?token={}",
+                    addr.port(),
+                    invite.token
+                ),
+                Err(e) => println!("{}", e),
+            }
         });
     }
 
     let server = async move {
         Server::bind(&addr)
-            .serve(app.into_make_service())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .with_graceful_shutdown(shutdown())
             .await
     };
@@ -141,6 +428,40 @@ async fn main() {
         loop {
             let db = state.db.read().await;
             db.save();
+            if let Err(e) = state.sessions.read().await.purge_expired(&db).await {
+                println!("{}", e);
+            }
+            if let Err(e) = state.invites.read().await.purge_expired(&db).await {
+                println!("{}", e);
+            }
+            if let Err(e) = state.throttle.read().await.purge_expired(&db).await {
+                println!("{}", e);
+            }
+            if let Err(e) = state.api_tokens.read().await.purge_expired(&db).await {
+                println!("{}", e);
+            }
+            if let Err(e) = state.password_resets.read().await.purge_expired(&db).await {
+                println!("{}", e);
+            }
+            match state.settings.read().await.get(&db).await {
+                Ok(settings) => {
+                    let retention = tombstone_store::retention(&settings);
+                    let tombstones = state.tombstones.read().await;
+                    if let Err(e) = tombstones.purge_expired(&db, retention).await {
+                        println!("{}", e);
+                    }
+                }
+                Err(e) => println!("{}", e),
+            }
+            // Picks up notes written by other instances.
+            let students = state.students.write().await.get_values(&db).await;
+            let imported = state.imported.write().await.get_values(&db).await;
+            match (students, imported) {
+                (Ok(students), Ok(imported)) => {
+                    state.search.write().unwrap().rebuild(&students, &imported)
+                }
+                (Err(e), _) | (_, Err(e)) => println!("{}", e),
+            }
             tokio::time::sleep(Duration::from_secs(30 * 60)).await;
         }
     });
@@ -148,6 +469,42 @@ async fn main() {
     tokio::join!(server).0.ok();
 }
 
+pub fn app(state: AppState) -> Router {
+    Router::new()
+        .nest(
+            "/api",
+            api_routes::routes()
+                .nest("/admin", admin_routes::routes())
+                .nest("/2fa", two_factor::routes())
+                .nest("/tokens", api_tokens::routes())
+                .nest("/kiosk", kiosk::routes())
+                .route("/role", routing::get(login::user_role_get))
+                .route("/permissions", routing::get(login::permissions_get))
+                .route("/logout", routing::post(login::logout_post))
+                .route_layer(middleware::from_fn(audit::session_layer_fn))
+                .route_layer(middleware::from_fn(csrf::csrf_layer_fn))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    login::auth_layer_fn,
+                ))
+                .route(
+                    "/invite",
+                    routing::get(login::invite_get).post(login::install_user_post),
+                )
+                .route("/login", routing::post(login::login_post))
+                .nest("/reset", password_reset::routes())
+                .route("/login/totp", routing::post(two_factor::login_totp_post))
+                .nest("/oidc", oidc::routes()),
+        )
+        .merge(embed_routes::routes())
+        .layer(middleware::from_fn(audit::audit_layer_fn))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            keys::rotation_layer_fn,
+        ))
+        .with_state(state)
+}
+
 async fn shutdown() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
@@ -0,0 +1,292 @@
+use std::marker::PhantomData;
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::categories;
+use crate::db::{self, Column};
+
+// Changes to how records are stored, made to the records already in the
+// database when the server starts. Records that were changed before are left
+// alone, so these can run every time.
+
+// A record as stored, which may not be readable as a `T` until it has been
+// migrated.
+#[derive(Deserialize, Serialize)]
+#[serde(transparent)]
+struct StoredRecord<T>(Value, #[serde(skip)] PhantomData<T>);
+
+impl<T> StoredRecord<T> {
+    fn new(value: Value) -> Self {
+        Self(value, PhantomData)
+    }
+}
+
+impl<T: db::PrimaryKeyName> db::PrimaryKeyName for StoredRecord<T> {
+    fn get_primary_key_name() -> &'static str {
+        T::get_primary_key_name()
+    }
+}
+
+// Migrates students, the students and notes kept in tombstones, and sessions.
+// Returns how many records were changed.
+pub async fn run(
+    db: &db::Backend,
+    students: &db::BackendColumn,
+    tombstones: &db::BackendColumn,
+    sessions: &db::BackendColumn,
+) -> Result<usize, String> {
+    let mut changed = migrate::<db::Student>(db, students, migrate_student).await?;
+    changed += migrate::<db::Tombstone>(db, tombstones, |tombstone| {
+        match tombstone.get_mut("item") {
+            Some(Value::Object(item)) if item.contains_key("Student") => {
+                migrate_student(&mut item["Student"])
+            }
+            Some(Value::Object(item)) if item.contains_key("Note") => {
+                migrate_note(&mut item["Note"]["note"])
+            }
+            _ => false,
+        }
+    })
+    .await?;
+    changed += migrate::<db::StoredSession>(db, sessions, session_user_key).await?;
+    Ok(changed)
+}
+
+async fn migrate<T: db::PrimaryKeyName>(
+    db: &db::Backend,
+    column: &db::BackendColumn,
+    change: impl Fn(&mut Value) -> bool,
+) -> Result<usize, String> {
+    let mut changed = 0;
+    for StoredRecord(mut record, _) in column.get_values::<StoredRecord<T>>(db).await? {
+        if !change(&mut record) {
+            continue;
+        }
+        let key = record[T::get_primary_key_name()]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        column
+            .put(db, &key, StoredRecord::<T>::new(record))
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+fn migrate_student(student: &mut Value) -> bool {
+    let moved = notes_into_categories(student);
+    let mut dated = false;
+    if let Some(Value::Object(notes)) = student.get_mut("notes") {
+        for note in notes.values_mut().filter_map(Value::as_array_mut).flatten() {
+            dated |= migrate_note(note);
+        }
+    }
+    moved || dated
+}
+
+fn migrate_note(note: &mut Value) -> bool {
+    let dated = date_note(note);
+    date_revisions(note) || dated
+}
+
+// Students stored before note categories existed have a `logins`, `notes` and
+// `behaviours` list instead of `notes` keyed by category.
+fn notes_into_categories(student: &mut Value) -> bool {
+    let Some(fields) = student.as_object_mut() else {
+        return false;
+    };
+    if fields.get("notes").is_some_and(Value::is_object) {
+        return false;
+    }
+
+    let mut notes = serde_json::Map::new();
+    for (id, _) in categories::DEFAULTS {
+        let list = fields.remove(id).unwrap_or(serde_json::json!([]));
+        notes.insert(id.to_string(), list);
+    }
+    fields.insert("notes".to_string(), Value::Object(notes));
+    true
+}
+
+// Notes used to have a `date` like "10-17-26", or an empty one when they were
+// imported, instead of a `created` timestamp. They are taken to have been
+// written at midnight.
+fn date_note(note: &mut Value) -> bool {
+    let Some(fields) = note.as_object_mut() else {
+        return false;
+    };
+    let Some(date) = fields.remove("date") else {
+        return false;
+    };
+
+    let created = date
+        .as_str()
+        .and_then(|date| NaiveDate::parse_from_str(date, "%m-%d-%y").ok())
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| midnight.and_local_timezone(Local).earliest());
+    fields
+        .entry("created")
+        .or_insert(serde_json::to_value(created).unwrap_or_default());
+    true
+}
+
+// Revisions used to have a Unix timestamp as `edited`, not a date and time
+// like the note itself.
+fn date_revisions(note: &mut Value) -> bool {
+    let Some(Value::Array(revisions)) = note.get_mut("revisions") else {
+        return false;
+    };
+    let mut changed = false;
+    for revision in revisions {
+        let Some(edited) = revision.get_mut("edited") else {
+            continue;
+        };
+        let Some(timestamp) = edited.as_i64() else {
+            continue;
+        };
+        let Some(time) = DateTime::from_timestamp(timestamp, 0) else {
+            continue;
+        };
+        *edited = serde_json::to_value(time.with_timezone(&Local)).unwrap_or_default();
+        changed = true;
+    }
+    changed
+}
+
+// Sessions used to hold a copy of their user, secrets and all, instead of the
+// user's primary key.
+fn session_user_key(session: &mut Value) -> bool {
+    let Some(user) = session.get_mut("user") else {
+        return false;
+    };
+    let Some(key) = user.get("primary_key").cloned() else {
+        return false;
+    };
+    *user = key;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, Timelike};
+    use serde_json::json;
+
+    use super::{run, StoredRecord};
+    use crate::db::{self, Column, Database};
+
+    #[tokio::test]
+    async fn old_students_tombstones_and_sessions_are_migrated() {
+        let db = db::Backend::Memory(db::MemoryDB::new());
+        db.create_table::<db::Student>("students").await.unwrap();
+        db.create_table::<db::Tombstone>("tombstones")
+            .await
+            .unwrap();
+        db.create_table::<db::StoredSession>("sessions")
+            .await
+            .unwrap();
+        #[allow(deprecated)]
+        let students = db.column("students");
+        #[allow(deprecated)]
+        let tombstones = db.column("tombstones");
+        #[allow(deprecated)]
+        let sessions = db.column("sessions");
+        let old = json!({
+            "first_name": "Ada",
+            "last_name": "Lovelace",
+            "id": "s1",
+            "name": "Ada Lovelace",
+            "date": null,
+            "time": null,
+            "belt": "White",
+            "logins": [{ "id": 1, "date": "", "user": "", "content": "signed in" }],
+            "notes": [{
+                "id": 2,
+                "date": "03-14-24",
+                "user": "Sensei",
+                "content": "Good focus",
+                "revisions": [{ "content": "Focus", "editor": "Sensei", "edited": 1710417600 }]
+            }],
+            "behaviours": [],
+            "assigned": null,
+            "note_counter": { "n": 2 },
+            "revision": 0
+        });
+        students
+            .put(&db, "s1", StoredRecord::<db::Student>::new(old.clone()))
+            .await
+            .unwrap();
+        let tombstone = json!({
+            "id": "t1",
+            "deleted_by": "Admin",
+            "deleted": 0,
+            "item": { "Student": old }
+        });
+        tombstones
+            .put(&db, "t1", StoredRecord::<db::Tombstone>::new(tombstone))
+            .await
+            .unwrap();
+
+        let session = json!({
+            "token": "abc",
+            "expires": 0,
+            "user": {
+                "name": "Sensei",
+                "primary_key": "sensei",
+                "password_hash": "$argon2id$hash",
+                "role": "Admin",
+                "totp_secret": "JBSWY3DPEHPK3PXP"
+            }
+        });
+        sessions
+            .put(&db, "abc", StoredRecord::<db::StoredSession>::new(session))
+            .await
+            .unwrap();
+
+        let run = || run(&db, &students, &tombstones, &sessions);
+        assert_eq!(run().await.unwrap(), 3);
+        assert_eq!(run().await.unwrap(), 0);
+        let student = students
+            .get::<db::Student>(&db, "s1")
+            .await
+            .unwrap()
+            .unwrap();
+        let login = &student.notes_in("logins")[0];
+        assert_eq!(login.content, "signed in");
+        assert!(login.created.is_none());
+        let created = student.notes_in("notes")[0].created.unwrap();
+        assert_eq!(
+            (created.year(), created.month(), created.day()),
+            (2024, 3, 14)
+        );
+        assert_eq!(created.hour(), 0);
+        let revision = &student.notes_in("notes")[0].revisions[0];
+        assert_eq!(revision.content, "Focus");
+        assert_eq!(revision.edited.timestamp(), 1710417600);
+        assert!(student.notes_in("behaviours").is_empty());
+
+        let tombstone = tombstones
+            .get::<db::Tombstone>(&db, "t1")
+            .await
+            .unwrap()
+            .unwrap();
+        let db::Deleted::Student(student) = tombstone.item else {
+            panic!("expected a student");
+        };
+        assert!(student.notes_in("notes")[0].created.is_some());
+        assert_eq!(
+            student.notes_in("notes")[0].revisions[0].edited.timestamp(),
+            1710417600
+        );
+
+        let session = sessions
+            .get::<db::StoredSession>(&db, "abc")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.user, "sensei");
+    }
+}
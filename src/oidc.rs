@@ -0,0 +1,544 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing, Json, Router,
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
+use cookie::{Cookie, SameSite};
+use data_encoding::BASE64URL_NOPAD;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    config::OidcConfig,
+    csrf, db,
+    login::{self, Client},
+};
+
+// Holds what the callback needs to check the provider's answer against.
+const COOKIE: &str = "oidc";
+
+// The parts of the provider's discovery document we use.
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    // PKCE code verifier, only its hash is sent to the authorization endpoint.
+    verifier: String,
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    // Either a single client ID or a list of them.
+    aud: serde_json::Value,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+// Not part of the auth layer, these are how a user gets a session.
+pub fn routes() -> Router<crate::AppState> {
+    Router::new()
+        .route("/", routing::get(status_get))
+        .route("/login", routing::get(login_get))
+        .route("/callback", routing::get(callback_get))
+}
+
+fn random_string() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+async fn discover(config: &OidcConfig) -> Result<Discovery, String> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer.trim_end_matches('/')
+    );
+    reqwest::get(url)
+        .await
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Tells the login page whether to offer single sign-on.
+async fn status_get(State(state): State<crate::AppState>) -> impl IntoResponse {
+    Json(json!({ "enabled": state.oidc.is_some() }))
+}
+
+async fn login_get(
+    State(state): State<crate::AppState>,
+    cookies: PrivateCookieJar,
+) -> Result<Response, String> {
+    let Some(config) = state.oidc.as_deref() else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let discovery = discover(config).await?;
+
+    let pending = PendingLogin {
+        state: random_string(),
+        nonce: random_string(),
+        verifier: random_string(),
+    };
+    let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(pending.verifier.as_bytes()));
+    let mut url =
+        reqwest::Url::parse(&discovery.authorization_endpoint).map_err(|e| e.to_string())?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &config.redirect_url)
+        .append_pair("scope", "openid email")
+        .append_pair("state", &pending.state)
+        .append_pair("nonce", &pending.nonce)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    // Lax, so it is sent when the provider redirects back to us.
+    let cookie = Cookie::build(
+        COOKIE,
+        serde_json::to_string(&pending).map_err(|e| e.to_string())?,
+    )
+    .max_age(cookie::time::Duration::minutes(10))
+    .same_site(SameSite::Lax)
+    .path("/api/oidc")
+    .finish();
+    Ok((cookies.add(cookie), Redirect::to(url.as_str())).into_response())
+}
+
+async fn callback_get(
+    State(state): State<crate::AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    cookies: PrivateCookieJar,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, String> {
+    let Some(config) = state.oidc.as_deref() else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let pending = cookies
+        .get(COOKIE)
+        .and_then(|cookie| serde_json::from_str::<PendingLogin>(cookie.value()).ok());
+    let cookies = cookies.remove(Cookie::build(COOKIE, "").path("/api/oidc").finish());
+
+    if let Some(error) = query.error {
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            cookies,
+            format!("The identity provider didn't log you in: {}", error),
+        )
+            .into_response());
+    }
+    let (Some(pending), Some(code)) = (pending, query.code) else {
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            cookies,
+            "The login took too long, please try again",
+        )
+            .into_response());
+    };
+    // The state ties the answer to the browser that started the login.
+    if !csrf::matches(&pending.state, query.state.as_deref().unwrap_or_default()) {
+        return Ok((StatusCode::UNAUTHORIZED, cookies, "Invalid login state").into_response());
+    }
+
+    let email = match exchange_code(config, &pending, &code).await {
+        Ok(email) => email,
+        Err(e) => {
+            println!("{}", e);
+            return Ok((
+                StatusCode::UNAUTHORIZED,
+                cookies,
+                "Couldn't log in with the identity provider",
+            )
+                .into_response());
+        }
+    };
+
+    let db = state.db.read().await;
+    let Some(user) = find_user(&state, &db, config, &email).await? else {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            cookies,
+            format!("There is no user for {}", email),
+        )
+            .into_response());
+    };
+
+    let settings = state.settings.read().await.get(&db).await?;
+    let stage = login::session_stage(&user, &settings);
+    // The page asks for the TOTP code, or for setting it up, from there.
+    let to = match stage {
+        db::SessionStage::Active => "/",
+        db::SessionStage::AwaitingTotp => "/?totp",
+        db::SessionStage::MustEnrollTotp => "/admin",
+    };
+    let sessions = state.sessions.read().await;
+    let (cookies, csrf_cookies) = login::start_session(
+        &db,
+        &sessions,
+        &cookies,
+        user,
+        &Client::from(connect_info, &headers),
+        stage,
+    )
+    .await?;
+    Ok((cookies, csrf_cookies, Redirect::to(to)).into_response())
+}
+
+// Trades the authorization code for an ID token and returns the verified
+// email address in it. The token comes straight from the provider over our
+// own authenticated request, so its claims are checked but not its signature.
+async fn exchange_code(
+    config: &OidcConfig,
+    pending: &PendingLogin,
+    code: &str,
+) -> Result<String, String> {
+    let discovery = discover(config).await?;
+    let res = reqwest::Client::new()
+        .post(&discovery.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.redirect_url),
+            ("client_id", &config.client_id),
+            ("client_secret", &config.client_secret),
+            ("code_verifier", &pending.verifier),
+        ])
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("The token endpoint answered {}", res.status()));
+    }
+    let tokens: TokenResponse = res.json().await.map_err(|e| e.to_string())?;
+
+    let payload = tokens
+        .id_token
+        .split('.')
+        .nth(1)
+        .ok_or("The ID token is malformed")?;
+    let payload = BASE64URL_NOPAD
+        .decode(payload.trim_end_matches('=').as_bytes())
+        .map_err(|e| e.to_string())?;
+    let claims: Claims = serde_json::from_slice(&payload).map_err(|e| e.to_string())?;
+
+    if claims.iss.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+        return Err(format!("The ID token is from {}", claims.iss));
+    }
+    let audience_matches = match &claims.aud {
+        serde_json::Value::String(aud) => aud == &config.client_id,
+        serde_json::Value::Array(aud) => aud.iter().any(|aud| aud == &config.client_id),
+        _ => false,
+    };
+    if !audience_matches {
+        return Err("The ID token is for another client".to_string());
+    }
+    if claims.exp < Utc::now().timestamp() {
+        return Err("The ID token has expired".to_string());
+    }
+    if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+        return Err("The ID token's nonce doesn't match".to_string());
+    }
+    if claims.email_verified == Some(false) {
+        return Err("The email address isn't verified".to_string());
+    }
+    claims
+        .email
+        .ok_or_else(|| "The ID token has no email address".to_string())
+}
+
+// Finds the user with the email address, or whose name is the address.
+// Unknown addresses get a new user if a default role is configured.
+async fn find_user(
+    state: &crate::AppState,
+    db: &db::Backend,
+    config: &OidcConfig,
+    email: &str,
+) -> Result<Option<db::User>, String> {
+    let mut users = state.users.write().await;
+    let key = email.to_lowercase();
+    let existing = users.get_values(db).await?.into_iter().find(|user| {
+        user.primary_key == key
+            || user
+                .email
+                .as_ref()
+                .is_some_and(|address| address.to_lowercase() == key)
+    });
+    if existing.is_some() {
+        return Ok(existing);
+    }
+
+    let Some(role) = config.default_role.clone() else {
+        return Ok(None);
+    };
+    let user = db::User {
+        name: email.to_string(),
+        primary_key: key.clone(),
+        hash: None,
+        // Can't be used to log in with a password.
+        password_hash: String::new(),
+        role,
+        revision: 0,
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: 0,
+        recovery_codes: vec![],
+        email: Some(email.to_string()),
+        pin_hash: None,
+    };
+    match users.put(db, &key, user.clone()).await {
+        Ok(()) => Ok(Some(user)),
+        // Created by a login that finished at the same time.
+        Err(db::WriteError::Conflict) => users.get(db, &key).await,
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::State,
+        http::{Method, StatusCode},
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use chrono::Utc;
+    use data_encoding::BASE64URL_NOPAD;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    use crate::config::{Config, OidcConfig};
+    use crate::db::{self, UserRole};
+    use crate::test_harness::TestApp;
+
+    // What the mock provider saw at the authorization endpoint.
+    #[derive(Default)]
+    struct Authorization {
+        nonce: String,
+        challenge: String,
+        email: String,
+    }
+
+    type Mock = Arc<Mutex<Authorization>>;
+
+    async fn token_post(
+        State((issuer, mock)): State<(String, Mock)>,
+        Form(form): Form<std::collections::HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, StatusCode> {
+        let mock = mock.lock().unwrap();
+        let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(form["code_verifier"].as_bytes()));
+        if form["code"] != "good-code" || challenge != mock.challenge {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let claims = json!({
+            "iss": issuer,
+            "aud": "notes-portal",
+            "exp": Utc::now().timestamp() + 60,
+            "nonce": mock.nonce,
+            "email": mock.email,
+            "email_verified": true,
+        });
+        let id_token = format!(
+            "{}.{}.",
+            BASE64URL_NOPAD.encode(br#"{"alg":"none"}"#),
+            BASE64URL_NOPAD.encode(claims.to_string().as_bytes())
+        );
+        Ok(Json(
+            json!({ "id_token": id_token, "token_type": "Bearer" }),
+        ))
+    }
+
+    // Starts a provider on a free port and returns its issuer URL.
+    async fn mock_provider(mock: Mock) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let issuer = format!("http://{}", addr);
+        let discovery = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+        });
+        let router = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || async move { Json(discovery) }),
+            )
+            .route("/token", post(token_post))
+            .with_state((issuer.clone(), mock));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+        issuer
+    }
+
+    async fn app_with_provider(mock: Mock, default_role: Option<UserRole>) -> TestApp {
+        let issuer = mock_provider(mock).await;
+        TestApp::with_config(Config {
+            oidc: Some(OidcConfig {
+                issuer,
+                client_id: "notes-portal".to_string(),
+                client_secret: "secret".to_string(),
+                redirect_url: "http://127.0.0.1:12000/api/oidc/callback".to_string(),
+                default_role,
+            }),
+            ..Config::default()
+        })
+        .await
+    }
+
+    // Follows the redirect to the provider, which logs `email` in, and
+    // returns the `state` to come back with.
+    async fn authorize(app: &mut TestApp, mock: &Mock, email: &str) -> String {
+        let res = app.get("/api/oidc/login").await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+        let location = res.headers["location"].to_str().unwrap();
+        let url = reqwest::Url::parse(location).unwrap();
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.to_string())
+                .unwrap()
+        };
+        assert_eq!(param("code_challenge_method"), "S256");
+        *mock.lock().unwrap() = Authorization {
+            nonce: param("nonce"),
+            challenge: param("code_challenge"),
+            email: email.to_string(),
+        };
+        param("state")
+    }
+
+    #[tokio::test]
+    async fn emails_are_mapped_to_users() {
+        let mock = Mock::default();
+        let mut app = app_with_provider(mock.clone(), None).await;
+        app.create_user("Sensei", "hunter2", UserRole::Standard)
+            .await;
+        app.create_user("Admin", "hunter2", UserRole::Admin).await;
+        app.login("Admin", "hunter2").await;
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/set_email",
+                &json!({ "name": "Sensei", "email": "sensei@dojo.example" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let state = authorize(&mut app, &mock, "Sensei@dojo.example").await;
+        let res = app
+            .get(&format!(
+                "/api/oidc/callback?code=good-code&state={}",
+                state
+            ))
+            .await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+        assert_eq!(res.headers["location"], "/");
+        assert_eq!(
+            app.get("/api/role").await.json::<UserRole>(),
+            UserRole::Standard
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_emails_need_a_default_role() {
+        let mock = Mock::default();
+        let mut app = app_with_provider(mock.clone(), None).await;
+        let state = authorize(&mut app, &mock, "new@dojo.example").await;
+        let res = app
+            .get(&format!(
+                "/api/oidc/callback?code=good-code&state={}",
+                state
+            ))
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert_eq!(app.get("/api/role").await.status, StatusCode::UNAUTHORIZED);
+
+        let mock = Mock::default();
+        let mut app = app_with_provider(mock.clone(), Some(UserRole::Observer)).await;
+        let state = authorize(&mut app, &mock, "new@dojo.example").await;
+        let res = app
+            .get(&format!(
+                "/api/oidc/callback?code=good-code&state={}",
+                state
+            ))
+            .await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+        assert_eq!(
+            app.get("/api/role").await.json::<UserRole>(),
+            UserRole::Observer
+        );
+
+        let db = app.state.db.read().await;
+        let mut users = app.state.users.write().await;
+        let user: db::User = users.get(&db, "new@dojo.example").await.unwrap().unwrap();
+        assert_eq!(user.email.as_deref(), Some("new@dojo.example"));
+        assert!(!crate::password::verify(&user, ""));
+    }
+
+    #[tokio::test]
+    async fn callbacks_must_match_the_login() {
+        let mock = Mock::default();
+        let mut app = app_with_provider(mock.clone(), Some(UserRole::Standard)).await;
+        let state = authorize(&mut app, &mock, "new@dojo.example").await;
+
+        let res = app
+            .get("/api/oidc/callback?code=good-code&state=forged")
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        // The pending login is gone after a failed callback.
+        let res = app
+            .get(&format!(
+                "/api/oidc/callback?code=good-code&state={}",
+                state
+            ))
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+        // A code can only be exchanged with the verifier of the login that
+        // asked for it.
+        let state = authorize(&mut app, &mock, "new@dojo.example").await;
+        mock.lock().unwrap().challenge = "someone else's".to_string();
+        let res = app
+            .get(&format!(
+                "/api/oidc/callback?code=good-code&state={}",
+                state
+            ))
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(app.get("/api/role").await.status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,68 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+
+use crate::db;
+
+// Hashes a password with Argon2id and returns it as a PHC string.
+pub fn hash(pass: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(pass.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub fn verify(user: &db::User, pass: &str) -> bool {
+    if let Some(legacy) = user.hash {
+        return legacy_hash(pass) == legacy;
+    }
+
+    matches(&user.password_hash, pass)
+}
+
+// Checks a secret against an Argon2 PHC string from `hash`.
+pub fn matches(hash: &str, pass: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(pass.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Checks a new password against the policy admins set, returning what is
+// wrong with it.
+pub fn check_policy(settings: &db::Settings, pass: &str) -> Result<(), String> {
+    if pass.chars().count() < settings.min_password_length {
+        return Err(format!(
+            "Passwords need at least {} characters",
+            settings.min_password_length
+        ));
+    }
+    if settings.password_needs_digit && !pass.chars().any(|c| c.is_ascii_digit()) {
+        return Err("Passwords need at least one digit".to_string());
+    }
+    if settings.password_needs_symbol && pass.chars().all(|c| c.is_alphanumeric()) {
+        return Err("Passwords need at least one symbol".to_string());
+    }
+    Ok(())
+}
+
+// Users created before Argon2 was introduced still have one of these until
+// their next successful login.
+pub fn needs_upgrade(user: &db::User) -> bool {
+    user.hash.is_some()
+}
+
+fn legacy_hash(pass: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    pass.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,255 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+    routing, Json, Router,
+};
+use axum_extra::extract::{Form, PrivateCookieJar};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    csrf, db, password,
+    reset_store::{PasswordResetStore, ResetError},
+};
+
+#[derive(Deserialize)]
+pub struct ResetQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetData {
+    token: String,
+    pass: String,
+    #[serde(default)]
+    csrf: String,
+}
+
+#[derive(Serialize)]
+pub struct ResetOut {
+    name: String,
+    expires: i64,
+}
+
+// The page behind an admin's reset link. Like invites, these are used without
+// a session.
+pub fn routes() -> Router<crate::AppState> {
+    Router::new().route("/", routing::get(reset_get).post(reset_post))
+}
+
+fn reset_error(e: ResetError) -> (StatusCode, String) {
+    match e {
+        ResetError::Failed(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+        e => (StatusCode::UNAUTHORIZED, e.to_string()),
+    }
+}
+
+// Also hands out the form's CSRF token in the `X-CSRF-Token` header.
+async fn reset_get(
+    State(state): State<crate::AppState>,
+    cookies: PrivateCookieJar,
+    Query(query): Query<ResetQuery>,
+) -> impl IntoResponse {
+    let (cookies, csrf_token) = csrf::form_token(cookies, csrf::RESET_COOKIE, "/api/reset");
+    let parts = (cookies, [(csrf::HEADER, csrf_token)]);
+
+    let db = state.db.read().await;
+    let resets = state.password_resets.read().await;
+    let reset = match resets.check(&db, &query.token).await {
+        Ok(reset) => reset,
+        Err(e) => return (parts, reset_error(e)).into_response(),
+    };
+    let user = state.users.write().await.get(&db, &reset.user).await;
+    match user {
+        Ok(Some(user)) => (
+            parts,
+            Json(ResetOut {
+                name: user.name,
+                expires: reset.expires,
+            }),
+        )
+            .into_response(),
+        Ok(None) => (parts, reset_error(ResetError::NotFound)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, parts, e).into_response(),
+    }
+}
+
+async fn reset_post(
+    State(state): State<crate::AppState>,
+    cookies: PrivateCookieJar,
+    Form(form): Form<ResetData>,
+) -> Result<impl IntoResponse, String> {
+    if !csrf::form_matches(&cookies, csrf::RESET_COOKIE, &form.csrf) {
+        return Ok((StatusCode::FORBIDDEN, "Missing or invalid CSRF token").into_response());
+    }
+
+    let db = state.db.read().await;
+    let settings = state.settings.read().await.get(&db).await?;
+    if let Err(e) = password::check_policy(&settings, &form.pass) {
+        return Ok((StatusCode::BAD_REQUEST, e).into_response());
+    }
+    let password_hash = password::hash(&form.pass)?;
+
+    let resets = state.password_resets.read().await;
+    let reset = match resets.redeem(&db, &form.token).await {
+        Ok(reset) => reset,
+        Err(e) => return Ok(reset_error(e).into_response()),
+    };
+    let mut users = state.users.write().await;
+    let user = match users.get(&db, &reset.user).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(reset_error(ResetError::NotFound).into_response()),
+        Err(e) => {
+            release(&resets, &db, &form.token).await;
+            return Err(e);
+        }
+    };
+    let written = users
+        .diff_update(&db, &user.primary_key, &user, |u| {
+            u.hash = None;
+            u.password_hash = password_hash.clone();
+        })
+        .await;
+    if let Err(e) = written {
+        // The password wasn't changed, so the link can be used again.
+        release(&resets, &db, &form.token).await;
+        return Ok(match e {
+            db::WriteError::Conflict => (
+                StatusCode::CONFLICT,
+                "The account was changed at the same time, try again",
+            )
+                .into_response(),
+            e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        });
+    }
+
+    // Whoever knew the old password is logged out, and the user can log in
+    // again straight away even if they were locked out.
+    let sessions = state.sessions.read().await;
+    sessions.remove_user(&db, &user.primary_key, None).await?;
+    let throttle = state.throttle.read().await;
+    throttle.record_success(&db, &user.primary_key).await?;
+
+    Ok(Redirect::to("/").into_response())
+}
+
+async fn release(resets: &PasswordResetStore, db: &db::Backend, token: &str) {
+    if let Err(e) = resets.release(db, token).await {
+        println!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::db::UserRole;
+    use crate::test_harness::TestApp;
+
+    async fn reset_link(app: &mut TestApp, name: &str) -> String {
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/reset_link",
+                &json!({ "name": name }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        res.json()
+    }
+
+    #[tokio::test]
+    async fn reset_links_let_users_choose_a_password_once() {
+        let mut admin = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        admin
+            .create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        let mut sensei = TestApp::from_state(admin.state.clone());
+        sensei.login("Sensei", "password").await;
+
+        let token = reset_link(&mut admin, "Sensei").await;
+        let mut user = TestApp::from_state(admin.state.clone());
+        let res = user.get(&format!("/api/reset?token={}", token)).await;
+        assert_eq!(res.json::<serde_json::Value>()["name"], "Sensei");
+
+        let res = user.reset_password(&token, "short").await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        let res = user.reset_password(&token, "correct horse").await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+        let res = user.reset_password(&token, "another one").await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.text(), "This reset link has already been used");
+
+        assert_eq!(
+            sensei.get("/api/role").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            user.login("Sensei", "password").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            user.login("Sensei", "correct horse").await.status,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn released_links_can_be_used_again() {
+        let mut admin = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        admin
+            .create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        let token = reset_link(&mut admin, "Sensei").await;
+        {
+            let db = admin.state.db.read().await;
+            let resets = admin.state.password_resets.read().await;
+            resets.redeem(&db, &token).await.unwrap();
+            resets.release(&db, &token).await.unwrap();
+        }
+
+        let mut user = TestApp::from_state(admin.state.clone());
+        let res = user.reset_password(&token, "correct horse").await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+
+        for hours in [0, 24 * 7 + 1, i64::MAX] {
+            let res = admin
+                .send_json(
+                    Method::POST,
+                    "/api/admin/reset_link",
+                    &json!({ "name": "Sensei", "hours": hours }),
+                )
+                .await;
+            assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn new_links_replace_old_ones() {
+        let mut admin = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        admin
+            .create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        let old = reset_link(&mut admin, "Sensei").await;
+        let new = reset_link(&mut admin, "sensei").await;
+
+        let mut user = TestApp::from_state(admin.state.clone());
+        let res = user.reset_password(&old, "correct horse").await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        let res = user.reset_password(&new, "correct horse").await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+
+        // Nor can anyone else make them.
+        let mut sensei = TestApp::from_state(admin.state.clone());
+        sensei.login("Sensei", "correct horse").await;
+        let res = sensei
+            .send_json(
+                Method::POST,
+                "/api/admin/reset_link",
+                &json!({ "name": "Admin" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,91 @@
+use axum::{
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use serde_diff::SerdeDiff;
+
+use crate::db::{self, UserRole};
+
+#[derive(SerdeDiff, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    ViewStudents,
+    // Adding notes, and editing or deleting the ones you wrote.
+    EditOwnNotes,
+    EditOthersNotes,
+    DeleteNotes,
+    // Deleting a whole student, notes and all.
+    DeleteStudents,
+    AssignStudents,
+    ImportCsv,
+    // Loading students from MyStudio and clearing check-in times.
+    RunSync,
+    ManageUsers,
+}
+
+use Permission::*;
+
+impl UserRole {
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            // Front desk staff who need to see the grid but not change it.
+            UserRole::Observer => &[ViewStudents],
+            UserRole::Standard => &[ViewStudents, EditOwnNotes, AssignStudents],
+            UserRole::Admin => &[
+                ViewStudents,
+                EditOwnNotes,
+                EditOthersNotes,
+                DeleteNotes,
+                DeleteStudents,
+                AssignStudents,
+                ImportCsv,
+                RunSync,
+                ManageUsers,
+            ],
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
+
+impl db::Session {
+    // What the session may do, which for API tokens is limited to the scopes
+    // the token was created with.
+    pub fn permissions(&self) -> Vec<Permission> {
+        self.user
+            .role
+            .permissions()
+            .iter()
+            .copied()
+            .filter(|permission| self.can(*permission))
+            .collect()
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.user.role.can(permission)
+            && self
+                .scopes
+                .as_ref()
+                .is_none_or(|scopes| scopes.contains(&permission))
+    }
+}
+
+// Layer for routes that need a permission, used with
+// `middleware::from_fn_with_state(Permission::..., require_layer)`.
+pub async fn require_layer<B>(
+    State(permission): State<Permission>,
+    Extension(session): Extension<db::Session>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if session.can(permission) {
+        next.run(req).await
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    }
+}
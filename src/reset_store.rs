@@ -0,0 +1,138 @@
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::db;
+
+// Kept for a while after they expire, like invites, so an old link explains
+// why it no longer works.
+const RETENTION_DAYS: i64 = 7;
+
+#[derive(Debug, PartialEq)]
+pub enum ResetError {
+    NotFound,
+    Expired,
+    Used,
+    Failed(String),
+}
+
+impl std::fmt::Display for ResetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetError::NotFound => write!(f, "This reset link does not exist or was replaced"),
+            ResetError::Expired => write!(f, "This reset link has expired"),
+            ResetError::Used => write!(f, "This reset link has already been used"),
+            ResetError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for ResetError {
+    fn from(value: String) -> Self {
+        ResetError::Failed(value)
+    }
+}
+
+// Password reset links, read straight from the database like invites.
+pub struct PasswordResetStore {
+    internal: db::AuditedColumn,
+}
+
+impl PasswordResetStore {
+    pub fn from(col: db::AuditedColumn) -> Self {
+        Self { internal: col }
+    }
+
+    // Any earlier link for the same user stops working.
+    pub async fn create(
+        &self,
+        db: &db::Backend,
+        creator: &str,
+        user: &str,
+        valid_for: Duration,
+    ) -> Result<db::PasswordReset, String> {
+        self.remove_user(db, user).await?;
+
+        let created = Utc::now();
+        let reset = db::PasswordReset {
+            token: Uuid::new_v4().to_string(),
+            user: user.to_string(),
+            creator: creator.to_string(),
+            created: created.timestamp(),
+            expires: (created + valid_for).timestamp(),
+            used: false,
+            revision: 0,
+        };
+        self.internal
+            .put(db, &reset.token.clone(), reset.clone())
+            .await?;
+        Ok(reset)
+    }
+
+    pub async fn check(
+        &self,
+        db: &db::Backend,
+        token: &str,
+    ) -> Result<db::PasswordReset, ResetError> {
+        match self.internal.get::<db::PasswordReset>(db, token).await? {
+            None => Err(ResetError::NotFound),
+            Some(reset) if reset.used => Err(ResetError::Used),
+            Some(reset) if reset.expires < Utc::now().timestamp() => Err(ResetError::Expired),
+            Some(reset) => Ok(reset),
+        }
+    }
+
+    // Marks the link as used. Like `InviteStore::redeem`, only one of two
+    // concurrent redemptions gets through.
+    pub async fn redeem(
+        &self,
+        db: &db::Backend,
+        token: &str,
+    ) -> Result<db::PasswordReset, ResetError> {
+        let reset = self.check(db, token).await?;
+        let used = db::PasswordReset {
+            used: true,
+            ..reset.clone()
+        };
+        match self.internal.put(db, token, used).await {
+            Ok(()) => Ok(reset),
+            Err(db::WriteError::Conflict) => Err(ResetError::Used),
+            Err(db::WriteError::Failed(e)) => Err(ResetError::Failed(e)),
+        }
+    }
+
+    // Hands a redeemed link back, like `InviteStore::release`, when the new
+    // password couldn't be saved.
+    pub async fn release(&self, db: &db::Backend, token: &str) -> Result<(), String> {
+        if let Some(reset) = self.internal.get::<db::PasswordReset>(db, token).await? {
+            let reset = db::PasswordReset {
+                used: false,
+                ..reset
+            };
+            self.internal.put(db, token, reset).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_user(&self, db: &db::Backend, user: &str) -> Result<(), String> {
+        for reset in self.internal.get_values::<db::PasswordReset>(db).await? {
+            if reset.user == user {
+                self.internal
+                    .delete::<db::PasswordReset>(db, &reset.token)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn purge_expired(&self, db: &db::Backend) -> Result<(), String> {
+        let cutoff = (Utc::now() - Duration::days(RETENTION_DAYS)).timestamp();
+        for reset in self.internal.get_values::<db::PasswordReset>(db).await? {
+            if reset.expires < cutoff {
+                self.internal
+                    .delete::<db::PasswordReset>(db, &reset.token)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
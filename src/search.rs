@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+
+// BM25 parameters, the usual defaults.
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+const DEFAULT_LIMIT: usize = 20;
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SearchHit {
+    // Primary key of the student.
+    pub student: String,
+    pub student_name: String,
+    // The category and id of the note, or neither when the student's name
+    // matched.
+    pub category: Option<String>,
+    pub note: Option<u32>,
+    pub author: Option<String>,
+    pub date: Option<DateTime<Local>>,
+    pub content: String,
+    pub score: f64,
+}
+
+// Students synced from MyStudio are keyed by their id, those only known from
+// the CSV import by their name in lowercase, which `student_get` takes as well.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Source {
+    MyStudio,
+    Imported,
+}
+
+// A student's name, or one of their notes.
+type DocumentKey = (Source, String, Option<(String, u32)>);
+
+struct Document {
+    hit: SearchHit,
+    terms: HashMap<String, u32>,
+    length: usize,
+}
+
+// Kept in memory and updated through `db::CachingColumn::on_write` whenever a
+// student or imported student is written. Writes made by other instances are
+// only picked up when the index is rebuilt.
+#[derive(Default)]
+pub struct SearchIndex {
+    documents: HashMap<DocumentKey, Document>,
+    postings: HashMap<String, HashSet<DocumentKey>>,
+    by_student: HashMap<(Source, String), Vec<DocumentKey>>,
+    // How many students from MyStudio have each name, in lowercase. Imported
+    // students with the same name are left out of the results, like they are
+    // left out of the student list.
+    synced_names: HashMap<String, usize>,
+    total_length: usize,
+}
+
+fn tokens(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+impl SearchIndex {
+    pub fn rebuild(&mut self, students: &[db::Student], imported: &[db::StudentImportedInfo]) {
+        *self = Self::default();
+        for student in students {
+            self.add(Source::MyStudio, &student.id, student);
+        }
+        for info in imported {
+            self.update_imported(&info.name.to_lowercase(), Some(info));
+        }
+    }
+
+    // Replaces what is indexed for the student `id` with `student`.
+    pub fn update(&mut self, id: &str, student: Option<&db::Student>) {
+        self.remove(Source::MyStudio, id);
+        if let Some(student) = student {
+            self.add(Source::MyStudio, id, student);
+        }
+    }
+
+    // Replaces what is indexed for the imported student stored under `key`.
+    pub fn update_imported(&mut self, key: &str, info: Option<&db::StudentImportedInfo>) {
+        self.remove(Source::Imported, key);
+        if let Some(info) = info {
+            let student = crate::api_routes::imported_student(info.clone());
+            self.add(Source::Imported, key, &student);
+        }
+    }
+
+    fn remove(&mut self, source: Source, id: &str) {
+        let keys = self.by_student.remove(&(source, id.to_string()));
+        for key in keys.unwrap_or_default() {
+            let Some(document) = self.documents.remove(&key) else {
+                continue;
+            };
+            if key.0 == Source::MyStudio && key.2.is_none() {
+                let name = document.hit.student_name.to_lowercase();
+                if let Some(count) = self.synced_names.get_mut(&name) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.synced_names.remove(&name);
+                    }
+                }
+            }
+            self.total_length -= document.length;
+            for term in document.terms.keys() {
+                if let Some(keys) = self.postings.get_mut(term) {
+                    keys.remove(&key);
+                    if keys.is_empty() {
+                        self.postings.remove(term);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, source: Source, id: &str, student: &db::Student) {
+        if source == Source::MyStudio {
+            *self
+                .synced_names
+                .entry(student.name.to_lowercase())
+                .or_default() += 1;
+        }
+        let name = SearchHit {
+            student: id.to_string(),
+            student_name: student.name.clone(),
+            category: None,
+            note: None,
+            author: None,
+            date: None,
+            content: student.name.clone(),
+            score: 0.0,
+        };
+        self.insert((source.clone(), id.to_string(), None), name.clone());
+        for (category, notes) in &student.notes {
+            for note in notes {
+                let hit = SearchHit {
+                    category: Some(category.clone()),
+                    note: Some(note.id),
+                    author: Some(note.user.clone()).filter(|user| !user.is_empty()),
+                    date: note.created,
+                    content: note.content.clone(),
+                    ..name.clone()
+                };
+                let key = (
+                    source.clone(),
+                    id.to_string(),
+                    Some((category.clone(), note.id)),
+                );
+                self.insert(key, hit);
+            }
+        }
+    }
+
+    fn insert(&mut self, key: DocumentKey, hit: SearchHit) {
+        let words = tokens(&hit.content);
+        let mut terms: HashMap<String, u32> = HashMap::new();
+        for word in &words {
+            *terms.entry(word.clone()).or_default() += 1;
+        }
+        for term in terms.keys() {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(key.clone());
+        }
+        self.total_length += words.len();
+        self.by_student
+            .entry((key.0.clone(), key.1.clone()))
+            .or_default()
+            .push(key.clone());
+        let document = Document {
+            hit,
+            terms,
+            length: words.len(),
+        };
+        self.documents.insert(key, document);
+    }
+
+    // The best matches for `query`, ranked with BM25. Documents only need to
+    // contain one of the words, but those containing more rank higher.
+    pub fn search(&self, query: &str, limit: Option<usize>) -> Vec<SearchHit> {
+        let count = self.documents.len() as f64;
+        let average_length = self.total_length as f64 / count.max(1.0);
+        let mut scores: HashMap<&DocumentKey, f64> = HashMap::new();
+        let mut terms = tokens(query);
+        terms.sort();
+        terms.dedup();
+        for term in &terms {
+            let Some(keys) = self.postings.get(term) else {
+                continue;
+            };
+            let found = keys.len() as f64;
+            let idf = (1.0 + (count - found + 0.5) / (found + 0.5)).ln();
+            for key in keys {
+                let document = &self.documents[key];
+                let frequency = document.terms[term] as f64;
+                let length = document.length as f64 / average_length.max(1.0);
+                *scores.entry(key).or_default() +=
+                    idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length));
+            }
+        }
+
+        let mut hits: Vec<SearchHit> = scores
+            .into_iter()
+            .filter(|(key, _)| key.0 == Source::MyStudio || !self.synced_names.contains_key(&key.1))
+            .map(|(key, score)| SearchHit {
+                score,
+                ..self.documents[key].hit.clone()
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| b.date.cmp(&a.date))
+        });
+        hits.truncate(limit.unwrap_or(DEFAULT_LIMIT));
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::{SearchHit, SearchIndex};
+    use crate::db::UserRole;
+    use crate::test_harness::{student, TestApp};
+
+    #[test]
+    fn rebuilding_finds_names_and_notes() {
+        let mut ada = student("s1", "Ada Lovelace");
+        ada.notes.get_mut("notes").unwrap().push(crate::db::Note {
+            id: 1,
+            created: None,
+            edited: None,
+            user: "Sensei".to_string(),
+            content: "Wrote the first program".to_string(),
+            revisions: vec![],
+        });
+        let mut index = SearchIndex::default();
+        index.rebuild(&[ada, student("s2", "Alan Turing")], &[]);
+
+        let hits = index.search("turing", None);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].student, "s2");
+        assert!(hits[0].category.is_none());
+        let hits = index.search("PROGRAM", None);
+        assert_eq!(hits[0].note, Some(1));
+        assert_eq!(hits[0].author.as_deref(), Some("Sensei"));
+
+        index.update("s1", None);
+        assert!(index.search("program", None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn writes_update_the_index() {
+        let mut app = TestApp::logged_in_as("Sensei", UserRole::Standard).await;
+        app.put_student(student("s1", "Ada Lovelace")).await;
+        app.put_student(student("s2", "Alan Turing")).await;
+        for (id, note) in [
+            ("s1", "Stuck on the Scratch maze project"),
+            ("s2", "Finished the maze"),
+            ("s2", "Scratch basics"),
+        ] {
+            app.send_json(
+                Method::PUT,
+                &format!("/api/students/{}/notes", id),
+                &json!({ "note": note }),
+            )
+            .await;
+        }
+
+        let res = app.get("/api/search?q=scratch%20maze%20project").await;
+        assert_eq!(res.status, StatusCode::OK);
+        let hits: Vec<SearchHit> = res.json();
+        assert_eq!(hits.len(), 3);
+        assert_eq!(hits[0].student_name, "Ada Lovelace");
+        assert_eq!(hits[0].category.as_deref(), Some("notes"));
+        assert_eq!(hits[0].author.as_deref(), Some("Sensei"));
+        assert!(hits[0].date.is_some());
+
+        let note_id = hits[0].note.unwrap();
+        app.send_json(
+            Method::PATCH,
+            "/api/students/s1/notes",
+            &json!({ "id": note_id, "note": "Got through the maze" }),
+        )
+        .await;
+        let hits: Vec<SearchHit> = app.get("/api/search?q=project").await.json();
+        assert!(hits.is_empty());
+        let hits: Vec<SearchHit> = app.get("/api/search?q=lovelace").await.json();
+        assert_eq!(hits[0].student, "s1");
+    }
+
+    #[tokio::test]
+    async fn imported_students_are_searched() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let csv = "Name,Belt,Logins,Notes,Behaviours\n\
+            Grace Hopper,Yellow,,Found a moth in the relay,\n\
+            Ada Lovelace,White,,Imported before the sync,\n";
+        app.post_multipart(
+            "/api/load_csv",
+            &[("row_data", "[1,2,3,4,5]"), ("file", csv)],
+        )
+        .await;
+
+        let hits: Vec<SearchHit> = app.get("/api/search?q=moth").await.json();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].student, "grace hopper");
+        assert_eq!(hits[0].student_name, "Grace Hopper");
+        assert_eq!(hits[0].category.as_deref(), Some("notes"));
+        let res = app.get("/api/students/grace%20hopper").await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        // Once MyStudio knows the student, only that record is searched.
+        let hits: Vec<SearchHit> = app.get("/api/search?q=sync").await.json();
+        assert_eq!(hits.len(), 1);
+        app.put_student(student("s1", "Ada Lovelace")).await;
+        let hits: Vec<SearchHit> = app.get("/api/search?q=sync").await.json();
+        assert!(hits.is_empty());
+        let hits: Vec<SearchHit> = app.get("/api/search?q=lovelace").await.json();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].student, "s1");
+    }
+}
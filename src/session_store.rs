@@ -0,0 +1,86 @@
+use chrono::Local;
+
+use crate::db::{self, Column};
+
+// Sessions are read straight from the database rather than through a
+// `CachingColumn`, so a logout on one instance is seen by every other one.
+pub struct SessionStore {
+    internal: db::BackendColumn,
+}
+
+impl SessionStore {
+    pub fn from(col: db::BackendColumn) -> Self {
+        Self { internal: col }
+    }
+
+    pub async fn get(
+        &self,
+        db: &db::Backend,
+        token: &str,
+    ) -> Result<Option<db::StoredSession>, String> {
+        match self.internal.get::<db::StoredSession>(db, token).await? {
+            Some(session) if session.expires >= Local::now().timestamp() => Ok(Some(session)),
+            Some(_) => {
+                self.remove(db, token).await?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_values(&self, db: &db::Backend) -> Result<Vec<db::StoredSession>, String> {
+        self.internal.get_values::<db::StoredSession>(db).await
+    }
+
+    pub async fn insert(&self, db: &db::Backend, session: db::StoredSession) -> Result<(), String> {
+        self.internal
+            .put(db, &session.token.clone(), session)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn remove(&self, db: &db::Backend, token: &str) -> Result<(), String> {
+        self.internal.delete::<db::StoredSession>(db, token).await
+    }
+
+    pub async fn for_user(
+        &self,
+        db: &db::Backend,
+        primary_key: &str,
+    ) -> Result<Vec<db::StoredSession>, String> {
+        Ok(self
+            .get_values(db)
+            .await?
+            .into_iter()
+            .filter(|session| session.user == primary_key)
+            .collect())
+    }
+
+    // Ends every session of the user, apart from the one with the token
+    // `keep`, if given.
+    pub async fn remove_user(
+        &self,
+        db: &db::Backend,
+        primary_key: &str,
+        keep: Option<&str>,
+    ) -> Result<(), String> {
+        for session in self.for_user(db, primary_key).await? {
+            if Some(session.token.as_str()) != keep {
+                self.remove(db, &session.token).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // DynamoDB drops expired sessions on its own through TTL, the other
+    // backends rely on this being run every now and then.
+    pub async fn purge_expired(&self, db: &db::Backend) -> Result<(), String> {
+        let now = Local::now().timestamp();
+        for session in self.get_values(db).await? {
+            if session.expires < now {
+                self.remove(db, &session.token).await?;
+            }
+        }
+        Ok(())
+    }
+}
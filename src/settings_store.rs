@@ -0,0 +1,28 @@
+use crate::db;
+
+pub struct SettingsStore {
+    internal: db::AuditedColumn,
+}
+
+impl SettingsStore {
+    pub fn from(col: db::AuditedColumn) -> Self {
+        Self { internal: col }
+    }
+
+    pub async fn get(&self, db: &db::Backend) -> Result<db::Settings, String> {
+        let key = db::Settings::default().key;
+        Ok(self
+            .internal
+            .get::<db::Settings>(db, &key)
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn put(
+        &self,
+        db: &db::Backend,
+        settings: db::Settings,
+    ) -> Result<(), db::WriteError> {
+        self.internal.put(db, &settings.key.clone(), settings).await
+    }
+}
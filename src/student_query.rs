@@ -0,0 +1,150 @@
+use chrono::NaiveTime;
+use data_encoding::BASE64URL_NOPAD;
+use serde::Deserialize;
+
+use crate::db;
+
+// Query parameters of `GET /api/students`. Every filter given has to match.
+#[derive(Deserialize, Default)]
+pub struct StudentQuery {
+    // Only "today", for the students who checked in today.
+    pub present: Option<String>,
+    // Comma separated names of senseis. An empty name matches students nobody
+    // is assigned to, so `Sensei,` gives Sensei's students and unassigned ones.
+    pub assigned: Option<String>,
+    pub belt: Option<String>,
+    // Start of the class the student checked in to, e.g. "5:00 PM".
+    pub time: Option<String>,
+    // Part of the student's name.
+    pub q: Option<String>,
+    // "time", the default, or "name".
+    pub sort: Option<String>,
+    pub limit: Option<usize>,
+    // Sent back in `X-Next-Cursor` when there are more students after a page.
+    pub cursor: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Sort {
+    // Students who checked in first, by class, then by name.
+    Time,
+    // By last name, then first name.
+    Name,
+}
+
+impl StudentQuery {
+    pub fn only_present(&self) -> bool {
+        self.present.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.present.as_ref().is_some_and(|p| p != "today") {
+            return Err("`present` must be 'today'".to_string());
+        }
+        self.sort()?;
+        self.after()?;
+        Ok(())
+    }
+
+    fn sort(&self) -> Result<Sort, String> {
+        match self.sort.as_deref() {
+            None | Some("time") => Ok(Sort::Time),
+            Some("name") => Ok(Sort::Name),
+            Some(_) => Err("`sort` must be one of ['time', 'name']".to_string()),
+        }
+    }
+
+    // The sort key of the last student of the previous page.
+    fn after(&self) -> Result<Option<Vec<String>>, String> {
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+        BASE64URL_NOPAD
+            .decode(cursor.as_bytes())
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .map(Some)
+            .ok_or("`cursor` is not valid".to_string())
+    }
+
+    // Expects `date` to have been cleared for students who didn't check in
+    // today.
+    pub fn matches(&self, student: &db::Student) -> bool {
+        let same = |a: &str, b: &str| a.trim().to_lowercase() == b.trim().to_lowercase();
+        (!self.only_present() || student.date.is_some())
+            && self.assigned.as_ref().is_none_or(|names| {
+                let assigned = student.assigned.as_deref().unwrap_or("");
+                names.split(',').any(|name| same(name, assigned))
+            })
+            && self
+                .belt
+                .as_ref()
+                .is_none_or(|belt| same(belt, &student.belt))
+            && self
+                .time
+                .as_ref()
+                .is_none_or(|time| student.time.as_ref().is_some_and(|t| same(time, t)))
+            && self.q.as_ref().is_none_or(|q| {
+                student
+                    .name
+                    .to_lowercase()
+                    .contains(&q.trim().to_lowercase())
+            })
+    }
+
+    // Sorts the students and returns the page the query asks for, along with
+    // the cursor for the next one if there are more.
+    pub fn page(
+        &self,
+        students: Vec<db::Student>,
+    ) -> Result<(Vec<db::Student>, Option<String>), String> {
+        let sort = self.sort()?;
+        let after = self.after()?;
+        let mut keyed: Vec<(Vec<String>, db::Student)> = students
+            .into_iter()
+            .map(|student| (sort_key(sort, &student), student))
+            .filter(|(key, _)| after.as_ref().is_none_or(|after| key > after))
+            .collect();
+        keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let limit = self.limit.unwrap_or(keyed.len());
+        let next = match keyed.len() > limit && limit > 0 {
+            true => serde_json::to_vec(&keyed[limit - 1].0)
+                .map(|json| Some(BASE64URL_NOPAD.encode(&json)))
+                .map_err(|e| e.to_string())?,
+            false => None,
+        };
+        keyed.truncate(limit);
+        Ok((
+            keyed.into_iter().map(|(_, student)| student).collect(),
+            next,
+        ))
+    }
+}
+
+// Ends with the lowercase name, which is unique, so every student has their
+// own place to continue from.
+fn sort_key(sort: Sort, student: &db::Student) -> Vec<String> {
+    let name = vec![
+        student.last_name.trim().to_lowercase(),
+        student.first_name.trim().to_lowercase(),
+        student.name.to_lowercase(),
+    ];
+    match sort {
+        Sort::Name => name,
+        Sort::Time => {
+            // "9:00 AM" comes before "10:00 AM".
+            let time = student.time.as_ref().map(|time| {
+                NaiveTime::parse_from_str(time.trim(), "%I:%M %p")
+                    .map(|t| t.format("%H:%M").to_string())
+                    .unwrap_or(time.clone())
+            });
+            let mut key = vec![
+                if time.is_some() { "0" } else { "1" }.to_string(),
+                time.unwrap_or_default(),
+            ];
+            key.extend(name);
+            key
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use axum_extra::extract::cookie::Key;
+use serde::{de::DeserializeOwned, Serialize};
+use tower::ServiceExt;
+
+use crate::{
+    config::Config,
+    csrf,
+    db::{self, UserRole},
+};
+
+// Builds the same router as `main` on top of an in-memory database and keeps
+// track of cookies between requests, like a browser would.
+pub struct TestApp {
+    pub state: crate::AppState,
+    router: Router,
+    cookies: HashMap<String, String>,
+    // The address requests appear to come from.
+    pub addr: SocketAddr,
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+impl TestApp {
+    pub async fn new() -> Self {
+        Self::with_config(Config::default()).await
+    }
+
+    pub async fn with_config(config: Config) -> Self {
+        Self::from_state(
+            crate::AppState::new(
+                db::Backend::Memory(db::MemoryDB::new()),
+                Key::generate().into(),
+                config,
+            )
+            .await,
+        )
+    }
+
+    pub fn from_state(state: crate::AppState) -> Self {
+        Self {
+            router: crate::app(state.clone()),
+            state,
+            cookies: HashMap::new(),
+            addr: SocketAddr::from(([127, 0, 0, 1], 50000)),
+        }
+    }
+
+    // Carries this app's cookies over to `other`, e.g. to act as the same
+    // browser against a restarted server.
+    pub fn share_cookies(&self, other: &mut TestApp) {
+        other.cookies = self.cookies.clone();
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies.get(name).cloned()
+    }
+
+    pub fn forget_cookie(&mut self, name: &str) {
+        self.cookies.remove(name);
+    }
+
+    pub async fn request(
+        &mut self,
+        method: Method,
+        uri: &str,
+        content_type: Option<&str>,
+        body: Body,
+        headers: &[(&str, &str)],
+    ) -> TestResponse {
+        // Like the frontend, send the CSRF token with anything but a GET
+        // unless the test picked one itself.
+        let csrf_token = self.cookies.get(csrf::COOKIE).filter(|_| {
+            method != Method::GET
+                && !headers
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case(csrf::HEADER))
+        });
+        let mut req = Request::builder()
+            .method(method.clone())
+            .uri(uri)
+            .extension(ConnectInfo(self.addr));
+        if let Some(content_type) = content_type {
+            req = req.header(header::CONTENT_TYPE, content_type);
+        }
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        if let Some(csrf_token) = csrf_token {
+            req = req.header(csrf::HEADER, csrf_token);
+        }
+        if !self.cookies.is_empty() {
+            let cookie = self
+                .cookies
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<String>>()
+                .join("; ");
+            req = req.header(header::COOKIE, cookie);
+        }
+
+        let res = self
+            .router
+            .clone()
+            .oneshot(req.body(body).unwrap())
+            .await
+            .unwrap();
+
+        for set_cookie in res.headers().get_all(header::SET_COOKIE) {
+            let cookie = cookie::Cookie::parse(set_cookie.to_str().unwrap().to_string()).unwrap();
+            if cookie.value().is_empty() || cookie.max_age().is_some_and(|age| age.is_zero()) {
+                self.cookies.remove(cookie.name());
+            } else {
+                self.cookies
+                    .insert(cookie.name().to_string(), cookie.value().to_string());
+            }
+        }
+
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        TestResponse {
+            status,
+            headers,
+            body: body.to_vec(),
+        }
+    }
+
+    pub async fn get(&mut self, uri: &str) -> TestResponse {
+        self.request(Method::GET, uri, None, Body::empty(), &[])
+            .await
+    }
+
+    pub async fn send_json<T: Serialize>(
+        &mut self,
+        method: Method,
+        uri: &str,
+        body: &T,
+    ) -> TestResponse {
+        self.request(
+            method,
+            uri,
+            Some("application/json"),
+            Body::from(serde_json::to_vec(body).unwrap()),
+            &[],
+        )
+        .await
+    }
+
+    pub async fn post_form(&mut self, uri: &str, fields: &[(&str, &str)]) -> TestResponse {
+        let body = fields
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, urlencode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+        self.request(
+            Method::POST,
+            uri,
+            Some("application/x-www-form-urlencoded"),
+            Body::from(body),
+            &[],
+        )
+        .await
+    }
+
+    pub async fn post_multipart(&mut self, uri: &str, fields: &[(&str, &str)]) -> TestResponse {
+        let boundary = "notes-portal-test-boundary";
+        let mut body = String::new();
+        for (name, value) in fields {
+            body += &format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            );
+        }
+        body += &format!("--{}--\r\n", boundary);
+        self.request(
+            Method::POST,
+            uri,
+            Some(&format!("multipart/form-data; boundary={}", boundary)),
+            Body::from(body),
+            &[],
+        )
+        .await
+    }
+
+    pub async fn create_invite(&self, role: UserRole, name: Option<&str>) -> db::Invite {
+        let db = self.state.db.read().await;
+        let invites = self.state.invites.read().await;
+        invites
+            .create(
+                &db,
+                "test",
+                role,
+                name.map(|name| name.to_string()),
+                chrono::Duration::hours(1),
+            )
+            .await
+            .unwrap()
+    }
+
+    // Opens the invite page and submits its form, like the create user page.
+    pub async fn redeem_invite(&mut self, token: &str, name: &str, pass: &str) -> TestResponse {
+        let res = self.get(&format!("/api/invite?token={}", token)).await;
+        let csrf_token = res.headers[csrf::HEADER].to_str().unwrap().to_string();
+        self.post_form(
+            "/api/invite",
+            &[
+                ("name", name),
+                ("pass", pass),
+                ("token", token),
+                ("csrf", &csrf_token),
+            ],
+        )
+        .await
+    }
+
+    // Opens a password reset link and submits its form.
+    pub async fn reset_password(&mut self, token: &str, pass: &str) -> TestResponse {
+        let res = self.get(&format!("/api/reset?token={}", token)).await;
+        let csrf_token = res.headers[csrf::HEADER].to_str().unwrap().to_string();
+        self.post_form(
+            "/api/reset",
+            &[("token", token), ("pass", pass), ("csrf", &csrf_token)],
+        )
+        .await
+    }
+
+    // Creates a user through the invite flow, the same way an admin would.
+    pub async fn create_user(&mut self, name: &str, pass: &str, role: UserRole) {
+        let token = self.create_invite(role, None).await.token;
+        let cookies = std::mem::take(&mut self.cookies);
+        let res = self.redeem_invite(&token, name, pass).await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+        self.cookies = cookies;
+    }
+
+    pub async fn login(&mut self, name: &str, pass: &str) -> TestResponse {
+        self.send_json(
+            Method::POST,
+            "/api/login",
+            &serde_json::json!({ "name": name, "pass": pass }),
+        )
+        .await
+    }
+
+    pub async fn logged_in_as(name: &str, role: UserRole) -> Self {
+        let mut app = Self::new().await;
+        app.create_user(name, "password", role).await;
+        assert_eq!(app.login(name, "password").await.status, StatusCode::OK);
+        app
+    }
+
+    pub async fn put_student(&self, student: db::Student) {
+        let db = self.state.db.read().await;
+        let mut students = self.state.students.write().await;
+        students
+            .put(&db, &student.id.clone(), student)
+            .await
+            .unwrap();
+    }
+
+    pub async fn get_student(&self, id: &str) -> Option<db::Student> {
+        let db = self.state.db.read().await;
+        let mut students = self.state.students.write().await;
+        students.get(&db, id).await.unwrap()
+    }
+}
+
+pub fn student(id: &str, name: &str) -> db::Student {
+    let (first_name, last_name) = name.split_at(name.find(' ').unwrap_or(0));
+    db::Student {
+        first_name: first_name.to_string(),
+        last_name: last_name.trim().to_string(),
+        id: id.to_string(),
+        name: name.to_string(),
+        date: None,
+        time: None,
+        belt: "White".to_string(),
+        notes: crate::categories::DEFAULTS
+            .iter()
+            .map(|(category, _)| (category.to_string(), vec![]))
+            .collect(),
+        assigned: None,
+        note_counter: crate::counter::Counter::new(),
+        check_ins: vec![],
+        revision: 0,
+    }
+}
+
+fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,241 @@
+use std::net::IpAddr;
+
+use chrono::Utc;
+
+use crate::{
+    config::Config,
+    db::{self, Column, LockoutKind},
+};
+
+// Failures allowed before every further attempt has to wait.
+const FREE_ATTEMPTS: u32 = 3;
+
+// Tracks failed logins per username and per IP address. After a few failures
+// each attempt has to wait twice as long as the previous one, and once the
+// configured threshold is reached the username or address is locked out.
+pub struct LoginThrottle {
+    internal: db::BackendColumn,
+    config: Config,
+}
+
+fn key(kind: &LockoutKind, subject: &str) -> String {
+    match kind {
+        LockoutKind::User => format!("user:{}", subject.to_lowercase()),
+        LockoutKind::Ip => format!("ip:{}", subject),
+    }
+}
+
+impl LoginThrottle {
+    pub fn from(col: db::BackendColumn, config: Config) -> Self {
+        Self {
+            internal: col,
+            config,
+        }
+    }
+
+    fn subjects(name: &str, ip: Option<IpAddr>) -> Vec<(LockoutKind, String)> {
+        let mut subjects = vec![(LockoutKind::User, name.to_lowercase())];
+        if let Some(ip) = ip {
+            subjects.push((LockoutKind::Ip, ip.to_string()));
+        }
+        subjects
+    }
+
+    fn threshold(&self, kind: &LockoutKind) -> u32 {
+        match kind {
+            LockoutKind::User => self.config.lockout_threshold,
+            LockoutKind::Ip => self.config.ip_lockout_threshold,
+        }
+    }
+
+    // Failures older than the lockout duration are forgotten.
+    fn is_stale(&self, attempts: &db::LoginAttempts, now: i64) -> bool {
+        let locked = attempts.locked_until.is_some_and(|until| until > now);
+        !locked && now - attempts.last_failure > self.config.lockout_duration.num_seconds()
+    }
+
+    fn blocked_until(&self, attempts: &db::LoginAttempts, now: i64) -> Option<i64> {
+        if let Some(until) = attempts.locked_until {
+            return (until > now).then_some(until);
+        }
+        if self.is_stale(attempts, now) || attempts.failures <= FREE_ATTEMPTS {
+            return None;
+        }
+
+        let backoff = 2_i64
+            .saturating_pow(attempts.failures - FREE_ATTEMPTS - 1)
+            .min(self.config.lockout_duration.num_seconds());
+        let until = attempts.last_failure + backoff;
+        (until > now).then_some(until)
+    }
+
+    // Returns how many seconds the caller has to wait before trying again,
+    // if they can't log in right now.
+    pub async fn check(
+        &self,
+        db: &db::Backend,
+        name: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Option<i64>, String> {
+        let now = Utc::now().timestamp();
+        let mut wait = None;
+        for (kind, subject) in Self::subjects(name, ip) {
+            let attempts = self
+                .internal
+                .get::<db::LoginAttempts>(db, &key(&kind, &subject))
+                .await?;
+            if let Some(until) = attempts.and_then(|a| self.blocked_until(&a, now)) {
+                wait = wait.max(Some(until - now));
+            }
+        }
+        Ok(wait)
+    }
+
+    pub async fn record_failure(
+        &self,
+        db: &db::Backend,
+        name: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), String> {
+        for (kind, subject) in Self::subjects(name, ip) {
+            // Retry if another failed login for the same key was recorded
+            // at the same time.
+            for _ in 0..3 {
+                match self.add_failure(db, &kind, &subject).await {
+                    Err(db::WriteError::Conflict) => continue,
+                    res => {
+                        res?;
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_failure(
+        &self,
+        db: &db::Backend,
+        kind: &LockoutKind,
+        subject: &str,
+    ) -> Result<(), db::WriteError> {
+        let now = Utc::now().timestamp();
+        let key = key(kind, subject);
+        let stored = self.internal.get::<db::LoginAttempts>(db, &key).await?;
+        if let Some(stored) = &stored
+            && stored.locked_until.is_some_and(|until| until > now)
+        {
+            return Ok(());
+        }
+
+        let mut attempts = match stored {
+            Some(attempts) if !self.is_stale(&attempts, now) && attempts.locked_until.is_none() => {
+                attempts
+            }
+            // Nothing recorded recently, or a lockout that has run out.
+            Some(attempts) => db::LoginAttempts {
+                failures: 0,
+                locked_until: None,
+                ..attempts
+            },
+            None => db::LoginAttempts {
+                key: key.clone(),
+                kind: kind.clone(),
+                subject: subject.to_string(),
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+                revision: 0,
+            },
+        };
+        attempts.failures += 1;
+        attempts.last_failure = now;
+        if attempts.failures >= self.threshold(kind) {
+            attempts.locked_until = Some(now + self.config.lockout_duration.num_seconds());
+        }
+        self.internal.put(db, &key, attempts).await
+    }
+
+    // Called after a successful login. Only the username is cleared, so a
+    // valid account can't be used to reset an address that is guessing
+    // other users' passwords.
+    pub async fn record_success(&self, db: &db::Backend, name: &str) -> Result<(), String> {
+        self.unlock(db, &key(&LockoutKind::User, name)).await
+    }
+
+    pub async fn locked(&self, db: &db::Backend) -> Result<Vec<db::LoginAttempts>, String> {
+        let now = Utc::now().timestamp();
+        Ok(self
+            .internal
+            .get_values::<db::LoginAttempts>(db)
+            .await?
+            .into_iter()
+            .filter(|attempts| attempts.locked_until.is_some_and(|until| until > now))
+            .collect())
+    }
+
+    pub async fn unlock(&self, db: &db::Backend, key: &str) -> Result<(), String> {
+        self.internal.delete::<db::LoginAttempts>(db, key).await
+    }
+
+    pub async fn purge_expired(&self, db: &db::Backend) -> Result<(), String> {
+        let now = Utc::now().timestamp();
+        for attempts in self.internal.get_values::<db::LoginAttempts>(db).await? {
+            if self.is_stale(&attempts, now) {
+                self.unlock(db, &attempts.key).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn attempts(failures: u32, last_failure: i64, locked_until: Option<i64>) -> db::LoginAttempts {
+        db::LoginAttempts {
+            key: "user:sensei".to_string(),
+            kind: LockoutKind::User,
+            subject: "sensei".to_string(),
+            failures,
+            last_failure,
+            locked_until,
+            revision: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn backoff_doubles_after_free_attempts() {
+        let db = db::Backend::Memory(db::MemoryDB::new());
+        #[allow(deprecated)]
+        let throttle = LoginThrottle::from(
+            db::Database::column(&db, "login_attempts"),
+            Config::default(),
+        );
+
+        assert_eq!(throttle.blocked_until(&attempts(3, 100, None), 100), None);
+        assert_eq!(
+            throttle.blocked_until(&attempts(4, 100, None), 100),
+            Some(101)
+        );
+        assert_eq!(
+            throttle.blocked_until(&attempts(6, 100, None), 100),
+            Some(104)
+        );
+        assert_eq!(throttle.blocked_until(&attempts(6, 100, None), 104), None);
+        assert_eq!(
+            throttle.blocked_until(&attempts(40, 100, None), 100),
+            Some(100 + Duration::minutes(15).num_seconds())
+        );
+        assert_eq!(
+            throttle.blocked_until(&attempts(5, 100, Some(500)), 200),
+            Some(500)
+        );
+        assert_eq!(
+            throttle.blocked_until(&attempts(5, 100, Some(500)), 500),
+            None
+        );
+    }
+}
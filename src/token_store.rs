@@ -0,0 +1,123 @@
+use chrono::Utc;
+use data_encoding::HEXLOWER;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{db, permissions::Permission};
+
+// Makes tokens easy to spot, e.g. by secret scanners.
+const PREFIX: &str = "np_";
+
+// Tokens are random enough that a fast hash is fine, unlike passwords, and
+// it lets a token be looked up by its hash on every request.
+fn hash(token: &str) -> String {
+    HEXLOWER.encode(&Sha256::digest(token.as_bytes()))
+}
+
+// Personal API tokens, read straight from the database like sessions so a
+// revoked token stops working on every instance.
+pub struct ApiTokenStore {
+    internal: db::AuditedColumn,
+}
+
+impl ApiTokenStore {
+    pub fn from(col: db::AuditedColumn) -> Self {
+        Self { internal: col }
+    }
+
+    // Returns the token itself, which isn't stored anywhere, along with the
+    // stored record.
+    pub async fn create(
+        &self,
+        db: &db::Backend,
+        user: &str,
+        name: &str,
+        scopes: Vec<Permission>,
+        expires: Option<i64>,
+    ) -> Result<(String, db::ApiToken), String> {
+        let token = format!(
+            "{}{}{}",
+            PREFIX,
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+        let api_token = db::ApiToken {
+            hash: hash(&token),
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            user: user.to_string(),
+            scopes,
+            created: Utc::now().timestamp(),
+            expires,
+            revision: 0,
+        };
+        self.internal
+            .put(db, &api_token.hash.clone(), api_token.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok((token, api_token))
+    }
+
+    pub async fn get(&self, db: &db::Backend, token: &str) -> Result<Option<db::ApiToken>, String> {
+        if !token.starts_with(PREFIX) {
+            return Ok(None);
+        }
+        let now = Utc::now().timestamp();
+        Ok(self
+            .internal
+            .get::<db::ApiToken>(db, &hash(token))
+            .await?
+            .filter(|api_token| api_token.expires.is_none_or(|expires| expires >= now)))
+    }
+
+    pub async fn for_user(
+        &self,
+        db: &db::Backend,
+        user: &str,
+    ) -> Result<Vec<db::ApiToken>, String> {
+        Ok(self
+            .internal
+            .get_values::<db::ApiToken>(db)
+            .await?
+            .into_iter()
+            .filter(|api_token| api_token.user == user)
+            .collect())
+    }
+
+    // Returns whether `user` had a token with the id.
+    pub async fn revoke(&self, db: &db::Backend, user: &str, id: &str) -> Result<bool, String> {
+        let Some(api_token) = self
+            .for_user(db, user)
+            .await?
+            .into_iter()
+            .find(|api_token| api_token.id == id)
+        else {
+            return Ok(false);
+        };
+        self.internal
+            .delete::<db::ApiToken>(db, &api_token.hash)
+            .await?;
+        Ok(true)
+    }
+
+    pub async fn remove_user(&self, db: &db::Backend, user: &str) -> Result<(), String> {
+        for api_token in self.for_user(db, user).await? {
+            self.internal
+                .delete::<db::ApiToken>(db, &api_token.hash)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn purge_expired(&self, db: &db::Backend) -> Result<(), String> {
+        let now = Utc::now().timestamp();
+        for api_token in self.internal.get_values::<db::ApiToken>(db).await? {
+            if api_token.expires.is_some_and(|expires| expires < now) {
+                self.internal
+                    .delete::<db::ApiToken>(db, &api_token.hash)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::db;
+
+// The longest retention that can be set, well clear of overflowing a
+// `Duration` or a timestamp.
+pub const MAX_RETENTION_DAYS: i64 = 3650;
+
+// How long tombstones are kept, as set on the admin page. Clamped as well, for
+// settings saved before there was a maximum.
+pub fn retention(settings: &db::Settings) -> Duration {
+    Duration::days(settings.deleted_retention_days.clamp(0, MAX_RETENTION_DAYS))
+}
+
+// Deleted notes, students and users, kept so a mistake can be undone.
+pub struct TombstoneStore {
+    internal: db::AuditedColumn,
+}
+
+impl TombstoneStore {
+    pub fn from(col: db::AuditedColumn) -> Self {
+        Self { internal: col }
+    }
+
+    pub async fn bury(
+        &self,
+        db: &db::Backend,
+        deleted_by: &str,
+        item: db::Deleted,
+    ) -> Result<db::Tombstone, String> {
+        let tombstone = db::Tombstone {
+            id: Uuid::new_v4().to_string(),
+            deleted_by: deleted_by.to_string(),
+            deleted: Utc::now().timestamp(),
+            item,
+        };
+        self.internal
+            .put(db, &tombstone.id.clone(), tombstone.clone())
+            .await?;
+        Ok(tombstone)
+    }
+
+    // The tombstones still within `retention`, newest first.
+    pub async fn list(
+        &self,
+        db: &db::Backend,
+        retention: Duration,
+    ) -> Result<Vec<db::Tombstone>, String> {
+        let cutoff = (Utc::now() - retention).timestamp();
+        let mut tombstones: Vec<db::Tombstone> = self
+            .internal
+            .get_values::<db::Tombstone>(db)
+            .await?
+            .into_iter()
+            .filter(|tombstone| tombstone.deleted >= cutoff)
+            .collect();
+        tombstones.sort_by_key(|tombstone| std::cmp::Reverse(tombstone.deleted));
+        Ok(tombstones)
+    }
+
+    pub async fn get(
+        &self,
+        db: &db::Backend,
+        id: &str,
+        retention: Duration,
+    ) -> Result<Option<db::Tombstone>, String> {
+        let cutoff = (Utc::now() - retention).timestamp();
+        Ok(self
+            .internal
+            .get::<db::Tombstone>(db, id)
+            .await?
+            .filter(|tombstone| tombstone.deleted >= cutoff))
+    }
+
+    // The ids of students deleted within `retention`, which the MyStudio sync
+    // leaves alone.
+    pub async fn deleted_student_ids(
+        &self,
+        db: &db::Backend,
+        retention: Duration,
+    ) -> Result<HashSet<String>, String> {
+        Ok(self
+            .list(db, retention)
+            .await?
+            .into_iter()
+            .filter_map(|tombstone| match tombstone.item {
+                db::Deleted::Student(student) => Some(student.id),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub async fn remove(&self, db: &db::Backend, id: &str) -> Result<(), String> {
+        self.internal.delete::<db::Tombstone>(db, id).await
+    }
+
+    pub async fn purge_expired(&self, db: &db::Backend, retention: Duration) -> Result<(), String> {
+        let cutoff = (Utc::now() - retention).timestamp();
+        for tombstone in self.internal.get_values::<db::Tombstone>(db).await? {
+            if tombstone.deleted < cutoff {
+                self.remove(db, &tombstone.id).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::TombstoneStore;
+    use crate::db::{self, Database};
+    use crate::test_harness::student;
+
+    #[tokio::test]
+    async fn expired_tombstones_are_purged() {
+        let db = db::Backend::Memory(db::MemoryDB::new());
+        db.create_table::<db::Tombstone>("tombstones")
+            .await
+            .unwrap();
+        db.create_table::<db::AuditEntry>("audit").await.unwrap();
+        #[allow(deprecated)]
+        let tombstones = TombstoneStore::from(db::AuditedColumn::new(
+            db.column("tombstones"),
+            "tombstones",
+            db.column("audit"),
+        ));
+
+        let fresh = tombstones
+            .bury(
+                &db,
+                "Admin",
+                db::Deleted::Student(student("s1", "Ada Lovelace")),
+            )
+            .await
+            .unwrap();
+        let old = db::Tombstone {
+            id: "old".to_string(),
+            deleted: (Utc::now() - Duration::days(31)).timestamp(),
+            ..fresh.clone()
+        };
+        tombstones.internal.put(&db, "old", old).await.unwrap();
+
+        let retention = Duration::days(30);
+        assert_eq!(tombstones.list(&db, retention).await.unwrap().len(), 1);
+        assert_eq!(
+            tombstones
+                .deleted_student_ids(&db, retention)
+                .await
+                .unwrap(),
+            ["s1".to_string()].into()
+        );
+        assert!(tombstones
+            .get(&db, "old", retention)
+            .await
+            .unwrap()
+            .is_none());
+        tombstones.purge_expired(&db, retention).await.unwrap();
+        assert!(tombstones
+            .get(&db, "old", Duration::days(365))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(tombstones
+            .get(&db, &fresh.id, retention)
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
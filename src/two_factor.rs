@@ -0,0 +1,426 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing, Extension, Json, Router,
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db,
+    login::{self, Client},
+    password, totp,
+};
+
+#[derive(Deserialize)]
+pub struct CodeIn {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct StatusOut {
+    enabled: bool,
+    required: bool,
+}
+
+#[derive(Serialize)]
+pub struct EnrollOut {
+    secret: String,
+    uri: String,
+    qr_svg: String,
+}
+
+pub fn routes() -> Router<crate::AppState> {
+    Router::new()
+        .route("/", routing::get(status_get))
+        .route("/enroll", routing::post(enroll_post))
+        .route("/confirm", routing::post(confirm_post))
+        .route("/disable", routing::post(disable_post))
+}
+
+async fn is_required(
+    state: &crate::AppState,
+    db: &db::Backend,
+    user: &db::User,
+) -> Result<bool, String> {
+    let settings = state.settings.read().await.get(db).await?;
+    Ok(settings.require_admin_totp && user.role == db::UserRole::Admin)
+}
+
+// Checks a TOTP or recovery code, and returns the user as it should be
+// stored afterwards so the same code can't be used again.
+fn check_code(user: &db::User, code: &str) -> Option<db::User> {
+    let secret = user.totp_secret.as_ref()?;
+    let now = Utc::now().timestamp() as u64;
+    if let Some(step) = totp::verify(secret, code, now, user.totp_last_step) {
+        return Some(db::User {
+            totp_last_step: step,
+            ..user.clone()
+        });
+    }
+
+    let code = code.trim().to_lowercase();
+    let used = user
+        .recovery_codes
+        .iter()
+        .position(|hash| password::matches(hash, &code))?;
+    let mut user = user.clone();
+    user.recovery_codes.remove(used);
+    Some(user)
+}
+
+async fn status_get(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+) -> Result<Json<StatusOut>, String> {
+    let db = state.db.read().await;
+    let mut users = state.users.write().await;
+    let Some(user) = users.get(&db, &session.user.primary_key).await? else {
+        return Err("User no longer exists".to_string());
+    };
+
+    Ok(Json(StatusOut {
+        enabled: user.totp_enabled,
+        required: is_required(&state, &db, &user).await?,
+    }))
+}
+
+async fn enroll_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let mut users = state.users.write().await;
+    let Some(user) = users.get(&db, &session.user.primary_key).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    if user.totp_enabled {
+        return Ok((
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled",
+        )
+            .into_response());
+    }
+
+    let secret = totp::generate_secret();
+    users
+        .diff_update(&db, &user.primary_key, &user, |u| {
+            u.totp_secret = Some(secret.clone())
+        })
+        .await?;
+
+    let uri = totp::provisioning_uri(&secret, &user.name);
+    Ok(Json(EnrollOut {
+        qr_svg: totp::qr_svg(&uri)?,
+        secret,
+        uri,
+    })
+    .into_response())
+}
+
+// Turns two-factor authentication on once the user shows their authenticator
+// works, and hands out the recovery codes. They are only ever shown here.
+async fn confirm_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    Json(payload): Json<CodeIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let mut users = state.users.write().await;
+    let Some(user) = users.get(&db, &session.user.primary_key).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    if user.totp_enabled {
+        return Ok((
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled",
+        )
+            .into_response());
+    }
+    let Some(secret) = &user.totp_secret else {
+        return Ok((StatusCode::BAD_REQUEST, "Enroll first").into_response());
+    };
+    let now = Utc::now().timestamp() as u64;
+    let Some(step) = totp::verify(secret, &payload.code, now, user.totp_last_step) else {
+        return Ok((StatusCode::UNAUTHORIZED, "Wrong code").into_response());
+    };
+
+    let codes = totp::generate_recovery_codes();
+    let hashes = codes
+        .iter()
+        .map(|code| password::hash(code))
+        .collect::<Result<Vec<String>, String>>()?;
+    users
+        .diff_update(&db, &user.primary_key, &user, |u| {
+            u.totp_enabled = true;
+            u.totp_last_step = step;
+            u.recovery_codes = hashes;
+        })
+        .await?;
+
+    if session.stage == db::SessionStage::MustEnrollTotp {
+        let sessions = state.sessions.read().await;
+        sessions
+            .insert(
+                &db,
+                db::Session {
+                    stage: db::SessionStage::Active,
+                    ..session.stored()
+                },
+            )
+            .await?;
+    }
+
+    Ok(Json(codes).into_response())
+}
+
+async fn disable_post(
+    State(state): State<crate::AppState>,
+    Extension(session): Extension<db::Session>,
+    Json(payload): Json<CodeIn>,
+) -> Result<impl IntoResponse, String> {
+    let db = state.db.read().await;
+    let mut users = state.users.write().await;
+    let Some(user) = users.get(&db, &session.user.primary_key).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    if is_required(&state, &db, &user).await? {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            "Two-factor authentication is required for your role",
+        )
+            .into_response());
+    }
+    if !user.totp_enabled || check_code(&user, &payload.code).is_none() {
+        return Ok((StatusCode::UNAUTHORIZED, "Wrong code").into_response());
+    }
+
+    users
+        .diff_update(&db, &user.primary_key, &user, |u| {
+            u.totp_secret = None;
+            u.totp_enabled = false;
+            u.recovery_codes = vec![];
+        })
+        .await?;
+
+    Ok(StatusCode::OK.into_response())
+}
+
+// Second step of logging in for users with two-factor authentication, after
+// `login_post` answered with 202 Accepted.
+pub async fn login_totp_post(
+    State(state): State<crate::AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    cookies: PrivateCookieJar,
+    Json(payload): Json<CodeIn>,
+) -> Result<impl IntoResponse, String> {
+    let client = Client::from(connect_info, &headers);
+    let db = state.db.read().await;
+    let sessions = state.sessions.read().await;
+    let throttle = state.throttle.read().await;
+
+    let Some(token) = cookies.get("token") else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let pending = match sessions.get(&db, token.value()).await? {
+        Some(session) if session.stage == db::SessionStage::AwaitingTotp => session,
+        _ => return Ok(StatusCode::UNAUTHORIZED.into_response()),
+    };
+    let name = pending.user.clone();
+    if let Some(wait) = throttle.check(&db, &name, client.ip).await? {
+        return Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait.to_string())],
+            format!("Too many failed logins, try again in {} seconds", wait),
+        )
+            .into_response());
+    }
+
+    let mut users = state.users.write().await;
+    let Some(user) = users.get(&db, &name).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let Some(updated) = check_code(&user, &payload.code) else {
+        throttle.record_failure(&db, &name, client.ip).await?;
+        return Ok((StatusCode::UNAUTHORIZED, "Wrong code").into_response());
+    };
+    let (last_step, recovery_codes) = (updated.totp_last_step, updated.recovery_codes.clone());
+    let written = users
+        .diff_update(&db, &name, &user, |u| {
+            u.totp_last_step = last_step;
+            u.recovery_codes = recovery_codes;
+        })
+        .await;
+    // Someone else logged in with the same code at the same time.
+    if written == Err(db::WriteError::Conflict) {
+        return Ok((StatusCode::UNAUTHORIZED, "Wrong code").into_response());
+    }
+    written?;
+    throttle.record_success(&db, &name).await?;
+
+    sessions.remove(&db, &pending.token).await?;
+    let (cookies, csrf_cookies) = login::start_session(
+        &db,
+        &sessions,
+        &cookies,
+        updated,
+        &client,
+        db::SessionStage::Active,
+    )
+    .await?;
+    Ok((StatusCode::OK, cookies, csrf_cookies).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use chrono::Utc;
+    use serde_json::json;
+
+    use crate::db::UserRole;
+    use crate::test_harness::TestApp;
+    use crate::totp;
+
+    fn current_code(secret: &str) -> String {
+        totp::code_at(secret, totp::step_at(Utc::now().timestamp() as u64)).unwrap()
+    }
+
+    // Enrolls the logged in user and returns their secret and recovery codes.
+    async fn enroll(app: &mut TestApp) -> (String, Vec<String>) {
+        let enrolled = app
+            .send_json(Method::POST, "/api/2fa/enroll", &json!({}))
+            .await
+            .json::<serde_json::Value>();
+        let secret = enrolled["secret"].as_str().unwrap().to_string();
+        assert!(enrolled["uri"]
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/Notes%20Portal:Admin?secret="));
+        assert!(enrolled["qr_svg"].as_str().unwrap().contains("<svg"));
+
+        // Codes are only accepted once, so confirm with the previous step's.
+        let previous = totp::step_at(Utc::now().timestamp() as u64) - 1;
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/2fa/confirm",
+                &json!({ "code": totp::code_at(&secret, previous).unwrap() }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        (secret, res.json::<Vec<String>>())
+    }
+
+    #[tokio::test]
+    async fn login_asks_for_a_code_after_enrolling() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let (secret, _) = enroll(&mut app).await;
+
+        let mut other = TestApp::from_state(app.state.clone());
+        assert_eq!(
+            other.login("Admin", "password").await.status,
+            StatusCode::ACCEPTED
+        );
+        assert_eq!(
+            other.get("/api/role").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let res = other
+            .send_json(
+                Method::POST,
+                "/api/login/totp",
+                &json!({ "code": "000000" }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+        let code = current_code(&secret);
+        let res = other
+            .send_json(Method::POST, "/api/login/totp", &json!({ "code": code }))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(other.get("/api/role").await.status, StatusCode::OK);
+
+        // The same code can't be used twice.
+        let mut third = TestApp::from_state(app.state.clone());
+        third.login("Admin", "password").await;
+        let res = third
+            .send_json(Method::POST, "/api/login/totp", &json!({ "code": code }))
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_work_once() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let (_, codes) = enroll(&mut app).await;
+        assert_eq!(codes.len(), 10);
+
+        let mut other = TestApp::from_state(app.state.clone());
+        other.login("Admin", "password").await;
+        let res = other
+            .send_json(
+                Method::POST,
+                "/api/login/totp",
+                &json!({ "code": codes[0] }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let mut third = TestApp::from_state(app.state.clone());
+        third.login("Admin", "password").await;
+        let res = third
+            .send_json(
+                Method::POST,
+                "/api/login/totp",
+                &json!({ "code": codes[0] }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn admins_can_require_two_factor() {
+        let mut app = TestApp::logged_in_as("Admin", UserRole::Admin).await;
+        let res = app
+            .send_json(
+                Method::POST,
+                "/api/admin/settings",
+                &json!({ "require_admin_totp": true }),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let mut other = TestApp::from_state(app.state.clone());
+        assert_eq!(
+            other.login("Admin", "password").await.status,
+            StatusCode::OK
+        );
+        let res = other.get("/api/admin/users").await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            other.get("/api/2fa").await.json::<serde_json::Value>(),
+            json!({ "enabled": false, "required": true })
+        );
+
+        enroll(&mut other).await;
+        assert_eq!(other.get("/api/admin/users").await.status, StatusCode::OK);
+        let res = other
+            .send_json(Method::POST, "/api/2fa/disable", &json!({ "code": "x" }))
+            .await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        // Standard users aren't affected.
+        app.create_user("Sensei", "password", UserRole::Standard)
+            .await;
+        let mut sensei = TestApp::from_state(app.state.clone());
+        sensei.login("Sensei", "password").await;
+        assert_eq!(sensei.get("/api/students").await.status, StatusCode::OK);
+    }
+}